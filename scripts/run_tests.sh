@@ -14,7 +14,7 @@ if [ $# -eq 0 ]; then
     cargo test
 else
     echo "🎯 运行特定测试: $1"
-    cargo test --test wim_parser_test "$1"
+    cargo test "$1"
 fi
 
 echo ""
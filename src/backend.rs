@@ -0,0 +1,221 @@
+//! Mount backends: the ways an image can be attached and its filesystems made available
+//!
+//! [`IsoMounter`](crate::IsoMounter) drives a backend through the same pipeline for every
+//! implementation: attach the image, discover its filesystems, mount each of them, and later
+//! unmount and detach again.
+
+use crate::udisks2::{LoopDeviceInfo, LoopOptions, MountInfo, MountOptions, UnmountOptions};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+mod iso9660;
+pub mod loop_device;
+pub mod udisks2;
+pub mod userspace;
+
+pub use loop_device::LoopDeviceBackend;
+pub use udisks2::Udisks2Backend;
+pub use userspace::UserspaceBackend;
+
+/// Available mount backends, selectable with `--backend` or `backend` in the config file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// Loop setup and mounting through the UDisks2 daemon on the system bus
+    #[default]
+    Udisks2,
+    /// `/dev/loop-control` ioctls and `mount(2)`, needs root
+    Loop,
+    /// Extract the image into a temporary directory, no kernel mount involved
+    Userspace,
+}
+
+impl BackendKind {
+    pub const ALL: [BackendKind; 3] = [Self::Udisks2, Self::Loop, Self::Userspace];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Udisks2 => "udisks2",
+            Self::Loop => "loop",
+            Self::Userspace => "userspace",
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown backend '{s}' (expected one of: {})",
+                    Self::ALL.map(Self::as_str).join(", ")
+                )
+            })
+    }
+}
+
+/// An image attached by a backend
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AttachedDevice {
+    /// Backend specific handle: a UDisks2 object path, a loop device node or an extraction directory
+    pub id: String,
+    /// Block device node, `None` for backends that do not create one
+    pub device: Option<PathBuf>,
+}
+
+/// A filesystem found on an attached image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageFilesystem {
+    /// Backend specific handle used to mount the filesystem
+    pub id: String,
+    /// Partition number, `None` when the filesystem spans the whole device
+    pub number: Option<u32>,
+    /// Partition type: a GUID on GPT, `0xNN` on MBR
+    pub type_guid: Option<String>,
+    pub label: Option<String>,
+    pub fstype: String,
+}
+
+/// What a backend reports about a filesystem it just mounted
+///
+/// The UDisks2 backend reads everything back from the daemon (see [`MountInfo`]), the others fill
+/// in what they can tell.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MountDetails {
+    /// Where the filesystem is mounted, or extracted to
+    pub mount_path: PathBuf,
+    /// Block device node of the filesystem, `None` for backends that do not create one
+    pub device: Option<PathBuf>,
+    /// Filesystem type it is mounted as
+    pub fstype: String,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    /// Size of the block device in bytes, `None` where unknown
+    pub size: Option<u64>,
+    pub read_only: bool,
+    /// Image file the filesystem comes from
+    pub backing_file: Option<PathBuf>,
+    /// UDisks2 object path of the filesystem, `None` for other backends
+    pub object_path: Option<String>,
+    /// UDisks2 object path of the loop device the filesystem sits on, `None` for other backends
+    pub loop_object_path: Option<String>,
+}
+
+impl From<MountInfo> for MountDetails {
+    fn from(info: MountInfo) -> Self {
+        Self {
+            mount_path: info.mount_path,
+            device: Some(info.device),
+            fstype: info.fstype,
+            label: info.label,
+            uuid: info.uuid,
+            size: Some(info.size),
+            read_only: info.read_only,
+            backing_file: info.backing_file,
+            object_path: Some(info.object_path.to_string()),
+            loop_object_path: info.loop_object_path.map(|path| path.to_string()),
+        }
+    }
+}
+
+/// A mounted filesystem belonging to an attached image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountedPartition {
+    /// Backend specific handle, see [`ImageFilesystem::id`]
+    pub id: String,
+    pub number: Option<u32>,
+    pub type_guid: Option<String>,
+    pub label: Option<String>,
+    pub fstype: String,
+    /// [`MountDetails::mount_path`] as a string
+    pub mount_path: String,
+    pub details: MountDetails,
+}
+
+impl MountedPartition {
+    pub fn new(filesystem: ImageFilesystem, details: MountDetails) -> Self {
+        Self {
+            id: filesystem.id,
+            number: filesystem.number,
+            type_guid: filesystem.type_guid,
+            label: filesystem.label,
+            fstype: filesystem.fstype,
+            mount_path: details.mount_path.to_string_lossy().into_owned(),
+            details,
+        }
+    }
+}
+
+/// A way of attaching images and mounting the filesystems on them
+#[async_trait]
+pub trait MountBackend: Send + Sync {
+    fn kind(&self) -> BackendKind;
+
+    /// Filesystem types that [`MountBackend::mount`] accepts as `fstype`
+    async fn supported_filesystems(&self) -> Result<Vec<String>>;
+
+    /// Attach the already opened `image` so that its filesystems can be discovered
+    async fn attach(
+        &self,
+        image: &Path,
+        file: &File,
+        options: &LoopOptions,
+    ) -> Result<AttachedDevice>;
+
+    /// Every filesystem on an attached image, ordered by partition number
+    async fn filesystems(&self, device: &AttachedDevice) -> Result<Vec<ImageFilesystem>>;
+
+    /// Mount one filesystem and report where and how it was mounted
+    async fn mount(
+        &self,
+        filesystem: &ImageFilesystem,
+        options: &MountOptions,
+    ) -> Result<MountDetails>;
+
+    async fn unmount(&self, partition: &MountedPartition, options: &UnmountOptions) -> Result<()>;
+
+    /// Release the attached image, after all of its filesystems have been unmounted
+    async fn detach(&self, device: &AttachedDevice) -> Result<()>;
+
+    /// Leave the image attached and let it go away together with its last mount
+    async fn keep_attached(&self, device: &AttachedDevice) -> Result<()>;
+
+    /// Every file-backed loop device on the system
+    async fn loop_devices(&self) -> Result<Vec<LoopDeviceInfo>> {
+        Err(anyhow::anyhow!(
+            "Listing loop devices is not supported by the {} backend",
+            self.kind()
+        ))
+    }
+
+    /// Unmount every filesystem of a loop device returned by [`MountBackend::loop_devices`] and
+    /// delete the device
+    async fn detach_loop_device(
+        &self,
+        device: &LoopDeviceInfo,
+        _options: &UnmountOptions,
+    ) -> Result<()> {
+        Err(anyhow::anyhow!(
+            "Cannot detach {}: not supported by the {} backend",
+            device.device.display(),
+            self.kind()
+        ))
+    }
+}
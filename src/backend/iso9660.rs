@@ -0,0 +1,272 @@
+//! Minimal read-only ISO 9660 reader used by the userspace backend
+//!
+//! Understands the primary volume descriptor, Joliet names and multi-extent files. Rock Ridge
+//! and UDF are not parsed, so on hybrid images only the ISO 9660 view is visible.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+
+const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR_SECTOR: u64 = 16;
+const MAX_DESCRIPTORS: u64 = 64;
+const MAX_DEPTH: usize = 64;
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// A directory record: one file, directory or file section
+#[derive(Debug, Clone)]
+struct DirRecord {
+    extent: u32,
+    length: u32,
+    flags: u8,
+    name: Vec<u8>,
+}
+
+impl DirRecord {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let name_len = *bytes.get(32)? as usize;
+        Some(Self {
+            extent: u32::from_le_bytes(bytes.get(2..6)?.try_into().ok()?),
+            length: u32::from_le_bytes(bytes.get(10..14)?.try_into().ok()?),
+            flags: *bytes.get(25)?,
+            name: bytes.get(33..33 + name_len)?.to_vec(),
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// The `.` and `..` entries are stored as a single 0x00 or 0x01 byte
+    fn is_special(&self) -> bool {
+        self.name == [0] || self.name == [1]
+    }
+}
+
+/// An ISO 9660 filesystem read through a file, starting `offset` bytes into it
+pub struct Iso9660 {
+    file: File,
+    offset: u64,
+    /// Bytes of the file from `offset` on, the upper bound for every extent
+    size: u64,
+    root: DirRecord,
+    joliet: bool,
+    volume_id: String,
+}
+
+impl Iso9660 {
+    pub fn open(file: File, offset: u64) -> Result<Self> {
+        let size = file
+            .metadata()
+            .context("Failed to read ISO image size")?
+            .len()
+            .saturating_sub(offset);
+        let mut primary = None;
+        let mut joliet = None;
+        for index in 0..MAX_DESCRIPTORS {
+            let mut sector = [0u8; SECTOR_SIZE as usize];
+            file.read_exact_at(
+                &mut sector,
+                offset + (FIRST_DESCRIPTOR_SECTOR + index) * SECTOR_SIZE,
+            )
+            .context("Failed to read ISO 9660 volume descriptor")?;
+            if &sector[1..6] != b"CD001" {
+                return Err(anyhow::anyhow!("Not an ISO 9660 image"));
+            }
+            match sector[0] {
+                DESCRIPTOR_PRIMARY => primary = Some(sector),
+                // Escape sequences %/@, %/C and %/E mark the Joliet UCS-2 levels 1-3
+                DESCRIPTOR_SUPPLEMENTARY
+                    if sector[88..90] == *b"%/" && matches!(sector[90], b'@' | b'C' | b'E') =>
+                {
+                    joliet = Some(sector)
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+
+        let primary = primary.context("ISO 9660 primary volume descriptor not found")?;
+        let descriptor = joliet.unwrap_or(primary);
+        let root = DirRecord::parse(&descriptor[156..190])
+            .context("Invalid ISO 9660 root directory record")?;
+        let volume_id = if joliet.is_some() {
+            decode_ucs2(&descriptor[40..72])
+        } else {
+            String::from_utf8_lossy(&primary[40..72]).into_owned()
+        };
+
+        Ok(Self {
+            file,
+            offset,
+            size,
+            root,
+            joliet: joliet.is_some(),
+            volume_id: volume_id.trim_end_matches([' ', '\0']).to_string(),
+        })
+    }
+
+    /// Volume label
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    /// Copy every file and directory into `dest`, returning the number of files written
+    pub fn extract(&self, dest: &Path) -> Result<u64> {
+        self.extract_dir(&self.root, dest, 0)
+    }
+
+    fn extract_dir(&self, dir: &DirRecord, dest: &Path, depth: usize) -> Result<u64> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow::anyhow!(
+                "Directory nesting deeper than {MAX_DEPTH} at {}",
+                dest.display()
+            ));
+        }
+
+        let mut files = 0;
+        let records = self.read_dir(dir)?;
+        let mut index = 0;
+        while index < records.len() {
+            let record = &records[index];
+            index += 1;
+            if record.is_special() {
+                continue;
+            }
+            let name = self.decode_name(record)?;
+            let path = dest.join(&name);
+
+            if record.is_dir() {
+                fs::create_dir_all(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                files += self.extract_dir(record, &path, depth + 1)?;
+                continue;
+            }
+
+            // Files over 4 GiB are split into several records with the same name
+            let mut sections = vec![record];
+            while sections.last().unwrap().flags & FLAG_MULTI_EXTENT != 0 && index < records.len() {
+                sections.push(&records[index]);
+                index += 1;
+            }
+            let mut out = File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            for section in sections {
+                let start = self.extent_start(section)?;
+                let mut reader = io::Read::take(
+                    ReadAt {
+                        file: &self.file,
+                        position: start,
+                    },
+                    section.length as u64,
+                );
+                let copied = io::copy(&mut reader, &mut out)
+                    .with_context(|| format!("Failed to extract {}", path.display()))?;
+                if copied != section.length as u64 {
+                    return Err(anyhow::anyhow!(
+                        "Truncated ISO image: {} has {} of {} bytes",
+                        path.display(),
+                        copied,
+                        section.length
+                    ));
+                }
+            }
+            files += 1;
+        }
+        Ok(files)
+    }
+
+    /// Absolute file offset of a record's data, which must lie within the image
+    fn extent_start(&self, record: &DirRecord) -> Result<u64> {
+        let start = record.extent as u64 * SECTOR_SIZE;
+        if start + record.length as u64 > self.size {
+            return Err(anyhow::anyhow!(
+                "Truncated ISO image: extent at sector {} with {} bytes ends past the image size {}",
+                record.extent,
+                record.length,
+                self.size
+            ));
+        }
+        Ok(self.offset + start)
+    }
+
+    fn read_dir(&self, dir: &DirRecord) -> Result<Vec<DirRecord>> {
+        // Checked first, so a corrupt length cannot size the buffer beyond the image
+        let start = self.extent_start(dir)?;
+        let mut data = vec![0u8; dir.length as usize];
+        self.file
+            .read_exact_at(&mut data, start)
+            .context("Failed to read ISO 9660 directory")?;
+
+        let mut records = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let length = data[position] as usize;
+            if length == 0 {
+                // Records never cross a sector boundary, the rest of this sector is padding
+                position = (position / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            let record = data
+                .get(position..position + length)
+                .and_then(DirRecord::parse)
+                .context("Truncated ISO 9660 directory record")?;
+            records.push(record);
+            position += length;
+        }
+        Ok(records)
+    }
+
+    /// File name without the `;1` version suffix, rejecting anything that could escape `dest`
+    fn decode_name(&self, record: &DirRecord) -> Result<String> {
+        let name = if self.joliet {
+            decode_ucs2(&record.name)
+        } else {
+            // Plain ISO 9660 names are upper case 8.3, presented in lower case like mount(8) does
+            String::from_utf8_lossy(&record.name).to_lowercase()
+        };
+        let mut name = name.split(';').next().unwrap_or_default().to_string();
+        if !record.is_dir() && !self.joliet && name.ends_with('.') {
+            name.pop();
+        }
+
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(anyhow::anyhow!("Invalid file name in ISO image: {name:?}"));
+        }
+        Ok(name)
+    }
+}
+
+/// Sequential reads at an advancing offset, without touching the shared file position
+struct ReadAt<'f> {
+    file: &'f File,
+    position: u64,
+}
+
+impl io::Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+fn decode_ucs2(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
@@ -0,0 +1,616 @@
+//! The loop backend: `/dev/loop-control` ioctls and `mount(2)`, for root on systems without udisksd
+
+use super::{
+    AttachedDevice, BackendKind, ImageFilesystem, MountBackend, MountDetails, MountedPartition,
+};
+use crate::busy::busy_message;
+use crate::udisks2::{LoopOptions, MountOptions, UnmountOptions, UnmountRetry};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use nix::{
+    errno::Errno,
+    mount::{MntFlags, MsFlags},
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+
+/// Directory below which each filesystem gets a mount point named after its device
+const DEFAULT_MOUNT_ROOT: &str = "/run/rust-system-tools";
+
+/// `LOOP_CTL_GET_FREE` can race with other users grabbing the same device
+const LOOP_SETUP_ATTEMPTS: u32 = 5;
+
+/// How often and how long to wait for partition device nodes to show up
+const PARTITION_NODE_ATTEMPTS: u32 = 20;
+const PARTITION_NODE_INTERVAL: Duration = Duration::from_millis(50);
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_FLAGS_PARTSCAN: u32 = 8;
+
+/// `struct loop_info64` from `<linux/loop.h>`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+impl LoopInfo64 {
+    fn zeroed() -> Self {
+        Self {
+            lo_device: 0,
+            lo_inode: 0,
+            lo_rdevice: 0,
+            lo_offset: 0,
+            lo_sizelimit: 0,
+            lo_number: 0,
+            lo_encrypt_type: 0,
+            lo_encrypt_key_size: 0,
+            lo_flags: 0,
+            lo_file_name: [0; 64],
+            lo_crypt_name: [0; 64],
+            lo_encrypt_key: [0; 32],
+            lo_init: [0; 2],
+        }
+    }
+}
+
+/// `struct loop_config` from `<linux/loop.h>`, for `LOOP_CONFIGURE`
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+mod ioctl {
+    use super::{LoopConfig, LoopInfo64};
+
+    nix::ioctl_none_bad!(loop_ctl_get_free, 0x4C82);
+    nix::ioctl_write_int_bad!(loop_set_fd, 0x4C00);
+    nix::ioctl_none_bad!(loop_clr_fd, 0x4C01);
+    nix::ioctl_write_ptr_bad!(loop_set_status64, 0x4C04, LoopInfo64);
+    nix::ioctl_read_bad!(loop_get_status64, 0x4C05, LoopInfo64);
+    nix::ioctl_write_ptr_bad!(loop_configure, 0x4C0A, LoopConfig);
+}
+
+/// Mount backend using the kernel loop driver directly, needs `CAP_SYS_ADMIN`
+#[derive(Debug, Clone)]
+pub struct LoopDeviceBackend {
+    mount_root: PathBuf,
+}
+
+impl Default for LoopDeviceBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoopDeviceBackend {
+    pub fn new() -> Self {
+        Self::with_mount_root(DEFAULT_MOUNT_ROOT)
+    }
+
+    /// Create mount points below `mount_root` instead of `/run/rust-system-tools`
+    pub fn with_mount_root<P: Into<PathBuf>>(mount_root: P) -> Self {
+        Self {
+            mount_root: mount_root.into(),
+        }
+    }
+
+    /// Attach `file` to a free loop device, returning the device node
+    ///
+    /// With `read_only` the device is read-only even if `file` was opened for writing.
+    fn setup_loop_device(
+        &self,
+        image: &Path,
+        file: &File,
+        options: &LoopOptions,
+    ) -> Result<String> {
+        let read_only_file;
+        let file = if options.read_only {
+            read_only_file = reopen_read_only(file)
+                .with_context(|| format!("Failed to reopen {} read-only", image.display()))?;
+            &read_only_file
+        } else {
+            file
+        };
+        let info = loop_info(image, options);
+
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(LOOP_CONTROL)
+            .with_context(|| format!("Failed to open {LOOP_CONTROL}"))?;
+
+        for attempt in 1..=LOOP_SETUP_ATTEMPTS {
+            // SAFETY: `control` is an open /dev/loop-control and the ioctl takes no argument
+            let number = unsafe { ioctl::loop_ctl_get_free(control.as_raw_fd()) }
+                .context("Failed to find a free loop device")?;
+            let node = format!("/dev/loop{number}");
+            let device = open_device(&node)?;
+
+            match configure(&device, file, &info) {
+                Ok(()) => return Ok(node),
+                Err(Errno::EBUSY) => {
+                    debug!("{node} was taken by someone else (attempt {attempt})");
+                }
+                Err(e) => return Err(e).with_context(|| format!("Failed to attach {node}")),
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "No free loop device after {LOOP_SETUP_ATTEMPTS} attempts"
+        ))
+    }
+
+    fn mount_point(&self, device: &str) -> PathBuf {
+        self.mount_root.join(device_name(device))
+    }
+}
+
+/// Offset, size limit, flags and file name for a new loop device
+fn loop_info(image: &Path, options: &LoopOptions) -> LoopInfo64 {
+    let mut info = LoopInfo64::zeroed();
+    info.lo_offset = options.offset.unwrap_or(0);
+    info.lo_sizelimit = options.size.unwrap_or(0);
+    if options.read_only {
+        info.lo_flags |= LO_FLAGS_READ_ONLY;
+    }
+    if !options.no_part_scan {
+        info.lo_flags |= LO_FLAGS_PARTSCAN;
+    }
+    let name = image.as_os_str().as_encoded_bytes();
+    let len = name.len().min(info.lo_file_name.len() - 1);
+    info.lo_file_name[..len].copy_from_slice(&name[..len]);
+    info
+}
+
+/// Attach `file` to `device` with `info` in one step, or in two on kernels before 5.8
+///
+/// `LOOP_SET_STATUS64` cannot set `LO_FLAGS_READ_ONLY`; the two step path relies on `file`
+/// being opened read-only instead.
+fn configure(device: &File, file: &File, info: &LoopInfo64) -> nix::Result<()> {
+    let config = LoopConfig {
+        fd: file.as_raw_fd() as u32,
+        block_size: 0,
+        info: *info,
+        reserved: [0; 8],
+    };
+    // SAFETY: `device` is an open loop device and `config` a valid `struct loop_config` that
+    // outlives the call; the kernel only reads it
+    match unsafe { ioctl::loop_configure(device.as_raw_fd(), &config) } {
+        // Unknown ioctl: ENOTTY, or EINVAL from the loop driver itself before 5.8
+        Err(Errno::ENOTTY) => {}
+        Err(Errno::EINVAL) if !has_loop_configure() => {}
+        result => return result.map(drop),
+    }
+
+    // SAFETY: both descriptors are open; the kernel takes its own reference to `file`
+    unsafe { ioctl::loop_set_fd(device.as_raw_fd(), file.as_raw_fd()) }?;
+    // SAFETY: `info` is a valid `struct loop_info64` that the kernel only reads
+    if let Err(e) = unsafe { ioctl::loop_set_status64(device.as_raw_fd(), info) } {
+        // SAFETY: `device` is an open loop device and the ioctl takes no argument
+        if let Err(clear_err) = unsafe { ioctl::loop_clr_fd(device.as_raw_fd()) } {
+            warn!("Failed to release the loop device: {clear_err}");
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Whether the running kernel knows `LOOP_CONFIGURE`, added in Linux 5.8
+fn has_loop_configure() -> bool {
+    let Ok(release) = fs::read_to_string("/proc/sys/kernel/osrelease") else {
+        return true;
+    };
+    let mut version = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    (version.next().unwrap_or(0), version.next().unwrap_or(0)) >= (5, 8)
+}
+
+/// Open the file behind `file` again, read-only
+fn reopen_read_only(file: &File) -> std::io::Result<File> {
+    File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// Open a loop device node for ioctls
+fn open_device(node: &str) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(nix::libc::O_CLOEXEC)
+        .open(node)
+        .with_context(|| format!("Failed to open {node}"))
+}
+
+/// `loop0p1` for `/dev/loop0p1`
+fn device_name(node: &str) -> &str {
+    node.rsplit('/').next().unwrap_or(node)
+}
+
+/// Partitions of a loop device as `(number, node, name)`, from sysfs
+fn sysfs_partitions(device: &str) -> Result<Vec<(u32, String, Option<String>)>> {
+    let name = device_name(device);
+    let dir = Path::new("/sys/block").join(name);
+    let mut partitions = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let entry_name = entry.file_name().to_string_lossy().into_owned();
+        if !entry_name.starts_with(name) {
+            continue;
+        }
+        let Ok(number) = fs::read_to_string(entry.path().join("partition")) else {
+            continue;
+        };
+        let Ok(number) = number.trim().parse() else {
+            continue;
+        };
+        let partition_name = fs::read_to_string(entry.path().join("uevent"))
+            .ok()
+            .and_then(|uevent| {
+                uevent
+                    .lines()
+                    .find_map(|line| line.strip_prefix("PARTNAME=").map(str::to_string))
+            });
+        partitions.push((number, format!("/dev/{entry_name}"), partition_name));
+    }
+    partitions.sort();
+    Ok(partitions)
+}
+
+/// The sysfs directory of the loop device itself, for a partition too
+fn sysfs_loop_dir(device: &str) -> Option<PathBuf> {
+    let dir = fs::canonicalize(Path::new("/sys/class/block").join(device_name(device))).ok()?;
+    if dir.join("partition").exists() {
+        dir.parent().map(Path::to_path_buf)
+    } else {
+        Some(dir)
+    }
+}
+
+/// Size of a block device in bytes, from its 512-byte sector count in sysfs
+fn sysfs_size(device: &str) -> Option<u64> {
+    let sectors = fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(device_name(device))
+            .join("size"),
+    )
+    .ok()?;
+    Some(sectors.trim().parse::<u64>().ok()? * 512)
+}
+
+/// The image file behind a loop device or one of its partitions
+fn sysfs_backing_file(device: &str) -> Option<PathBuf> {
+    let backing_file =
+        fs::read_to_string(sysfs_loop_dir(device)?.join("loop/backing_file")).ok()?;
+    Some(PathBuf::from(backing_file.trim_end_matches('\n')))
+}
+
+/// Whether the kernel marked a block device read-only
+fn is_read_only(device: &str) -> bool {
+    fs::read_to_string(
+        Path::new("/sys/class/block")
+            .join(device_name(device))
+            .join("ro"),
+    )
+    .is_ok_and(|ro| ro.trim() == "1")
+}
+
+/// Tell ext2, ext3 and ext4 apart by the feature flags of an ext superblock, like blkid
+///
+/// A journal makes ext3, features ext3 lacks (extents, 64bit, huge files, ...) make ext4.
+fn ext_version(buf: &[u8]) -> &'static str {
+    const COMPAT_HAS_JOURNAL: u32 = 0x4;
+    // FILETYPE, RECOVER and META_BG
+    const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x10;
+    // SPARSE_SUPER, LARGE_FILE and BTREE_DIR
+    const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+    let le32 = |offset: usize| {
+        buf.get(offset..offset + 4)
+            .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let (compat, incompat, ro_compat) = (le32(0x45c), le32(0x460), le32(0x464));
+
+    if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+        "ext4"
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    }
+}
+
+/// Recognise the filesystem on a block device from its superblock magic
+fn probe_fstype(device: &str) -> Option<&'static str> {
+    let file = File::open(device).ok()?;
+    let mut buf = vec![0u8; 0x10048];
+    let read = file.read_at(&mut buf, 0).ok()?;
+    buf.truncate(read);
+    let at = |offset: usize, magic: &[u8]| buf.get(offset..offset + magic.len()) == Some(magic);
+
+    // Hybrid Windows images carry both, and the UDF side is the complete one
+    let udf = (0..16).any(|i| at(32769 + i * 2048, b"NSR02") || at(32769 + i * 2048, b"NSR03"));
+    if udf {
+        Some("udf")
+    } else if at(32769, b"CD001") {
+        Some("iso9660")
+    } else if at(0x438, &[0x53, 0xef]) {
+        Some(ext_version(&buf))
+    } else if at(3, b"NTFS    ") {
+        Some("ntfs")
+    } else if at(3, b"EXFAT   ") {
+        Some("exfat")
+    } else if at(510, &[0x55, 0xaa]) && (at(54, b"FAT") || at(82, b"FAT32")) {
+        Some("vfat")
+    } else if at(0, b"hsqs") {
+        Some("squashfs")
+    } else if at(0, b"XFSB") {
+        Some("xfs")
+    } else if at(1024, &0xe0f5e1e2u32.to_le_bytes()) {
+        Some("erofs")
+    } else if at(0x10040, b"_BHRfS_M") {
+        Some("btrfs")
+    } else {
+        None
+    }
+}
+
+/// Split `-o` style options into `mount(2)` flags and the filesystem specific data string
+fn parse_mount_options(options: Option<&str>) -> (MsFlags, String) {
+    let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+    let mut data = Vec::new();
+    for option in options
+        .unwrap_or_default()
+        .split(',')
+        .filter(|o| !o.is_empty())
+    {
+        match option {
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "rw" => flags &= !MsFlags::MS_RDONLY,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "sync" => flags |= MsFlags::MS_SYNCHRONOUS,
+            other => data.push(other),
+        }
+    }
+    (flags, data.join(","))
+}
+
+/// Block device filesystems the running kernel knows about, from `/proc/filesystems`
+fn kernel_filesystems() -> Result<Vec<String>> {
+    Ok(fs::read_to_string("/proc/filesystems")
+        .context("Failed to read /proc/filesystems")?
+        .lines()
+        .filter(|line| !line.starts_with("nodev"))
+        .map(|line| line.trim().to_string())
+        .filter(|fs| !fs.is_empty())
+        .collect())
+}
+
+#[async_trait]
+impl MountBackend for LoopDeviceBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Loop
+    }
+
+    async fn supported_filesystems(&self) -> Result<Vec<String>> {
+        kernel_filesystems()
+    }
+
+    async fn attach(
+        &self,
+        image: &Path,
+        file: &File,
+        options: &LoopOptions,
+    ) -> Result<AttachedDevice> {
+        let node = self.setup_loop_device(image, file, options)?;
+        info!("Loop device created: {node}");
+        Ok(AttachedDevice {
+            id: node.clone(),
+            device: Some(PathBuf::from(node)),
+        })
+    }
+
+    async fn filesystems(&self, device: &AttachedDevice) -> Result<Vec<ImageFilesystem>> {
+        let partitions = sysfs_partitions(&device.id)?;
+        if partitions.is_empty() {
+            return Ok(vec![ImageFilesystem {
+                id: device.id.clone(),
+                number: None,
+                type_guid: None,
+                label: None,
+                fstype: probe_fstype(&device.id).unwrap_or_default().to_string(),
+            }]);
+        }
+
+        let mut filesystems = Vec::new();
+        for (number, node, name) in partitions {
+            for attempt in 1..=PARTITION_NODE_ATTEMPTS {
+                if Path::new(&node).exists() {
+                    break;
+                }
+                debug!("Waiting for {node} (attempt {attempt})");
+                tokio::time::sleep(PARTITION_NODE_INTERVAL).await;
+            }
+            // Partitions without a recognisable filesystem, like BIOS boot or MSR, are skipped
+            let Some(fstype) = probe_fstype(&node) else {
+                debug!("No filesystem recognised on {node}");
+                continue;
+            };
+            filesystems.push(ImageFilesystem {
+                id: node,
+                number: Some(number),
+                type_guid: None,
+                label: name.filter(|n| !n.is_empty()),
+                fstype: fstype.to_string(),
+            });
+        }
+        Ok(filesystems)
+    }
+
+    async fn mount(
+        &self,
+        filesystem: &ImageFilesystem,
+        options: &MountOptions,
+    ) -> Result<MountDetails> {
+        let candidates = match (&options.fstype, filesystem.fstype.as_str()) {
+            (Some(fstype), _) => vec![fstype.clone()],
+            (None, "") => kernel_filesystems()?,
+            (None, fstype) => vec![fstype.to_string()],
+        };
+        let (mut flags, data) = parse_mount_options(options.options.as_deref());
+        if is_read_only(&filesystem.id) {
+            flags |= MsFlags::MS_RDONLY;
+        }
+
+        let target = self.mount_point(&filesystem.id);
+        fs::create_dir_all(&target)
+            .with_context(|| format!("Failed to create mount point {}", target.display()))?;
+
+        let mut last_error = None;
+        for fstype in &candidates {
+            let mut result = nix::mount::mount(
+                Some(filesystem.id.as_str()),
+                &target,
+                Some(fstype.as_str()),
+                flags,
+                Some(data.as_str()),
+            );
+            let mut read_only = flags.contains(MsFlags::MS_RDONLY);
+            // Like mount(8), fall back to read-only for write protected media
+            if matches!(result, Err(Errno::EROFS | Errno::EACCES)) && !read_only {
+                read_only = true;
+                result = nix::mount::mount(
+                    Some(filesystem.id.as_str()),
+                    &target,
+                    Some(fstype.as_str()),
+                    flags | MsFlags::MS_RDONLY,
+                    Some(data.as_str()),
+                );
+            }
+            match result {
+                Ok(()) => {
+                    info!("Mounted at: {}", target.display());
+                    return Ok(MountDetails {
+                        mount_path: target,
+                        device: Some(PathBuf::from(&filesystem.id)),
+                        fstype: fstype.clone(),
+                        label: filesystem.label.clone(),
+                        uuid: None,
+                        size: sysfs_size(&filesystem.id),
+                        read_only,
+                        backing_file: sysfs_backing_file(&filesystem.id),
+                        ..MountDetails::default()
+                    });
+                }
+                Err(e) => {
+                    debug!("Mounting {} as {fstype} failed: {e}", filesystem.id);
+                    last_error = Some((fstype, e));
+                }
+            }
+        }
+
+        if let Err(e) = fs::remove_dir(&target) {
+            debug!("Failed to remove {}: {e}", target.display());
+        }
+        match last_error {
+            Some((fstype, e)) => Err(anyhow::anyhow!(
+                "Failed to mount {} as {fstype} on {}: {e}",
+                filesystem.id,
+                target.display()
+            )),
+            None => Err(anyhow::anyhow!(
+                "No filesystem type to mount {} with",
+                filesystem.id
+            )),
+        }
+    }
+
+    async fn unmount(&self, partition: &MountedPartition, options: &UnmountOptions) -> Result<()> {
+        // Without a daemon to kill the users of a busy mount, force means a lazy unmount
+        let flags = if options.force {
+            MntFlags::MNT_DETACH
+        } else {
+            MntFlags::empty()
+        };
+        let mut delays = options.retry.iter().flat_map(UnmountRetry::delays);
+        loop {
+            match nix::mount::umount2(partition.mount_path.as_str(), flags) {
+                Ok(()) => break,
+                Err(Errno::EBUSY) => {
+                    if let Some(delay) = delays.next() {
+                        warn!(
+                            "{} is busy, trying again in {delay:?}",
+                            partition.mount_path
+                        );
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    return Err(Errno::EBUSY)
+                        .with_context(|| busy_message(Path::new(&partition.mount_path)));
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to unmount {}", partition.mount_path));
+                }
+            }
+        }
+        if let Err(e) = fs::remove_dir(&partition.mount_path) {
+            debug!("Failed to remove {}: {e}", partition.mount_path);
+        }
+
+        info!("Unmounted successfully");
+        Ok(())
+    }
+
+    async fn detach(&self, device: &AttachedDevice) -> Result<()> {
+        let file = open_device(&device.id)?;
+        // SAFETY: `file` is an open loop device and the ioctl takes no argument
+        unsafe { ioctl::loop_clr_fd(file.as_raw_fd()) }
+            .with_context(|| format!("Failed to delete loop device {}", device.id))?;
+
+        info!("Loop device deleted: {}", device.id);
+        Ok(())
+    }
+
+    async fn keep_attached(&self, device: &AttachedDevice) -> Result<()> {
+        let file = open_device(&device.id)?;
+        let mut info = LoopInfo64::zeroed();
+        // SAFETY: `info` is a valid `struct loop_info64` for the kernel to fill in
+        unsafe { ioctl::loop_get_status64(file.as_raw_fd(), &mut info) }
+            .with_context(|| format!("Failed to read status of {}", device.id))?;
+        info.lo_flags |= LO_FLAGS_AUTOCLEAR;
+        // SAFETY: `info` is a valid `struct loop_info64` that the kernel only reads
+        unsafe { ioctl::loop_set_status64(file.as_raw_fd(), &info) }
+            .context("Failed to set loop device autoclear")?;
+
+        debug!("Autoclear set on {}", device.id);
+        Ok(())
+    }
+}
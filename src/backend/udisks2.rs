@@ -0,0 +1,278 @@
+//! The UDisks2 backend: loop setup and mounting through udisksd on the system bus
+
+use super::{
+    AttachedDevice, BackendKind, ImageFilesystem, MountBackend, MountDetails, MountedPartition,
+};
+use crate::luks::PassphraseSource;
+use crate::udisks2::{
+    FilesystemObject, LoopDeviceInfo, LoopOptions, MountOptions, UDisks2Block, UDisks2Encrypted,
+    UDisks2Filesystem, UDisks2Loop, UDisks2Manager, UDisks2ObjectManager, UnmountOptions,
+    find_encrypted, find_filesystems, partition_table_settled,
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{fs::File, os::fd::AsFd, path::Path, time::Duration};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::{
+    Connection,
+    zvariant::{Fd, ObjectPath},
+};
+
+/// How often and how long to wait for partitions of a new loop device to show up
+const PARTITION_DISCOVERY_ATTEMPTS: u32 = 20;
+const PARTITION_DISCOVERY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Mount backend talking to the UDisks2 daemon
+///
+/// LUKS containers on an image are unlocked with keys from the [`PassphraseSource`] when their
+/// filesystems are discovered, and locked again before the loop device is deleted.
+#[derive(Clone)]
+pub struct Udisks2Backend {
+    connection: Connection,
+    manager: UDisks2Manager,
+    object_manager: UDisks2ObjectManager,
+    passphrase: PassphraseSource,
+}
+
+impl Udisks2Backend {
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            manager: UDisks2Manager::new(connection).await?,
+            object_manager: UDisks2ObjectManager::new(connection).await?,
+            passphrase: PassphraseSource::default(),
+        })
+    }
+
+    /// Read keys for LUKS containers from `passphrase` instead of prompting on the terminal
+    pub fn with_passphrase_source(mut self, passphrase: PassphraseSource) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    /// The D-Bus connection the backend talks to UDisks2 over
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn manager(&self) -> &UDisks2Manager {
+        &self.manager
+    }
+
+    pub fn object_manager(&self) -> &UDisks2ObjectManager {
+        &self.object_manager
+    }
+
+    /// Poll the ObjectManager until the partitions of `loop_path` have been exported
+    ///
+    /// A LUKS container counts as found, and so does each unlocked container once a filesystem
+    /// shows up on its cleartext device.
+    async fn discover_filesystems(
+        &self,
+        loop_path: &ObjectPath<'_>,
+    ) -> Result<Vec<FilesystemObject>> {
+        let mut filesystems = Vec::new();
+        for attempt in 1..=PARTITION_DISCOVERY_ATTEMPTS {
+            let objects = self.object_manager.managed_objects().await?;
+            filesystems = find_filesystems(&objects, loop_path);
+            let containers = find_encrypted(&objects, loop_path);
+            let cleartext_ready = containers.iter().all(|c| {
+                c.cleartext_device.as_ref().is_none_or(|cleartext| {
+                    filesystems.iter().any(|fs| fs.object_path == *cleartext)
+                })
+            });
+            if (!filesystems.is_empty() || !containers.is_empty())
+                && cleartext_ready
+                && partition_table_settled(&objects, loop_path)
+            {
+                break;
+            }
+            if attempt < PARTITION_DISCOVERY_ATTEMPTS {
+                debug!("Waiting for filesystems on {loop_path} (attempt {attempt})");
+                tokio::time::sleep(PARTITION_DISCOVERY_INTERVAL).await;
+            }
+        }
+        Ok(filesystems)
+    }
+
+    /// Unlock every locked LUKS container on `loop_path`, returning whether any was unlocked
+    async fn unlock_containers(&self, loop_path: &ObjectPath<'_>) -> Result<bool> {
+        let objects = self.object_manager.managed_objects().await?;
+        let mut unlocked = false;
+        for container in find_encrypted(&objects, loop_path)
+            .into_iter()
+            .filter(|c| c.cleartext_device.is_none())
+        {
+            info!(
+                "{} is LUKS encrypted, unlocking",
+                container.device.display()
+            );
+            let source = self.passphrase.clone();
+            let device = container.device.clone();
+            let key = tokio::task::spawn_blocking(move || source.read(&device))
+                .await
+                .context("Passphrase task failed")??;
+            UDisks2Encrypted::new(&self.connection, container.object_path.into_inner())
+                .await?
+                .unlock(&key)
+                .await?;
+            unlocked = true;
+        }
+        Ok(unlocked)
+    }
+
+    /// Lock every unlocked LUKS container on `loop_path`, whose filesystems must be unmounted
+    async fn lock_containers(&self, loop_path: &ObjectPath<'_>) -> Result<()> {
+        let objects = self.object_manager.managed_objects().await?;
+        for container in find_encrypted(&objects, loop_path)
+            .into_iter()
+            .filter(|c| c.cleartext_device.is_some())
+        {
+            UDisks2Encrypted::new(&self.connection, container.object_path.into_inner())
+                .await?
+                .lock()
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a backend handle back into a UDisks2 object path
+fn object_path(id: &str) -> Result<ObjectPath<'static>> {
+    ObjectPath::try_from(id.to_string())
+        .with_context(|| format!("Invalid UDisks2 object path: {id}"))
+}
+
+#[async_trait]
+impl MountBackend for Udisks2Backend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Udisks2
+    }
+
+    async fn supported_filesystems(&self) -> Result<Vec<String>> {
+        self.manager.supported_filesystems().await
+    }
+
+    async fn attach(
+        &self,
+        image: &Path,
+        file: &File,
+        options: &LoopOptions,
+    ) -> Result<AttachedDevice> {
+        let iso_fd = Fd::from(file.as_fd());
+        debug!("Opening ISO file: {} (fd: {})", image.display(), iso_fd);
+        let loop_device = self.manager.setup_loop_device(iso_fd, options).await?;
+        let id = loop_device.object_path().to_string();
+
+        let device = match UDisks2Block::new(&self.connection, loop_device.object_path().clone())
+            .await?
+            .device()
+            .await
+        {
+            Ok(device) => Some(device),
+            Err(e) => {
+                warn!("Failed to read device node of {id}: {e:#}");
+                None
+            }
+        };
+        Ok(AttachedDevice { id, device })
+    }
+
+    async fn filesystems(&self, device: &AttachedDevice) -> Result<Vec<ImageFilesystem>> {
+        let loop_path = object_path(&device.id)?;
+        let mut filesystems = self.discover_filesystems(&loop_path).await?;
+        if self.unlock_containers(&loop_path).await? {
+            filesystems = self.discover_filesystems(&loop_path).await?;
+        }
+        Ok(filesystems
+            .into_iter()
+            .map(|fs| ImageFilesystem {
+                id: fs.object_path.to_string(),
+                number: fs.number,
+                type_guid: fs.type_guid,
+                label: fs.label,
+                fstype: fs.fstype,
+            })
+            .collect())
+    }
+
+    async fn mount(
+        &self,
+        filesystem: &ImageFilesystem,
+        options: &MountOptions,
+    ) -> Result<MountDetails> {
+        let udisks_filesystem =
+            UDisks2Filesystem::new(&self.connection, object_path(&filesystem.id)?).await?;
+        let mount_info = udisks_filesystem.mount(options).await?;
+        debug!(
+            "Mounted {} ({}) from {:?}",
+            mount_info.device.display(),
+            mount_info.fstype,
+            mount_info.backing_file
+        );
+        Ok(mount_info.into())
+    }
+
+    async fn unmount(&self, partition: &MountedPartition, options: &UnmountOptions) -> Result<()> {
+        UDisks2Filesystem::new(&self.connection, object_path(&partition.id)?)
+            .await?
+            .unmount(options)
+            .await
+    }
+
+    async fn detach(&self, device: &AttachedDevice) -> Result<()> {
+        let loop_path = object_path(&device.id)?;
+        self.lock_containers(&loop_path).await?;
+        UDisks2Loop::new(&self.connection, loop_path)
+            .await?
+            .delete()
+            .await
+    }
+
+    async fn keep_attached(&self, device: &AttachedDevice) -> Result<()> {
+        UDisks2Loop::new(&self.connection, object_path(&device.id)?)
+            .await?
+            .set_autoclear(true)
+            .await
+    }
+
+    async fn loop_devices(&self) -> Result<Vec<LoopDeviceInfo>> {
+        self.object_manager.loop_devices().await
+    }
+
+    async fn detach_loop_device(
+        &self,
+        device: &LoopDeviceInfo,
+        options: &UnmountOptions,
+    ) -> Result<()> {
+        for fs in device
+            .filesystems
+            .iter()
+            .filter(|fs| !fs.mount_points.is_empty())
+        {
+            UDisks2Filesystem::new(&self.connection, fs.object_path.clone().into())
+                .await?
+                .unmount(options)
+                .await?;
+        }
+        self.lock_containers(&device.object_path).await?;
+
+        // An autoclear loop device disappears together with its last mount
+        if device.autoclear
+            && !self
+                .object_manager
+                .loop_devices()
+                .await?
+                .iter()
+                .any(|d| d.object_path == device.object_path)
+        {
+            info!("Loop device {} was autocleared", device.device.display());
+            return Ok(());
+        }
+        UDisks2Loop::new(&self.connection, device.object_path.clone().into())
+            .await?
+            .delete()
+            .await
+    }
+}
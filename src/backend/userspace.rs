@@ -0,0 +1,191 @@
+//! The userspace backend: extract the image into a temporary directory, no kernel mount needed
+//!
+//! Works in containers and CI without udisksd or root. Only ISO 9660 images are understood; the
+//! loop options apart from `offset` and all mount options are ignored.
+
+use super::{
+    AttachedDevice, BackendKind, ImageFilesystem, MountBackend, MountDetails, MountedPartition,
+    iso9660::Iso9660,
+};
+use crate::udisks2::{LoopOptions, MountOptions, UnmountOptions};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+use tempfile::TempDir;
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+
+/// An image attached to an extraction directory
+struct Extraction {
+    dir: TempDir,
+    image: PathBuf,
+    file: File,
+    offset: u64,
+}
+
+/// Mount backend that copies image contents out instead of mounting them
+#[derive(Default)]
+pub struct UserspaceBackend {
+    extractions: Mutex<HashMap<String, Extraction>>,
+    temp_root: Option<PathBuf>,
+}
+
+impl UserspaceBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create extraction directories below `temp_root` instead of the system temp directory
+    pub fn with_temp_root<P: Into<PathBuf>>(temp_root: P) -> Self {
+        Self {
+            temp_root: Some(temp_root.into()),
+            ..Self::default()
+        }
+    }
+
+    /// The attached images; every update is a single insert or remove, so a poisoned map is
+    /// still consistent
+    fn extractions(&self) -> MutexGuard<'_, HashMap<String, Extraction>> {
+        self.extractions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Open the image of an attached device for reading
+    fn open(&self, id: &str) -> Result<Iso9660> {
+        let extractions = self.extractions();
+        let extraction = extractions
+            .get(id)
+            .with_context(|| format!("{id} is not attached"))?;
+        let file = extraction
+            .file
+            .try_clone()
+            .context("Failed to duplicate image file descriptor")?;
+        Iso9660::open(file, extraction.offset)
+    }
+}
+
+#[async_trait]
+impl MountBackend for UserspaceBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Userspace
+    }
+
+    async fn supported_filesystems(&self) -> Result<Vec<String>> {
+        Ok(vec!["iso9660".to_string()])
+    }
+
+    async fn attach(
+        &self,
+        image: &Path,
+        file: &File,
+        options: &LoopOptions,
+    ) -> Result<AttachedDevice> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("rust-system-tools-");
+        let dir = match &self.temp_root {
+            Some(root) => builder.tempdir_in(root),
+            None => builder.tempdir(),
+        }
+        .context("Failed to create extraction directory")?;
+        let id = dir.path().to_string_lossy().into_owned();
+
+        let extraction = Extraction {
+            dir,
+            image: image.to_path_buf(),
+            file: file
+                .try_clone()
+                .context("Failed to duplicate image file descriptor")?,
+            offset: options.offset.unwrap_or(0),
+        };
+        debug!("Attached {} to {id}", image.display());
+        self.extractions().insert(id.clone(), extraction);
+        Ok(AttachedDevice { id, device: None })
+    }
+
+    async fn filesystems(&self, device: &AttachedDevice) -> Result<Vec<ImageFilesystem>> {
+        let iso = self.open(&device.id)?;
+        Ok(vec![ImageFilesystem {
+            id: device.id.clone(),
+            number: None,
+            type_guid: None,
+            label: Some(iso.volume_id().to_string()).filter(|l| !l.is_empty()),
+            fstype: "iso9660".to_string(),
+        }])
+    }
+
+    async fn mount(
+        &self,
+        filesystem: &ImageFilesystem,
+        options: &MountOptions,
+    ) -> Result<MountDetails> {
+        if options.options.is_some() {
+            debug!("Mount options are ignored by the userspace backend");
+        }
+        let iso = self.open(&filesystem.id)?;
+        let image = self
+            .extractions()
+            .get(&filesystem.id)
+            .map(|extraction| extraction.image.clone());
+        let dest = PathBuf::from(&filesystem.id);
+        let files = tokio::task::spawn_blocking(move || iso.extract(&dest))
+            .await
+            .context("Extraction task failed")??;
+
+        info!("Extracted {files} files to {}", filesystem.id);
+        Ok(MountDetails {
+            mount_path: PathBuf::from(&filesystem.id),
+            device: None,
+            fstype: filesystem.fstype.clone(),
+            label: filesystem.label.clone(),
+            uuid: None,
+            size: None,
+            // The extracted files are a private copy, writing them leaves the image alone
+            read_only: false,
+            backing_file: image,
+            ..MountDetails::default()
+        })
+    }
+
+    async fn unmount(&self, partition: &MountedPartition, _options: &UnmountOptions) -> Result<()> {
+        for entry in fs::read_dir(&partition.mount_path)
+            .with_context(|| format!("Failed to read {}", partition.mount_path))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            }
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    async fn detach(&self, device: &AttachedDevice) -> Result<()> {
+        let extraction = self
+            .extractions()
+            .remove(&device.id)
+            .with_context(|| format!("{} is not attached", device.id))?;
+        extraction
+            .dir
+            .close()
+            .with_context(|| format!("Failed to remove {}", device.id))
+    }
+
+    async fn keep_attached(&self, device: &AttachedDevice) -> Result<()> {
+        let extraction = self
+            .extractions()
+            .remove(&device.id)
+            .with_context(|| format!("{} is not attached", device.id))?;
+        let dir = extraction.dir.keep();
+        info!(
+            "Extracted files are kept in {}, remove the directory when done",
+            dir.display()
+        );
+        Ok(())
+    }
+}
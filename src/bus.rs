@@ -0,0 +1,117 @@
+//! Which D-Bus to reach UDisks2 on
+//!
+//! Normally that is the system bus, but a nested udisksd in a container, a test bus or a remote
+//! machine's system bus forwarded over SSH (`ssh -L /tmp/remote-bus:/run/dbus/system_bus_socket`)
+//! can be selected with [`BusAddress`] and connected to with [`Udisks2ConnectionBuilder`].
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::{Address, Connection, connection};
+
+/// Overrides the system bus address, as in libdbus
+pub const SYSTEM_BUS_ADDRESS_ENV: &str = "DBUS_SYSTEM_BUS_ADDRESS";
+
+/// A bus selectable with `--bus` or `bus` in the config file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum BusAddress {
+    /// The system bus, at `$DBUS_SYSTEM_BUS_ADDRESS` if set
+    #[default]
+    System,
+    /// The session bus of the current user
+    Session,
+    /// An explicit D-Bus address such as `unix:path=/run/container/dbus/system_bus_socket`
+    Address(String),
+}
+
+impl fmt::Display for BusAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => f.write_str("system"),
+            Self::Session => f.write_str("session"),
+            Self::Address(address) => f.write_str(address),
+        }
+    }
+}
+
+impl FromStr for BusAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Self::System),
+            "session" => Ok(Self::Session),
+            _ => Address::from_str(s)
+                .map(|_| Self::Address(s.to_string()))
+                .map_err(|e| {
+                    format!("invalid bus '{s}' (expected system, session or a D-Bus address): {e}")
+                }),
+        }
+    }
+}
+
+impl From<BusAddress> for String {
+    fn from(bus: BusAddress) -> Self {
+        bus.to_string()
+    }
+}
+
+impl TryFrom<String> for BusAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Connects to the bus UDisks2 runs on
+#[derive(Debug, Clone, Default)]
+pub struct Udisks2ConnectionBuilder {
+    bus: BusAddress,
+}
+
+impl Udisks2ConnectionBuilder {
+    /// A builder for the system bus
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to `bus` instead of the system bus
+    pub fn bus(mut self, bus: BusAddress) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    /// Open the connection
+    ///
+    /// For [`BusAddress::System`] a non-empty `$DBUS_SYSTEM_BUS_ADDRESS` takes precedence over the
+    /// well-known socket.
+    pub async fn build(self) -> Result<Connection> {
+        match self.bus {
+            BusAddress::System => match std::env::var(SYSTEM_BUS_ADDRESS_ENV) {
+                Ok(address) if !address.is_empty() => {
+                    debug!("Using the system bus at {address} from {SYSTEM_BUS_ADDRESS_ENV}");
+                    connect_to(&address)
+                        .await
+                        .with_context(|| format!("Failed to connect to system bus at {address}"))
+                }
+                _ => Connection::system()
+                    .await
+                    .context("Failed to connect to system bus"),
+            },
+            BusAddress::Session => Connection::session()
+                .await
+                .context("Failed to connect to session bus"),
+            BusAddress::Address(address) => connect_to(&address)
+                .await
+                .with_context(|| format!("Failed to connect to D-Bus at {address}")),
+        }
+    }
+}
+
+async fn connect_to(address: &str) -> Result<Connection> {
+    Ok(connection::Builder::address(address)?.build().await?)
+}
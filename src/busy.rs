@@ -0,0 +1,152 @@
+//! Finding the processes that keep a mount point busy, like `fuser -m` or `lsof +f --`
+//!
+//! Scans `/proc/<pid>` for working directories, root directories, open files and memory-mapped
+//! files (e.g. a program started from the mount) below the mount point. Without root only the
+//! caller's own processes can be inspected, so the list may be incomplete.
+
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    fmt, fs,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
+
+/// Appended by the kernel to links and mappings of files that were removed
+const DELETED: &str = " (deleted)";
+
+/// How a process uses a path below the mount point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HolderUsage {
+    /// Current working directory (`/proc/<pid>/cwd`)
+    Cwd,
+    /// Root directory, e.g. a chroot (`/proc/<pid>/root`)
+    Root,
+    /// Open file descriptor (`/proc/<pid>/fd/*`)
+    OpenFile,
+    /// Memory-mapped file, such as an executable or library (`/proc/<pid>/maps`)
+    Mapped,
+}
+
+impl fmt::Display for HolderUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cwd => "working directory",
+            Self::Root => "root directory",
+            Self::OpenFile => "open file",
+            Self::Mapped => "mapped file",
+        })
+    }
+}
+
+/// A process holding a path below a mount point
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MountHolder {
+    pub pid: u32,
+    /// Process name (`/proc/<pid>/comm`)
+    pub command: String,
+    pub usage: HolderUsage,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for MountHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pid {} ({}): {} {}",
+            self.pid,
+            self.command,
+            self.usage,
+            self.path.display()
+        )
+    }
+}
+
+/// Every process with its working directory, root, an open or a mapped file below `mount_point`
+pub fn find_holders(mount_point: &Path) -> Vec<MountHolder> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut pids: Vec<u32> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    pids.sort_unstable();
+
+    let mut holders = Vec::new();
+    for pid in pids {
+        let proc_dir = PathBuf::from(format!("/proc/{pid}"));
+        let mut found = Vec::new();
+        for (link, usage) in [("cwd", HolderUsage::Cwd), ("root", HolderUsage::Root)] {
+            if let Some(path) = link_target(&proc_dir.join(link))
+                && path.starts_with(mount_point)
+            {
+                found.push((usage, path));
+            }
+        }
+        if let Ok(fds) = fs::read_dir(proc_dir.join("fd")) {
+            for fd in fds.flatten() {
+                if let Some(path) = link_target(&fd.path())
+                    && path.starts_with(mount_point)
+                {
+                    found.push((HolderUsage::OpenFile, path));
+                }
+            }
+        }
+        if let Ok(maps) = fs::read_to_string(proc_dir.join("maps")) {
+            found.extend(
+                maps.lines()
+                    .filter_map(mapped_path)
+                    .filter(|path| path.starts_with(mount_point))
+                    .map(|path| (HolderUsage::Mapped, path)),
+            );
+        }
+        if found.is_empty() {
+            continue;
+        }
+
+        let command = fs::read_to_string(proc_dir.join("comm"))
+            .map(|c| c.trim_end().to_string())
+            .unwrap_or_default();
+        found.sort();
+        found.dedup();
+        holders.extend(found.into_iter().map(|(usage, path)| MountHolder {
+            pid,
+            command: command.clone(),
+            usage,
+            path,
+        }));
+    }
+    holders
+}
+
+/// The target of a `/proc/<pid>` link, without the [`DELETED`] marker
+fn link_target(link: &Path) -> Option<PathBuf> {
+    let mut target = fs::read_link(link).ok()?.into_os_string().into_vec();
+    if target.ends_with(DELETED.as_bytes()) {
+        target.truncate(target.len() - DELETED.len());
+    }
+    Some(PathBuf::from(OsString::from_vec(target)))
+}
+
+/// The file of a `/proc/<pid>/maps` line, `None` for anonymous and special mappings
+///
+/// The path is everything after the fifth field, so it may contain spaces.
+fn mapped_path(line: &str) -> Option<PathBuf> {
+    let path = line.splitn(6, ' ').nth(5)?.trim_start();
+    let path = path.strip_suffix(DELETED).unwrap_or(path);
+    path.starts_with('/').then(|| PathBuf::from(path))
+}
+
+/// Explain why `mount_point` could not be unmounted, listing its holders if any are visible
+pub fn busy_message(mount_point: &Path) -> String {
+    let holders = find_holders(mount_point);
+    if holders.is_empty() {
+        return format!("{} is busy", mount_point.display());
+    }
+    let mut message = format!("{} is still in use by:", mount_point.display());
+    for holder in holders {
+        message.push_str(&format!("\n  {holder}"));
+    }
+    message
+}
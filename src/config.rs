@@ -1,3 +1,8 @@
+use crate::backend::BackendKind;
+use crate::bus::BusAddress;
+use crate::udisks2::MountOptions;
+use crate::udisks2::auth::AuthMode;
+use crate::udisks2::policy::CallPolicy;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -24,19 +29,17 @@ impl FontLoader {
     /// 根据字体族名称查找字体数据
     pub fn find_font_data(&self, family_names: &[String]) -> Option<Vec<u8>> {
         for family_name in family_names {
+            // 找到字体后尝试从face中获取数据
             if let Some(id) = self.db.query(&fontdb::Query {
                 families: &[fontdb::Family::Name(family_name)],
                 ..Default::default()
-            }) {
-                if let Some(_face) = self.db.face(id) {
-                    // 尝试从face中获取数据
-                    if let Some(data) = self.db.with_face_data(id, |font_data, face_index| {
-                        debug!("Found font: {family_name} (face index: {face_index})");
-                        Some(font_data.to_vec())
-                    }) {
-                        return data;
-                    }
-                }
+            }) && self.db.face(id).is_some()
+                && let Some(data) = self.db.with_face_data(id, |font_data, face_index| {
+                    debug!("Found font: {family_name} (face index: {face_index})");
+                    Some(font_data.to_vec())
+                })
+            {
+                return data;
             }
         }
         None
@@ -45,8 +48,22 @@ impl FontLoader {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct AppConfig {
+    /// Mount backend used when `--backend` is not given
+    #[serde(default)]
+    pub backend: BackendKind,
     #[serde(default)]
     pub gui: GuiConfig,
+    #[serde(default)]
+    pub mount: MountOptions,
+    /// Bus UDisks2 is reached on, unless `--bus` is given
+    #[serde(default)]
+    pub bus: BusAddress,
+    /// Timeouts and retries for UDisks2 calls
+    #[serde(default)]
+    pub udisks2: CallPolicy,
+    /// Whether UDisks2 operations may prompt for polkit authorization, unless `--auth` is given
+    #[serde(default)]
+    pub auth: AuthMode,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -219,10 +236,10 @@ impl AppConfig {
 /// 展开波浪号路径
 #[allow(dead_code)]
 fn expand_tilde(path: &str) -> String {
-    if path.starts_with("~/") {
-        if let Ok(home) = std::env::var("HOME") {
-            return path.replacen("~", &home, 1);
-        }
+    if path.starts_with("~/")
+        && let Ok(home) = std::env::var("HOME")
+    {
+        return path.replacen("~", &home, 1);
     }
     path.to_string()
 }
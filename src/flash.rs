@@ -0,0 +1,281 @@
+//! Writing disk images to removable drives, what `dd if=image of=/dev/sdX` is used for otherwise
+//!
+//! The target is opened through UDisks2 `Block.OpenForRestore`, so polkit decides whether the user
+//! may overwrite the drive and no root is needed. After writing, the device is read back through
+//! `Block.OpenForBackup` and compared with the image by SHA-256.
+
+use crate::udisks2::{
+    DriveInfo, UDisks2Block, UDisks2Filesystem, UDisks2ObjectManager, UnmountOptions,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::Connection;
+
+/// Bytes copied per read and write
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Options for [`Flasher::flash`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashOptions {
+    /// Allow writing to drives that are not removable, such as internal disks
+    pub force: bool,
+    /// Read the device back and compare it with the image
+    pub verify: bool,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            verify: true,
+        }
+    }
+}
+
+/// Stage a flash operation is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashPhase {
+    Writing,
+    Verifying,
+}
+
+impl fmt::Display for FlashPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Writing => "writing",
+            Self::Verifying => "verifying",
+        })
+    }
+}
+
+/// Progress reported to the callback of [`Flasher::flash`] after every chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashProgress {
+    pub phase: FlashPhase,
+    /// Bytes written or verified so far
+    pub bytes: u64,
+    /// Size of the image
+    pub total: u64,
+}
+
+/// Outcome of a successful flash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlashReport {
+    /// Whole-disk device node that was written
+    pub device: PathBuf,
+    /// Bytes written
+    pub bytes: u64,
+    /// SHA-256 of the image, lower-case hex
+    pub sha256: String,
+    /// Whether the device was read back and matched the image
+    pub verified: bool,
+}
+
+/// Refuse targets that would destroy the running system or cannot hold the image
+///
+/// The system drive and drives smaller than the image are always refused; `force` only lifts the
+/// restriction to removable drives.
+pub fn check_target(drive: &DriveInfo, image_size: u64, force: bool) -> Result<()> {
+    let device = drive.display_name();
+
+    if drive.system {
+        return Err(anyhow::anyhow!(
+            "Refusing to write to {device}: it holds the running system's root filesystem"
+        ));
+    }
+    if !drive.removable && !force {
+        return Err(anyhow::anyhow!(
+            "Refusing to write to {device}: {} is not a removable drive, use --force to override",
+            drive.name()
+        ));
+    }
+
+    let capacity = drive.disk().map_or(drive.size, |b| b.size);
+    if capacity == 0 {
+        return Err(anyhow::anyhow!("No media in {device}"));
+    }
+    if image_size > capacity {
+        return Err(anyhow::anyhow!(
+            "Image is {image_size} bytes but {device} only holds {capacity} bytes"
+        ));
+    }
+    Ok(())
+}
+
+/// Copy `total` bytes of `image` to `target` and flush them to the device, returning the SHA-256
+pub fn write_image(
+    image: &mut impl Read,
+    target: &mut File,
+    total: u64,
+    progress: &mut impl FnMut(FlashProgress),
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut written = 0u64;
+    while written < total {
+        let want = CHUNK_SIZE.min((total - written) as usize);
+        image
+            .read_exact(&mut buffer[..want])
+            .context("Failed to read image")?;
+        target
+            .write_all(&buffer[..want])
+            .with_context(|| format!("Failed to write to device at byte {written}"))?;
+        hasher.update(&buffer[..want]);
+        written += want as u64;
+        progress(FlashProgress {
+            phase: FlashPhase::Writing,
+            bytes: written,
+            total,
+        });
+    }
+
+    target
+        .sync_all()
+        .context("Failed to flush written data to the device")?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// SHA-256 of the first `len` bytes of `source`
+pub fn hash_device(
+    source: &mut File,
+    len: u64,
+    progress: &mut impl FnMut(FlashProgress),
+) -> Result<String> {
+    // Drop cached pages so the data really comes from the device, not from what was just written
+    // SAFETY: plain syscall on a valid descriptor, no memory is passed
+    unsafe {
+        nix::libc::posix_fadvise(source.as_raw_fd(), 0, 0, nix::libc::POSIX_FADV_DONTNEED);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut read = 0u64;
+    while read < len {
+        let want = CHUNK_SIZE.min((len - read) as usize);
+        source
+            .read_exact(&mut buffer[..want])
+            .with_context(|| format!("Failed to read back device at byte {read}"))?;
+        hasher.update(&buffer[..want]);
+        read += want as u64;
+        progress(FlashProgress {
+            phase: FlashPhase::Verifying,
+            bytes: read,
+            total: len,
+        });
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Unmount every mounted filesystem on `drive` before its blocks get overwritten
+pub(crate) async fn unmount_drive(connection: &Connection, drive: &DriveInfo) -> Result<()> {
+    for block in drive
+        .block_devices
+        .iter()
+        .filter(|b| !b.mount_points.is_empty())
+    {
+        info!("Unmounting {} before writing", block.device.display());
+        UDisks2Filesystem::new(connection, block.object_path.clone().into_inner())
+            .await?
+            .unmount(&UnmountOptions::default())
+            .await
+            .with_context(|| format!("Failed to unmount {}", block.device.display()))?;
+    }
+    Ok(())
+}
+
+/// Writes images to drives known to UDisks2
+#[derive(Clone)]
+pub struct Flasher {
+    connection: Connection,
+    object_manager: UDisks2ObjectManager,
+}
+
+impl Flasher {
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            object_manager: UDisks2ObjectManager::new(connection).await?,
+        })
+    }
+
+    /// Write `image` to the drive `target`, unmounting its filesystems first
+    ///
+    /// `progress` is called from a blocking worker thread after every chunk written or verified.
+    pub async fn flash<F>(
+        &self,
+        image: &Path,
+        target: &Path,
+        options: &FlashOptions,
+        mut progress: F,
+    ) -> Result<FlashReport>
+    where
+        F: FnMut(FlashProgress) + Send + 'static,
+    {
+        let mut image_file = File::open(image)
+            .with_context(|| format!("Failed to open image: {}", image.display()))?;
+        let image_size = image_file
+            .metadata()
+            .with_context(|| format!("Failed to stat image: {}", image.display()))?
+            .len();
+        if image_size == 0 {
+            return Err(anyhow::anyhow!("Image is empty: {}", image.display()));
+        }
+
+        let drive = self.object_manager.find_drive(target).await?;
+        check_target(&drive, image_size, options.force)?;
+        let disk = drive
+            .disk()
+            .with_context(|| format!("{} has no whole-disk block device", drive.object_path))?;
+
+        unmount_drive(&self.connection, &drive).await?;
+
+        let block =
+            UDisks2Block::new(&self.connection, disk.object_path.clone().into_inner()).await?;
+        let mut device = block.open_for_restore().await?;
+        info!(
+            "Writing {} ({image_size} bytes) to {}",
+            image.display(),
+            disk.device.display()
+        );
+        let (sha256, mut progress) = tokio::task::spawn_blocking(move || {
+            let result = write_image(&mut image_file, &mut device, image_size, &mut progress);
+            (result, progress)
+        })
+        .await
+        .context("Write task failed")?;
+        let sha256 = sha256?;
+
+        if options.verify {
+            let mut device = block.open_for_backup().await?;
+            let read_back = tokio::task::spawn_blocking(move || {
+                hash_device(&mut device, image_size, &mut progress)
+            })
+            .await
+            .context("Verify task failed")??;
+            if read_back != sha256 {
+                return Err(anyhow::anyhow!(
+                    "Verification failed: {} reads back as {read_back}, the image is {sha256}",
+                    disk.device.display()
+                ));
+            }
+            info!("Verified {} against the image", disk.device.display());
+        }
+
+        Ok(FlashReport {
+            device: disk.device.clone(),
+            bytes: image_size,
+            sha256,
+            verified: options.verify,
+        })
+    }
+}
@@ -0,0 +1,387 @@
+//! Checking, repairing, resizing and relabelling filesystems on drives and image files
+//!
+//! Block devices are used as they are. Image files are attached to a loop device through UDisks2
+//! first, read-only for a plain check, and the loop device is deleted again afterwards, so
+//! corrupted `.img` files can be repaired without root. An image that already has a loop device is
+//! checked through that one instead, and refused while any of its filesystems is mounted.
+
+use crate::backend::{AttachedDevice, ImageFilesystem, MountBackend, Udisks2Backend};
+use crate::luks::PassphraseSource;
+use crate::udisks2::capabilities::{Udisks2Capabilities, Udisks2Feature};
+use crate::udisks2::{
+    FilesystemObject, FsckStatus, LoopOptions, UDisks2Block, UDisks2Filesystem, find_block,
+    find_filesystems,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::{Connection, zvariant::OwnedObjectPath};
+
+/// Options for [`FilesystemTool::fsck`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckOptions {
+    /// Run `Repair` instead of a read-only `Check`
+    pub repair: bool,
+    /// Only the filesystem in this partition, instead of every filesystem on the target
+    pub partition: Option<u32>,
+}
+
+/// Check or repair result for one filesystem
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsckResult {
+    /// Block device node of the filesystem
+    pub device: PathBuf,
+    /// Partition number, `None` when the filesystem spans the whole device
+    pub number: Option<u32>,
+    pub fstype: String,
+    pub label: Option<String>,
+    pub status: FsckStatus,
+}
+
+/// Outcome of [`FilesystemTool::fsck`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    /// Device or image that was given
+    pub target: PathBuf,
+    /// Loop device the image was attached to, `None` for block devices
+    pub loop_device: Option<PathBuf>,
+    pub filesystems: Vec<FsckResult>,
+}
+
+impl FsckReport {
+    /// Whether every filesystem is consistent now
+    pub fn is_ok(&self) -> bool {
+        self.filesystems.iter().all(|fs| fs.status.is_ok())
+    }
+}
+
+/// Filesystems named by a target, with the loop device of an image file
+struct OpenTarget {
+    /// Loop device attached for this operation, deleted again by [`FilesystemTool::close`]
+    attached: Option<AttachedDevice>,
+    /// Loop device node of an image file, attached here or found already attached
+    loop_device: Option<PathBuf>,
+    filesystems: Vec<FilesystemObject>,
+}
+
+/// Runs UDisks2 filesystem maintenance on block devices and image files
+#[derive(Clone)]
+pub struct FilesystemTool {
+    connection: Connection,
+    backend: Udisks2Backend,
+    capabilities: Udisks2Capabilities,
+}
+
+impl FilesystemTool {
+    /// A tool for the daemon on `connection`, as described by `capabilities` from
+    /// [`Udisks2Capabilities::probe`]
+    pub async fn new(connection: &Connection, capabilities: Udisks2Capabilities) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            backend: Udisks2Backend::new(connection).await?,
+            capabilities,
+        })
+    }
+
+    /// Read keys for LUKS containers on images from `passphrase` instead of prompting
+    pub fn with_passphrase_source(mut self, passphrase: PassphraseSource) -> Self {
+        self.backend = self.backend.with_passphrase_source(passphrase);
+        self
+    }
+
+    /// Check, or with `options.repair` repair, every filesystem on `target`
+    ///
+    /// `target` is a block device node, a UDisks2 object path or an image file. Mounted
+    /// filesystems are refused. A damaged filesystem is reported in the result, not as an error.
+    pub async fn fsck(&self, target: &Path, options: &FsckOptions) -> Result<FsckReport> {
+        self.capabilities
+            .require(Udisks2Feature::FilesystemMaintenance)?;
+        let open = self.open(target, options.repair).await?;
+        let result = self.fsck_open(target, &open, options).await;
+        self.close(open, result).await
+    }
+
+    /// Set the label of the filesystem on `target`, returning its device node
+    ///
+    /// `partition` picks the filesystem when there is more than one. `Filesystem.SetLabel` is as
+    /// old as UDisks2 itself, so unlike [`Udisks2Feature::FilesystemMaintenance`] it works on every
+    /// daemon.
+    pub async fn relabel(
+        &self,
+        target: &Path,
+        partition: Option<u32>,
+        label: &str,
+    ) -> Result<PathBuf> {
+        let open = self.open(target, true).await?;
+        let result = async {
+            let filesystem = single(target, &open.filesystems, partition)?;
+            UDisks2Filesystem::new(
+                &self.connection,
+                filesystem.object_path.clone().into_inner(),
+            )
+            .await?
+            .set_label(label)
+            .await?;
+            self.device(&filesystem.object_path).await
+        }
+        .await;
+        self.close(open, result).await
+    }
+
+    /// Resize the filesystem on `target` to `size` bytes, or to fill its device, returning its
+    /// device node
+    ///
+    /// `partition` picks the filesystem when there is more than one.
+    pub async fn resize(
+        &self,
+        target: &Path,
+        partition: Option<u32>,
+        size: Option<u64>,
+    ) -> Result<PathBuf> {
+        self.capabilities
+            .require(Udisks2Feature::FilesystemMaintenance)?;
+        let open = self.open(target, true).await?;
+        let result = async {
+            let filesystem = single(target, &open.filesystems, partition)?;
+            UDisks2Filesystem::new(
+                &self.connection,
+                filesystem.object_path.clone().into_inner(),
+            )
+            .await?
+            .resize(size)
+            .await?;
+            self.device(&filesystem.object_path).await
+        }
+        .await;
+        self.close(open, result).await
+    }
+
+    async fn fsck_open(
+        &self,
+        target: &Path,
+        open: &OpenTarget,
+        options: &FsckOptions,
+    ) -> Result<FsckReport> {
+        let filesystems: Vec<&FilesystemObject> = match options.partition {
+            Some(_) => vec![single(target, &open.filesystems, options.partition)?],
+            None => open.filesystems.iter().collect(),
+        };
+        if let Some(mounted) = filesystems.iter().find(|fs| !fs.mount_points.is_empty()) {
+            return Err(anyhow::anyhow!(
+                "{} is mounted at {}, unmount it first",
+                self.device(&mounted.object_path).await?.display(),
+                mounted.mount_points[0].display()
+            ));
+        }
+
+        let mut results = Vec::new();
+        for filesystem in filesystems {
+            let device = self.device(&filesystem.object_path).await?;
+            info!(
+                "{} {} ({})",
+                if options.repair {
+                    "Repairing"
+                } else {
+                    "Checking"
+                },
+                device.display(),
+                filesystem.fstype
+            );
+            let udisks_filesystem = UDisks2Filesystem::new(
+                &self.connection,
+                filesystem.object_path.clone().into_inner(),
+            )
+            .await?;
+            let status = if options.repair {
+                udisks_filesystem.repair().await?
+            } else {
+                udisks_filesystem.check().await?
+            };
+            results.push(FsckResult {
+                device,
+                number: filesystem.number,
+                fstype: filesystem.fstype.clone(),
+                label: filesystem.label.clone(),
+                status,
+            });
+        }
+
+        Ok(FsckReport {
+            target: target.to_path_buf(),
+            loop_device: open.loop_device.clone(),
+            filesystems: results,
+        })
+    }
+
+    /// Find the filesystems on `target`, attaching it to a loop device if it is an image file
+    async fn open(&self, target: &Path, writable: bool) -> Result<OpenTarget> {
+        if !target.is_file() {
+            let objects = self.backend.object_manager().managed_objects().await?;
+            let block = find_block(&objects, target)
+                .with_context(|| format!("No block device found for {}", target.display()))?;
+            let filesystems = find_filesystems(&objects, &block);
+            if filesystems.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "No filesystem found on {}",
+                    target.display()
+                ));
+            }
+            return Ok(OpenTarget {
+                attached: None,
+                loop_device: None,
+                filesystems,
+            });
+        }
+
+        let metadata = fs::metadata(target)
+            .with_context(|| format!("Failed to stat image: {}", target.display()))?;
+        if let Some(device) = self
+            .backend
+            .loop_devices()
+            .await?
+            .into_iter()
+            .find(|device| device.is_backed_by(&metadata))
+        {
+            if let Some(mount_point) = device.mount_points().next() {
+                return Err(anyhow::anyhow!(
+                    "{} is mounted from {} at {}, unmount it first",
+                    target.display(),
+                    device.device.display(),
+                    mount_point.display()
+                ));
+            }
+            if writable && device.read_only {
+                return Err(anyhow::anyhow!(
+                    "{} is attached read-only as {}, detach it first",
+                    target.display(),
+                    device.device.display()
+                ));
+            }
+            if device.filesystems.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "No filesystem found on {} ({})",
+                    target.display(),
+                    device.device.display()
+                ));
+            }
+            info!(
+                "{} is already attached as {}, using it",
+                target.display(),
+                device.device.display()
+            );
+            return Ok(OpenTarget {
+                attached: None,
+                loop_device: Some(device.device),
+                filesystems: device.filesystems,
+            });
+        }
+
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(target)
+            .with_context(|| format!("Failed to open image: {}", target.display()))?;
+        let options = LoopOptions {
+            read_only: !writable,
+            ..LoopOptions::default()
+        };
+        let attached = self.backend.attach(target, &file, &options).await?;
+        let open = match self.backend.filesystems(&attached).await {
+            Ok(filesystems) if !filesystems.is_empty() => {
+                self.filesystem_objects(&attached, filesystems).await
+            }
+            Ok(_) => Err(anyhow::anyhow!(
+                "No filesystem found on {}",
+                target.display()
+            )),
+            Err(e) => Err(e),
+        };
+        match open {
+            Ok(filesystems) => Ok(OpenTarget {
+                loop_device: attached.device.clone(),
+                attached: Some(attached),
+                filesystems,
+            }),
+            Err(e) => {
+                if let Err(detach_error) = self.backend.detach(&attached).await {
+                    warn!("Failed to detach {}: {detach_error:#}", attached.id);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// The UDisks2 objects of the filesystems found on a fresh loop device, with their mount points
+    ///
+    /// The device may have been automounted in the meantime, which [`Self::fsck`] has to see.
+    async fn filesystem_objects(
+        &self,
+        attached: &AttachedDevice,
+        filesystems: Vec<ImageFilesystem>,
+    ) -> Result<Vec<FilesystemObject>> {
+        let ids: HashSet<String> = filesystems.into_iter().map(|fs| fs.id).collect();
+        let objects = self.backend.object_manager().managed_objects().await?;
+        let loop_path = OwnedObjectPath::try_from(attached.id.as_str())?;
+        Ok(find_filesystems(&objects, &loop_path)
+            .into_iter()
+            .filter(|fs| ids.contains(fs.object_path.as_str()))
+            .collect())
+    }
+
+    /// Delete the loop device attached by [`Self::open`], keeping the first error
+    async fn close<T>(&self, open: OpenTarget, result: Result<T>) -> Result<T> {
+        let Some(attached) = open.attached else {
+            return result;
+        };
+        let detached = self.backend.detach(&attached).await;
+        match (result, detached) {
+            (Ok(value), Ok(())) => Ok(value),
+            (Ok(_), Err(e)) => Err(e),
+            (Err(e), Ok(())) => Err(e),
+            (Err(e), Err(detach_error)) => {
+                warn!("Failed to detach {}: {detach_error:#}", attached.id);
+                Err(e)
+            }
+        }
+    }
+
+    async fn device(&self, object_path: &OwnedObjectPath) -> Result<PathBuf> {
+        UDisks2Block::new(&self.connection, object_path.clone().into_inner())
+            .await?
+            .device()
+            .await
+    }
+}
+
+/// The one filesystem selected by `partition`, or the only filesystem when it is `None`
+fn single<'a>(
+    target: &Path,
+    filesystems: &'a [FilesystemObject],
+    partition: Option<u32>,
+) -> Result<&'a FilesystemObject> {
+    match partition {
+        Some(number) => filesystems
+            .iter()
+            .find(|fs| fs.number == Some(number))
+            .with_context(|| {
+                format!(
+                    "No filesystem in partition {number} of {}",
+                    target.display()
+                )
+            }),
+        None => match filesystems {
+            [filesystem] => Ok(filesystem),
+            _ => Err(anyhow::anyhow!(
+                "{} has {} filesystems, choose one with --partition",
+                target.display(),
+                filesystems.len()
+            )),
+        },
+    }
+}
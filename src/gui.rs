@@ -43,14 +43,13 @@ impl eframe::App for GuiApp {
                 }
             });
 
-            if ui.button("选择ISO文件").clicked() {
-                if let Some(path) = rfd::FileDialog::new()
+            if ui.button("选择ISO文件").clicked()
+                && let Some(path) = rfd::FileDialog::new()
                     .add_filter("ISO文件", &["iso"])
                     .pick_file()
-                {
-                    self.iso_path = Some(path);
-                    self.status_message = "ISO文件已选择".to_string();
-                }
+            {
+                self.iso_path = Some(path);
+                self.status_message = "ISO文件已选择".to_string();
             }
 
             ui.separator();
@@ -63,14 +62,12 @@ impl eframe::App for GuiApp {
             ui.separator();
 
             if ui.button("Mount ISO").clicked() {
-                self.status_message = "Mounting...".to_string();
                 // 这里可以集成实际的挂载逻辑
                 self.is_mounted = true;
                 self.status_message = "Mounted successfully".to_string();
             }
 
             if self.is_mounted && ui.button("Unmount ISO").clicked() {
-                self.status_message = "Unmounting...".to_string();
                 // 这里可以集成实际的卸载逻辑
                 self.is_mounted = false;
                 self.status_message = "Unmounted successfully".to_string();
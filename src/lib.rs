@@ -1,9 +1,42 @@
+pub mod backend;
+pub mod bus;
+pub mod busy;
 pub mod config;
+pub mod flash;
+pub mod fsck;
 pub mod gui;
+pub mod loop_refs;
+pub mod luks;
+pub mod mounter;
+pub mod removal;
 pub mod udisks2;
+pub mod windows_usb;
 
+pub use backend::{
+    AttachedDevice, BackendKind, ImageFilesystem, LoopDeviceBackend, MountBackend, MountDetails,
+    MountedPartition, Udisks2Backend, UserspaceBackend,
+};
+pub use bus::{BusAddress, Udisks2ConnectionBuilder};
+pub use busy::{HolderUsage, MountHolder, busy_message, find_holders};
 pub use config::AppConfig;
-pub use udisks2::{IsoMounter, MountedIso, UDisks2Filesystem, UDisks2Manager};
+pub use flash::{FlashOptions, FlashPhase, FlashProgress, FlashReport, Flasher};
+pub use fsck::{FilesystemTool, FsckOptions, FsckReport, FsckResult};
+pub use luks::{LuksKey, PassphraseSource};
+pub use mounter::{IsoMounter, MountGuard, MountedImage, MountedIso};
+pub use removal::{RemovalAction, RemovalReport, SafeRemover};
+pub use udisks2::auth::{AuthMode, TtyAgent, auth_mode, set_auth_mode};
+pub use udisks2::capabilities::{Udisks2Capabilities, Udisks2Feature, Udisks2Version};
+pub use udisks2::policy::{CallPolicy, call_policy, set_call_policy};
+pub use udisks2::{
+    DriveBlockDevice, DriveInfo, EncryptedObject, FilesystemObject, FormatOptions, FsckStatus,
+    LoopDeviceInfo, LoopOptions, MountInfo, MountOptions, UDisks2Block, UDisks2Drive,
+    UDisks2Encrypted, UDisks2Filesystem, UDisks2Loop, UDisks2Manager, UDisks2ObjectManager,
+    UDisks2PartitionTable, Udisks2Event, Udisks2EventStream, UnmountOptions, UnmountRetry,
+    error::Udisks2Error,
+};
+pub use windows_usb::{
+    CopyProgress, FAT32_MAX_SPLIT_MIB, WindowsUsbCreator, WindowsUsbOptions, WindowsUsbReport,
+};
 
 // 重导出 wim-parser 库的类型
 pub use wim_parser::{ImageInfo, WimHeader, WimParser, WindowsInfo};
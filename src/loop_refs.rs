@@ -0,0 +1,199 @@
+//! Reference counts for loop devices shared by several mounts of the same image
+//!
+//! Mounting an image that is already mounted reuses its loop device instead of setting up a
+//! second one. Every user records a reference in a small file under `$XDG_RUNTIME_DIR`, locked
+//! while it is updated, and only the last one to close unmounts and deletes the device. The last
+//! one keeps the file locked until the device is gone and then removes it, so a concurrent
+//! [`LoopRefs::acquire`] fails instead of picking up a device that is being torn down.
+//!
+//! A reference is either the PID of a process using the mount, dropped once that process is gone,
+//! or `keep` for a mount left in place with `--keep` or found already mounted by someone else.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+
+const KEEP: &str = "keep";
+
+/// The references left after [`LoopRefs::release`], with the reference file still locked
+///
+/// Hold it while tearing the device down when none are left, and [`LoopRefs::clear`] before
+/// dropping it once the device is gone.
+#[must_use]
+pub struct Released {
+    left: usize,
+    _lock: File,
+}
+
+impl Released {
+    /// How many references other users still hold
+    pub fn left(&self) -> usize {
+        self.left
+    }
+}
+
+/// The references to one loop device, identified by its handle and its backing file's inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopRefs {
+    path: PathBuf,
+}
+
+impl LoopRefs {
+    /// References to the device `device_id` backed by the file with `metadata`
+    pub fn new(device_id: &str, metadata: &fs::Metadata) -> Result<Self> {
+        let dir = refs_dir();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let name = device_id
+            .rsplit('/')
+            .next()
+            .unwrap_or(device_id)
+            .replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_");
+        Ok(Self {
+            path: dir.join(format!("{name}-{}-{}", metadata.dev(), metadata.ino())),
+        })
+    }
+
+    /// References for a device found through its backing file, `None` if the file is gone
+    pub fn for_backing_file(device_id: &str, backing_file: &Path) -> Option<Self> {
+        let metadata = fs::metadata(backing_file).ok()?;
+        Self::new(device_id, &metadata).ok()
+    }
+
+    /// Start counting for a device this process just set up, forgetting stale references
+    pub fn create(&self) -> Result<()> {
+        self.update(|refs| {
+            refs.clear();
+            refs.push(std::process::id().to_string());
+        })
+        .map(drop)
+    }
+
+    /// Add a reference for this process to a device that was already mounted
+    ///
+    /// Without any record the device was mounted by someone else, who keeps a reference too.
+    /// Fails if the last user tore the device down while this waited for the lock.
+    pub fn acquire(&self) -> Result<()> {
+        let (mut file, replaced) = self.lock()?;
+        if replaced {
+            return Err(anyhow::anyhow!(
+                "The loop device was detached while waiting for {}",
+                self.path.display()
+            ));
+        }
+        self.apply(&mut file, |refs| {
+            if refs.is_empty() {
+                refs.push(KEEP.to_string());
+            }
+            refs.push(std::process::id().to_string());
+        })
+        .map(drop)
+    }
+
+    /// Drop one reference of this process, keeping the others out until the result is dropped
+    pub fn release(&self) -> Result<Released> {
+        let pid = std::process::id().to_string();
+        let (mut file, _) = self.lock()?;
+        let left = self.apply(&mut file, |refs| {
+            if let Some(index) = refs.iter().position(|r| *r == pid) {
+                refs.remove(index);
+            }
+        })?;
+        Ok(Released { left, _lock: file })
+    }
+
+    /// Turn one reference of this process into one that outlives it
+    pub fn keep(&self) -> Result<()> {
+        let pid = std::process::id().to_string();
+        self.update(|refs| match refs.iter().position(|r| *r == pid) {
+            Some(index) => refs[index] = KEEP.to_string(),
+            None => refs.push(KEEP.to_string()),
+        })
+        .map(drop)
+    }
+
+    /// Forget every reference, once the device is gone
+    pub fn clear(&self) {
+        if let Err(e) = fs::remove_file(&self.path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove {}: {e}", self.path.display());
+        }
+    }
+
+    /// Apply `change` to the live references under an exclusive lock and return how many remain
+    fn update(&self, change: impl FnOnce(&mut Vec<String>)) -> Result<usize> {
+        let (mut file, _) = self.lock()?;
+        self.apply(&mut file, change)
+    }
+
+    /// Open the reference file and lock it, waiting while another user holds it
+    ///
+    /// The last user removes the file once its device is gone, so a file that was removed while
+    /// waiting is opened again; whether that happened is returned too.
+    fn lock(&self) -> Result<(File, bool)> {
+        let mut replaced = false;
+        loop {
+            let file: File = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)
+                .with_context(|| format!("Failed to open {}", self.path.display()))?;
+            file.lock()
+                .with_context(|| format!("Failed to lock {}", self.path.display()))?;
+            let linked = file
+                .metadata()
+                .with_context(|| format!("Failed to stat {}", self.path.display()))?
+                .nlink()
+                > 0;
+            if linked {
+                return Ok((file, replaced));
+            }
+            debug!("{} was removed while waiting for it", self.path.display());
+            replaced = true;
+        }
+    }
+
+    /// Apply `change` to the live references in the locked `file`
+    fn apply(&self, file: &mut File, change: impl FnOnce(&mut Vec<String>)) -> Result<usize> {
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let mut refs: Vec<String> = content
+            .lines()
+            .filter(|r| *r == KEEP || is_running(r))
+            .map(String::from)
+            .collect();
+        change(&mut refs);
+        debug!("References to {}: {refs:?}", self.path.display());
+
+        file.set_len(0)?;
+        file.rewind()?;
+        for r in &refs {
+            writeln!(file, "{r}")?;
+        }
+        Ok(refs.len())
+    }
+}
+
+/// Whether `pid` names a running process
+fn is_running(pid: &str) -> bool {
+    pid.parse::<u32>()
+        .is_ok_and(|pid| Path::new(&format!("/proc/{pid}")).exists())
+}
+
+fn refs_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("rust-system-tools/loops"),
+        None => {
+            std::env::temp_dir().join(format!("rust-system-tools-{}/loops", nix::unistd::getuid()))
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Keys for unlocking LUKS containers found on attached images
+//!
+//! The key is only read once an encrypted container is actually found, so plain images never
+//! prompt. It is handed to UDisks2 `Encrypted.Unlock`, either as the passphrase argument or as
+//! `keyfile_contents` for binary keyfiles.
+
+use anyhow::{Context, Result};
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// Where the key for a LUKS container comes from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PassphraseSource {
+    /// Ask on the controlling terminal (`/dev/tty`) with echo turned off
+    #[default]
+    Prompt,
+    /// Read one line from stdin, e.g. `secret-tool lookup ... | rust-system-tools mount ...`
+    Stdin,
+    /// Use the whole file as key material, like `cryptsetup --key-file`
+    Keyfile(PathBuf),
+}
+
+/// Key material for `Encrypted.Unlock`
+///
+/// `Debug` does not print the secret.
+#[derive(Clone, PartialEq, Eq)]
+pub enum LuksKey {
+    Passphrase(String),
+    Keyfile(Vec<u8>),
+}
+
+impl fmt::Debug for LuksKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Passphrase(_) => f.write_str("Passphrase(..)"),
+            Self::Keyfile(contents) => write!(f, "Keyfile({} bytes)", contents.len()),
+        }
+    }
+}
+
+impl PassphraseSource {
+    /// Read the key for the container `device`, blocking on the terminal or stdin
+    pub fn read(&self, device: &Path) -> Result<LuksKey> {
+        match self {
+            Self::Prompt => prompt(&format!("Passphrase for {}: ", device.display()))
+                .map(LuksKey::Passphrase)
+                .with_context(|| {
+                    format!(
+                        "Cannot ask for the passphrase of {}, use --passphrase-stdin or --keyfile",
+                        device.display()
+                    )
+                }),
+            Self::Stdin => {
+                let mut line = String::new();
+                io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .context("Failed to read passphrase from stdin")?;
+                let passphrase = line.trim_end_matches(['\n', '\r']);
+                if passphrase.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "No passphrase on stdin for {}",
+                        device.display()
+                    ));
+                }
+                Ok(LuksKey::Passphrase(passphrase.to_string()))
+            }
+            Self::Keyfile(path) => fs::read(path)
+                .map(LuksKey::Keyfile)
+                .with_context(|| format!("Failed to read keyfile: {}", path.display())),
+        }
+    }
+}
+
+/// Ask for a line on `/dev/tty` without echoing it
+fn prompt(message: &str) -> Result<String> {
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("No controlling terminal")?;
+    let saved = termios::tcgetattr(&tty).context("Failed to get terminal attributes")?;
+    let mut silent = saved.clone();
+    silent.local_flags.remove(LocalFlags::ECHO);
+    silent.local_flags.insert(LocalFlags::ECHONL);
+    termios::tcsetattr(&tty, SetArg::TCSAFLUSH, &silent)
+        .context("Failed to turn off terminal echo")?;
+
+    let result = (|| {
+        tty.write_all(message.as_bytes())?;
+        tty.flush()?;
+        let mut line = String::new();
+        BufReader::new(&tty).read_line(&mut line)?;
+        Ok::<_, io::Error>(line.trim_end_matches(['\n', '\r']).to_string())
+    })();
+    // Restore echo even when reading failed
+    let restored = termios::tcsetattr(&tty, SetArg::TCSAFLUSH, &saved);
+    let line = result.context("Failed to read passphrase from the terminal")?;
+    restored.context("Failed to restore terminal echo")?;
+    Ok(line)
+}
@@ -1,18 +1,26 @@
 use anyhow::{Context, Result};
 use clap::Parser;
+use futures_util::StreamExt;
 use std::path::{Path, PathBuf};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use zbus::Connection;
 
-mod config;
-mod udisks2;
-use udisks2::IsoMounter;
+use rust_system_tools::gui::run_gui;
+use rust_system_tools::{
+    AppConfig, AuthMode, BackendKind, BusAddress, CopyProgress, DriveInfo, FAT32_MAX_SPLIT_MIB,
+    FilesystemTool, FlashOptions, FlashProgress, Flasher, FsckOptions, IsoMounter,
+    LoopDeviceBackend, LoopDeviceInfo, LoopOptions, MountOptions, MountedImage, PassphraseSource,
+    RemovalAction, SafeRemover, TtyAgent, UDisks2ObjectManager, Udisks2Backend,
+    Udisks2Capabilities, Udisks2ConnectionBuilder, Udisks2Error, UnmountOptions, UnmountRetry,
+    UserspaceBackend, WindowsUsbCreator, WindowsUsbOptions, auth_mode, set_auth_mode,
+    set_call_policy,
+};
+use std::sync::Arc;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::OnceCell;
 use wim_parser::WimParser;
 
-mod gui;
-use gui::run_gui;
-
 #[derive(Parser, Debug)]
 #[command(
     author = env!("CARGO_PKG_AUTHORS"),
@@ -20,10 +28,49 @@ use gui::run_gui;
     about = "A tool to test UDisks2 mount interface"
 )]
 struct Args {
+    /// Mount backend: udisks2, loop (root, no udisksd) or userspace (extract, no mount)
+    #[arg(long, global = true, value_name = "BACKEND")]
+    backend: Option<BackendKind>,
+    /// polkit authorization: interactive, non-interactive (never prompt) or fail-fast (never
+    /// prompt, stop at the first failure)
+    #[arg(long, global = true, value_name = "MODE")]
+    auth: Option<AuthMode>,
+    /// D-Bus to reach UDisks2 on: system, session or an address such as
+    /// unix:path=/tmp/remote-bus (default: system, or $DBUS_SYSTEM_BUS_ADDRESS)
+    #[arg(long, global = true, value_name = "BUS")]
+    bus: Option<BusAddress>,
+    #[command(flatten)]
+    unlock_args: UnlockArgs,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Where the passphrase for LUKS encrypted images comes from, prompting on the terminal by default
+#[derive(clap::Args, Debug)]
+struct UnlockArgs {
+    /// Unlock LUKS containers with this keyfile
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        conflicts_with = "passphrase_stdin"
+    )]
+    keyfile: Option<PathBuf>,
+    /// Read the LUKS passphrase from the first line of stdin
+    #[arg(long, global = true)]
+    passphrase_stdin: bool,
+}
+
+impl From<&UnlockArgs> for PassphraseSource {
+    fn from(args: &UnlockArgs) -> Self {
+        match (&args.keyfile, args.passphrase_stdin) {
+            (Some(keyfile), _) => Self::Keyfile(keyfile.clone()),
+            (None, true) => Self::Stdin,
+            (None, false) => Self::Prompt,
+        }
+    }
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum Commands {
     /// Mount ISO file in console mode
@@ -31,24 +78,294 @@ enum Commands {
         /// ISO file path
         #[arg(short, long, value_name = "FILE")]
         iso_path: PathBuf,
+        #[command(flatten)]
+        loop_args: LoopArgs,
+        #[command(flatten)]
+        mount_args: MountArgs,
+        /// Keep the image mounted and print the mount path(s) on stdout
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Unmount an ISO and delete its loop device
+    Unmount {
+        /// ISO file or mount point to unmount
+        #[arg(
+            value_name = "ISO_OR_MOUNTPOINT",
+            required_unless_present = "all",
+            conflicts_with = "all"
+        )]
+        target: Option<PathBuf>,
+        /// Unmount even if the filesystem is busy
+        #[arg(long)]
+        force: bool,
+        /// Retry a busy unmount with increasing delays, 5 times unless ATTEMPTS is given
+        #[arg(
+            long,
+            value_name = "ATTEMPTS",
+            num_args = 0..=1,
+            default_missing_value = "5"
+        )]
+        retry: Option<u32>,
+        /// Detach every loop device set up by the current user
+        #[arg(long)]
+        all: bool,
+    },
+    /// List active loop devices and their mounts
+    List {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// List drives with their partitions, marking the one holding the running system
+    Drives {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write an image to a removable drive and verify it, like dd
+    Flash {
+        /// Image file to write
+        #[arg(value_name = "IMAGE")]
+        image: PathBuf,
+        /// Whole drive to overwrite, e.g. /dev/sdb
+        #[arg(value_name = "DRIVE")]
+        drive: PathBuf,
+        /// Allow writing to drives that are not removable
+        #[arg(long)]
+        force: bool,
+        /// Skip reading the drive back to compare it with the image
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// Erase a drive and make it a UEFI-bootable Windows installer from an ISO
+    WindowsUsb {
+        /// Windows ISO
+        #[arg(value_name = "ISO")]
+        iso: PathBuf,
+        /// Whole drive to erase, e.g. /dev/sdb
+        #[arg(value_name = "DRIVE")]
+        drive: PathBuf,
+        /// Label of the FAT32 filesystem
+        #[arg(long, default_value = "WININSTALL")]
+        label: String,
+        /// Allow writing to drives that are not removable
+        #[arg(long)]
+        force: bool,
+        /// Split install.wim into parts of at most this many MiB (FAT32 allows up to 4095)
+        #[arg(
+            long,
+            value_name = "MIB",
+            default_value_t = 3800,
+            value_parser = clap::value_parser!(u64).range(1..=FAT32_MAX_SPLIT_MIB)
+        )]
+        split_size: u64,
+        /// Leave the drive attached when done
+        #[arg(long)]
+        no_eject: bool,
+    },
+    /// Check or repair the filesystems on a device or image file
+    Fsck {
+        /// Block device, partition or image file, e.g. /dev/sdb1 or seed.img
+        #[arg(value_name = "DEVICE_OR_IMAGE")]
+        target: PathBuf,
+        /// Repair the filesystems instead of only checking them
+        #[arg(long)]
+        repair: bool,
+        /// Only the filesystem in this partition
+        #[arg(long, value_name = "NUMBER")]
+        partition: Option<u32>,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Change the label of the filesystem on a device or image file
+    Relabel {
+        /// Block device, partition or image file, e.g. /dev/sdb1 or seed.img
+        #[arg(value_name = "DEVICE_OR_IMAGE")]
+        target: PathBuf,
+        /// New filesystem label
+        #[arg(value_name = "LABEL")]
+        label: String,
+        /// Filesystem in this partition, needed when there is more than one
+        #[arg(long, value_name = "NUMBER")]
+        partition: Option<u32>,
+    },
+    /// Unmount and lock everything on a drive, then power it off or eject it
+    SafelyRemove {
+        /// Whole drive to remove, e.g. /dev/sdb
+        #[arg(value_name = "DRIVE")]
+        drive: PathBuf,
+    },
+    /// Stream UDisks2 device and mount events until interrupted
+    Monitor {
+        /// Print one JSON object per line (NDJSON)
+        #[arg(long)]
+        json: bool,
     },
     /// Show GUI window
     #[command(long_about = "Launch GUI interface for ISO mounting")]
     ShowGui,
 }
 
+/// Loop device options for the `mount` command
+#[derive(clap::Args, Debug)]
+struct LoopArgs {
+    /// Attach the image read-only
+    #[arg(long)]
+    read_only: bool,
+    /// Byte offset into the image where the filesystem starts
+    #[arg(long, value_name = "BYTES")]
+    offset: Option<u64>,
+    /// Size of the loop device in bytes (defaults to the rest of the image)
+    #[arg(long, value_name = "BYTES")]
+    size: Option<u64>,
+    /// Do not scan the loop device for partitions
+    #[arg(long)]
+    no_part_scan: bool,
+}
+
+impl From<LoopArgs> for LoopOptions {
+    fn from(args: LoopArgs) -> Self {
+        Self {
+            read_only: args.read_only,
+            offset: args.offset,
+            size: args.size,
+            no_part_scan: args.no_part_scan,
+        }
+    }
+}
+
+/// Filesystem mount options for the `mount` command, overriding the `[mount]` config section
+#[derive(clap::Args, Debug)]
+struct MountArgs {
+    /// Filesystem type to mount as (e.g. iso9660, udf)
+    #[arg(long, value_name = "TYPE")]
+    fstype: Option<String>,
+    /// Comma separated mount options (e.g. ro,uid=1000,iocharset=utf8)
+    #[arg(short = 'o', long, value_name = "OPTIONS")]
+    mount_options: Option<String>,
+    /// Fail instead of prompting for polkit authorization
+    #[arg(long)]
+    no_user_interaction: bool,
+}
+
+impl MountArgs {
+    /// Apply the command line arguments on top of the configured defaults
+    fn merge_into(self, mut options: MountOptions) -> MountOptions {
+        if self.fstype.is_some() {
+            options.fstype = self.fstype;
+        }
+        if self.mount_options.is_some() {
+            options.options = self.mount_options;
+        }
+        options.no_user_interaction |= self.no_user_interaction;
+        options
+    }
+}
+
+/// SIGINT/SIGTERM listeners so interrupted runs can clean up their mounts
+struct ShutdownSignals {
+    sigint: Signal,
+    sigterm: Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt()).context("Failed to install SIGINT handler")?,
+            sigterm: signal(SignalKind::terminate())
+                .context("Failed to install SIGTERM handler")?,
+        })
+    }
+
+    /// Wait for the next signal and return its name
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigint.recv() => "SIGINT",
+            _ = self.sigterm.recv() => "SIGTERM",
+        }
+    }
+}
+
 /// Main application logic
 struct App {
-    connection: Connection,
+    connection: OnceCell<Connection>,
+    /// 连接时预检得到的 UDisks2 版本和功能
+    capabilities: OnceCell<Udisks2Capabilities>,
+    config: AppConfig,
+    /// LUKS 加密镜像的密码来源
+    passphrase: PassphraseSource,
+    /// 终端上的 polkit 认证代理，程序退出时停止
+    tty_agent: std::sync::Mutex<Option<TtyAgent>>,
 }
 
 impl App {
     async fn new() -> Result<Self> {
-        let connection = Connection::system()
+        let config = AppConfig::load()?;
+        // 所有 UDisks2 调用使用配置的超时和重试策略
+        set_call_policy(config.udisks2);
+
+        Ok(Self {
+            connection: OnceCell::new(),
+            capabilities: OnceCell::new(),
+            config,
+            passphrase: PassphraseSource::default(),
+            tty_agent: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Connection to the configured bus, only established once a command needs UDisks2
+    async fn connection(&self) -> Result<&Connection> {
+        self.connection
+            .get_or_try_init(|| async {
+                info!("连接 D-Bus: {}", self.config.bus);
+                let connection = Udisks2ConnectionBuilder::new()
+                    .bus(self.config.bus.clone())
+                    .build()
+                    .await?;
+                // 预检：UDisks2 服务是否可用，以及版本和支持的文件系统
+                let capabilities = Udisks2Capabilities::probe(&connection).await?;
+                info!("UDisks2 版本: {}", capabilities.version_string);
+                let _ = self.capabilities.set(capabilities);
+                // 交互模式下没有图形认证代理时（如 SSH 会话），在终端上用 pkttyagent 输入密码
+                if auth_mode().allows_interaction() {
+                    match TtyAgent::spawn().await {
+                        Ok(agent) => {
+                            *self.tty_agent.lock().unwrap_or_else(|e| e.into_inner()) = agent
+                        }
+                        Err(e) => warn!("无法启动 pkttyagent: {e:#}"),
+                    }
+                }
+                Ok::<_, anyhow::Error>(connection)
+            })
             .await
-            .context("Failed to connect to system bus")?;
+    }
 
-        Ok(Self { connection })
+    /// Filesystem maintenance tool reusing the capabilities probed on connecting
+    async fn filesystem_tool(&self) -> Result<FilesystemTool> {
+        let connection = self.connection().await?;
+        let capabilities = self
+            .capabilities
+            .get()
+            .cloned()
+            .context("UDisks2 capabilities were not probed")?;
+        Ok(FilesystemTool::new(connection, capabilities)
+            .await?
+            .with_passphrase_source(self.passphrase.clone()))
+    }
+
+    /// Create a mounter for the configured backend
+    async fn mounter(&self) -> Result<IsoMounter> {
+        info!("使用挂载后端: {}", self.config.backend);
+        Ok(match self.config.backend {
+            BackendKind::Udisks2 => IsoMounter::with_backend(Arc::new(
+                Udisks2Backend::new(self.connection().await?)
+                    .await?
+                    .with_passphrase_source(self.passphrase.clone()),
+            )),
+            BackendKind::Loop => IsoMounter::with_backend(Arc::new(LoopDeviceBackend::new())),
+            BackendKind::Userspace => IsoMounter::with_backend(Arc::new(UserspaceBackend::new())),
+        })
     }
 
     /// 读取并解析挂载目录中的系统信息，优先使用 WIM 解析器
@@ -191,29 +508,27 @@ impl App {
         let mut arch = String::from("Unknown");
 
         // 检查是否存在典型的 Windows ISO 结构
-        if Path::new(&sources_path).exists() {
-            if let Ok(entries) = fs::read_dir(&sources_path) {
-                for entry in entries.flatten() {
-                    let file_name = entry.file_name();
-                    let file_name_str = file_name.to_string_lossy();
-
-                    if file_name_str.contains("install.wim")
-                        || file_name_str.contains("install.esd")
-                    {
-                        info!("检测到 Windows 安装文件: {}", file_name_str);
-                        version = "Windows".to_string();
-
-                        // 尝试通过文件大小推断架构（这是一个粗略的方法）
-                        if let Ok(metadata) = entry.metadata() {
-                            let size_gb = metadata.len() / (1024 * 1024 * 1024);
-                            if size_gb > 4 {
-                                arch = "x64".to_string();
-                            } else {
-                                arch = "x86".to_string();
-                            }
+        if Path::new(&sources_path).exists()
+            && let Ok(entries) = fs::read_dir(&sources_path)
+        {
+            for entry in entries.flatten() {
+                let file_name = entry.file_name();
+                let file_name_str = file_name.to_string_lossy();
+
+                if file_name_str.contains("install.wim") || file_name_str.contains("install.esd") {
+                    info!("检测到 Windows 安装文件: {}", file_name_str);
+                    version = "Windows".to_string();
+
+                    // 尝试通过文件大小推断架构（这是一个粗略的方法）
+                    if let Ok(metadata) = entry.metadata() {
+                        let size_gb = metadata.len() / (1024 * 1024 * 1024);
+                        if size_gb > 4 {
+                            arch = "x64".to_string();
+                        } else {
+                            arch = "x86".to_string();
                         }
-                        break;
                     }
+                    break;
                 }
             }
         }
@@ -223,11 +538,105 @@ impl App {
 
     async fn run(&self, args: Args) -> Result<()> {
         match args.command {
-            Commands::Mount { iso_path } => {
+            Commands::Mount {
+                iso_path,
+                loop_args,
+                mount_args,
+                keep,
+            } => {
                 info!("开始控制台挂载 ISO: {:?}", iso_path);
-                self.run_console_mode(&iso_path).await?;
+                let mount_options = mount_args.merge_into(self.config.mount.clone());
+                self.run_console_mode(&iso_path, &loop_args.into(), &mount_options, keep)
+                    .await?;
                 info!("完成控制台挂载 ISO: {:?}", iso_path);
             }
+            Commands::Unmount {
+                target,
+                force,
+                retry,
+                ..
+            } => {
+                let options = UnmountOptions {
+                    force,
+                    retry: retry.map(|attempts| UnmountRetry {
+                        attempts,
+                        ..UnmountRetry::default()
+                    }),
+                };
+                self.unmount_workflow(target.as_deref(), &options).await?;
+            }
+            Commands::List { json } => {
+                let object_manager = UDisks2ObjectManager::new(self.connection().await?).await?;
+                let devices = object_manager.loop_devices().await?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&devices)?);
+                } else {
+                    print_loop_table(&devices);
+                }
+            }
+            Commands::Drives { json } => {
+                let object_manager = UDisks2ObjectManager::new(self.connection().await?).await?;
+                let drives = object_manager.drives().await?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&drives)?);
+                } else {
+                    print_drives(&drives);
+                }
+            }
+            Commands::Flash {
+                image,
+                drive,
+                force,
+                no_verify,
+            } => {
+                let options = FlashOptions {
+                    force,
+                    verify: !no_verify,
+                };
+                self.flash_workflow(&image, &drive, &options).await?;
+            }
+            Commands::WindowsUsb {
+                iso,
+                drive,
+                label,
+                force,
+                split_size,
+                no_eject,
+            } => {
+                let options = WindowsUsbOptions {
+                    label,
+                    force,
+                    split_size_mib: split_size,
+                    eject: !no_eject,
+                    ..WindowsUsbOptions::default()
+                };
+                self.windows_usb_workflow(&iso, &drive, &options).await?;
+            }
+            Commands::Fsck {
+                target,
+                repair,
+                partition,
+                json,
+            } => {
+                let options = FsckOptions { repair, partition };
+                self.fsck_workflow(&target, &options, json).await?;
+            }
+            Commands::Relabel {
+                target,
+                label,
+                partition,
+            } => {
+                info!("修改卷标 {:?} -> {:?}", target, label);
+                let tool = self.filesystem_tool().await?;
+                let device = tool.relabel(&target, partition, &label).await?;
+                println!("Labelled {} as {label:?}", device.display());
+            }
+            Commands::SafelyRemove { drive } => {
+                self.safely_remove_workflow(&drive).await?;
+            }
+            Commands::Monitor { json } => {
+                self.monitor_events(json).await?;
+            }
             Commands::ShowGui => {
                 info!("启动 GUI 界面");
                 self.run_with_gui().await?;
@@ -237,8 +646,173 @@ impl App {
         Ok(())
     }
 
-    async fn run_console_mode(&self, iso_path: &Path) -> Result<()> {
-        match self.mount_iso_workflow(iso_path).await {
+    async fn flash_workflow(
+        &self,
+        image: &Path,
+        drive: &Path,
+        options: &FlashOptions,
+    ) -> Result<()> {
+        info!("写入镜像 {:?} 到 {:?}", image, drive);
+        let flasher = Flasher::new(self.connection().await?).await?;
+        let mut last_percent = None;
+        let report = flasher
+            .flash(image, drive, options, move |progress: FlashProgress| {
+                let percent = progress.bytes * 100 / progress.total.max(1);
+                if last_percent != Some((progress.phase, percent)) {
+                    last_percent = Some((progress.phase, percent));
+                    eprint!(
+                        "\r{:<9} {:>3}%  {} / {}",
+                        progress.phase,
+                        percent,
+                        format_size(progress.bytes),
+                        format_size(progress.total)
+                    );
+                    if progress.bytes == progress.total {
+                        eprintln!();
+                    }
+                }
+            })
+            .await?;
+
+        println!(
+            "Wrote {} to {}, sha256 {}{}",
+            format_size(report.bytes),
+            report.device.display(),
+            report.sha256,
+            if report.verified { ", verified" } else { "" }
+        );
+        Ok(())
+    }
+
+    async fn windows_usb_workflow(
+        &self,
+        iso: &Path,
+        drive: &Path,
+        options: &WindowsUsbOptions,
+    ) -> Result<()> {
+        info!("制作 Windows 安装盘: {:?} -> {:?}", iso, drive);
+        let creator =
+            WindowsUsbCreator::new(self.connection().await?, self.mounter().await?).await?;
+        let mut last_percent = None;
+        let report = creator
+            .create(iso, drive, options, move |progress: CopyProgress| {
+                let percent = progress.bytes * 100 / progress.total.max(1);
+                if last_percent != Some(percent) {
+                    last_percent = Some(percent);
+                    eprint!(
+                        "\rcopying {:>3}%  {} / {}",
+                        percent,
+                        format_size(progress.bytes),
+                        format_size(progress.total)
+                    );
+                    if progress.bytes == progress.total {
+                        eprintln!();
+                    }
+                }
+            })
+            .await?;
+
+        println!(
+            "Copied {} files ({}) to {}",
+            report.files,
+            format_size(report.bytes),
+            report.partition.display()
+        );
+        if !report.split_parts.is_empty() {
+            println!(
+                "Split install.wim into {}",
+                report
+                    .split_parts
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        let device = report.device.display();
+        match report.removal {
+            RemovalAction::PoweredOff => println!("Powered off {device}, it can be removed"),
+            RemovalAction::Ejected => println!("Ejected {device}, it can be removed"),
+            RemovalAction::None => {}
+        }
+        Ok(())
+    }
+
+    async fn fsck_workflow(&self, target: &Path, options: &FsckOptions, json: bool) -> Result<()> {
+        info!(
+            "{}文件系统 {:?}",
+            if options.repair { "修复" } else { "检查" },
+            target
+        );
+        let tool = self.filesystem_tool().await?;
+        let report = tool.fsck(target, options).await?;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            let mut rows = vec![
+                ["DEVICE", "PART", "FSTYPE", "LABEL", "STATUS"]
+                    .map(String::from)
+                    .to_vec(),
+            ];
+            for fs in &report.filesystems {
+                rows.push(vec![
+                    fs.device.display().to_string(),
+                    fs.number.map_or_else(|| "-".into(), |n| n.to_string()),
+                    fs.fstype.clone(),
+                    fs.label.clone().unwrap_or_else(|| "-".into()),
+                    fs.status.to_string(),
+                ]);
+            }
+            print_table(&rows, "");
+        }
+
+        // 有文件系统仍然损坏时以非零状态退出
+        if !report.is_ok() {
+            return Err(anyhow::anyhow!(
+                "{} has inconsistent filesystems{}",
+                target.display(),
+                if options.repair {
+                    ""
+                } else {
+                    ", run again with --repair"
+                }
+            ));
+        }
+        Ok(())
+    }
+
+    async fn safely_remove_workflow(&self, drive: &Path) -> Result<()> {
+        info!("安全移除磁盘 {:?}", drive);
+        let remover = SafeRemover::new(self.connection().await?).await?;
+        let report = remover.safely_remove(drive).await?;
+
+        for device in &report.unmounted {
+            println!("Unmounted {}", device.display());
+        }
+        for device in &report.locked {
+            println!("Locked {}", device.display());
+        }
+        let device = report.device.display();
+        match report.action {
+            RemovalAction::PoweredOff => println!("Powered off {device}, it can be removed"),
+            RemovalAction::Ejected => println!("Ejected {device}, it can be removed"),
+            RemovalAction::None => println!("{device} can be removed"),
+        }
+        Ok(())
+    }
+
+    async fn run_console_mode(
+        &self,
+        iso_path: &Path,
+        loop_options: &LoopOptions,
+        mount_options: &MountOptions,
+        keep: bool,
+    ) -> Result<()> {
+        match self
+            .mount_iso_workflow(iso_path, loop_options, mount_options, keep)
+            .await
+        {
             Ok(_) => info!("ISO mount workflow completed successfully"),
             Err(e) => {
                 error!("Error during ISO mount workflow: {}", e);
@@ -253,29 +827,160 @@ impl App {
         run_gui()
     }
 
-    async fn mount_iso_workflow(&self, iso_path: &Path) -> Result<()> {
-        let mounter = IsoMounter::new(&self.connection).await?;
-        let mounted_iso = mounter.mount_iso(iso_path).await?;
+    async fn mount_iso_workflow(
+        &self,
+        iso_path: &Path,
+        loop_options: &LoopOptions,
+        mount_options: &MountOptions,
+        keep: bool,
+    ) -> Result<()> {
+        // 在挂载前注册信号处理：挂载期间（如等待 polkit 认证或 LoopSetup）收到信号时立即放弃挂载，
+        // 挂载守卫会卸载已挂载的文件系统并删除 loop 设备
+        let mut shutdown = ShutdownSignals::new()?;
 
-        info!("ISO successfully mounted at: {}", mounted_iso.mount_path);
+        let mounter = self.mounter().await?;
+        // biased：信号和结果同时就绪时优先处理信号，被中断时不会按 --keep 保持挂载
+        let mounted_image = tokio::select! {
+            biased;
+            signal = shutdown.recv() => {
+                return Err(anyhow::anyhow!("Interrupted by {signal} while mounting"));
+            }
+            mounted = mounter.mount_guarded(iso_path, loop_options, mount_options) => mounted?,
+        };
 
-        // 读取并打印系统信息
-        match self
-            .read_and_parse_system_info(&mounted_iso.mount_path)
-            .await
-        {
-            Ok((version, arch)) => {
-                info!("系统版本: {}", version);
-                info!("系统架构: {}", arch);
+        let result = tokio::select! {
+            biased;
+            signal = shutdown.recv() => Err(anyhow::anyhow!("Interrupted by {signal}")),
+            result = self.analyze_mounted_image(&mounted_image) => result,
+        };
+
+        if keep && result.is_ok() {
+            // 保持挂载：loop 设备设置 autoclear，卸载时自动删除；挂载路径输出到 stdout 供脚本使用
+            let mounted_image = mounted_image.keep_mounted().await?;
+            for partition in &mounted_image.partitions {
+                println!("{}", partition.mount_path);
             }
-            Err(e) => {
-                error!("读取系统信息失败: {}", e);
+            return Ok(());
+        }
+
+        // 无论分析是否成功都卸载镜像并删除 loop 设备
+        let close_result = mounted_image.close().await;
+        result.and(close_result)
+    }
+
+    /// 查找并卸载指定镜像或挂载点对应的 loop 设备，未指定时卸载当前用户的全部 loop 设备
+    async fn unmount_workflow(
+        &self,
+        target: Option<&Path>,
+        options: &UnmountOptions,
+    ) -> Result<()> {
+        let mounter = self.mounter().await?;
+
+        let devices = match target {
+            Some(target) => {
+                let device = mounter
+                    .find_loop_device(target)
+                    .await?
+                    .with_context(|| format!("No loop device found for {}", target.display()))?;
+                vec![device]
+            }
+            None => {
+                let uid = nix::unistd::getuid().as_raw();
+                let devices = mounter.loop_devices_of(uid).await?;
+                if devices.is_empty() {
+                    info!("没有找到 UID {} 创建的 loop 设备", uid);
+                }
+                devices
             }
+        };
+
+        // 一个设备卸载失败时继续卸载其余设备，最后汇总报告；fail-fast 模式下立即停止
+        let mut failures = Vec::new();
+        for device in &devices {
+            match mounter.detach(device, options).await {
+                Ok(()) => info!(
+                    "已卸载 {} ({})",
+                    device.backing_file.display(),
+                    device.device.display()
+                ),
+                Err(e) if auth_mode().is_fail_fast() => return Err(e),
+                Err(e) => {
+                    error!("卸载 {} 失败: {e:#}", device.device.display());
+                    failures.push((device, e));
+                }
+            }
+        }
+        let failed: Vec<String> = failures
+            .iter()
+            .map(|(device, _)| device.device.display().to_string())
+            .collect();
+        let Some((_, first_error)) = failures.into_iter().next() else {
+            return Ok(());
+        };
+        if failed.len() == 1 {
+            return Err(first_error);
         }
+        // 保留第一个错误作为根因，退出码仍按其 UDisks2 错误类型区分
+        Err(first_error.context(format!(
+            "Failed to unmount {} of {} loop devices: {}",
+            failed.len(),
+            devices.len(),
+            failed.join(", ")
+        )))
+    }
+
+    /// 持续打印 UDisks2 事件，直到收到 SIGINT/SIGTERM
+    async fn monitor_events(&self, json: bool) -> Result<()> {
+        let mut shutdown = ShutdownSignals::new()?;
+        let object_manager = UDisks2ObjectManager::new(self.connection().await?).await?;
+        let mut events = object_manager.events().await?;
+        info!("开始监听 UDisks2 事件");
+
+        loop {
+            let event = tokio::select! {
+                event = events.next() => event,
+                signal = shutdown.recv() => {
+                    info!("收到 {}，停止监听", signal);
+                    return Ok(());
+                }
+            };
+            let Some(event) = event else {
+                return Err(anyhow::anyhow!("UDisks2 event stream ended"));
+            };
+            if json {
+                println!("{}", serde_json::to_string(&event)?);
+            } else {
+                println!("{event}");
+            }
+        }
+    }
 
-        self.process_mounted_files(&mounted_iso.mount_path).await?;
+    /// 分析已挂载镜像中每个文件系统的内容
+    async fn analyze_mounted_image(&self, mounted_image: &MountedImage) -> Result<()> {
+        for partition in &mounted_image.partitions {
+            info!(
+                "Filesystem {} (partition {}, label {}) mounted at: {}",
+                partition.fstype,
+                partition
+                    .number
+                    .map_or_else(|| "-".to_string(), |n| n.to_string()),
+                partition.label.as_deref().unwrap_or("-"),
+                partition.mount_path
+            );
 
-        mounter.unmount_iso(mounted_iso).await?;
+            // 读取并打印系统信息
+            match self.read_and_parse_system_info(&partition.mount_path).await {
+                Ok((version, arch)) => {
+                    info!("系统版本: {}", version);
+                    info!("系统架构: {}", arch);
+                }
+                Err(e) => {
+                    error!("读取系统信息失败: {}", e);
+                }
+            }
+
+            self.process_mounted_files(&partition.mount_path).await?;
+        }
         Ok(())
     }
 
@@ -294,6 +999,165 @@ impl App {
     }
 }
 
+/// 以表格形式打印 loop 设备，每个文件系统占一行
+fn print_loop_table(devices: &[LoopDeviceInfo]) {
+    let header = [
+        "DEVICE",
+        "BACKING FILE",
+        "UID",
+        "AUTOCLEAR",
+        "SIZE",
+        "FSTYPE",
+        "LABEL",
+        "MOUNTPOINTS",
+    ]
+    .map(String::from);
+
+    let mut rows = vec![header.to_vec()];
+    for device in devices {
+        let device_columns = [
+            device.device.display().to_string(),
+            device.backing_file.display().to_string(),
+            device.setup_by_uid.to_string(),
+            if device.autoclear { "yes" } else { "no" }.to_string(),
+            format_size(device.size),
+        ];
+        let mut filesystem_columns: Vec<[String; 3]> = device
+            .filesystems
+            .iter()
+            .map(|fs| {
+                let mount_points = fs
+                    .mount_points
+                    .iter()
+                    .map(|m| m.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                [
+                    fs.fstype.clone(),
+                    fs.label.clone().unwrap_or_else(|| "-".into()),
+                    if mount_points.is_empty() {
+                        "-".into()
+                    } else {
+                        mount_points
+                    },
+                ]
+            })
+            .collect();
+        if filesystem_columns.is_empty() {
+            filesystem_columns.push(["-".into(), "-".into(), "-".into()]);
+        }
+        for columns in filesystem_columns {
+            rows.push(device_columns.iter().cloned().chain(columns).collect());
+        }
+    }
+
+    print_table(&rows, "");
+}
+
+/// 打印每个磁盘的概要和其上的块设备，运行中系统所在的磁盘标记为 [SYSTEM]
+fn print_drives(drives: &[DriveInfo]) {
+    if drives.is_empty() {
+        println!("No drives found");
+        return;
+    }
+
+    for (index, drive) in drives.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        let device = drive.display_name();
+        let mut summary = vec![format_size(drive.size)];
+        if !drive.serial.is_empty() {
+            summary.push(format!("serial {}", drive.serial));
+        }
+        if !drive.connection_bus.is_empty() {
+            summary.push(drive.connection_bus.clone());
+        }
+        if drive.removable {
+            summary.push("removable".into());
+        }
+        if drive.ejectable {
+            summary.push("ejectable".into());
+        }
+        if !drive.media.is_empty() {
+            summary.push(format!("media {}", drive.media));
+        }
+        println!(
+            "{}{device}  {}  ({})",
+            if drive.system { "[SYSTEM] " } else { "" },
+            drive.name(),
+            summary.join(", ")
+        );
+
+        let mut rows = vec![
+            ["DEVICE", "PART", "SIZE", "FSTYPE", "LABEL", "MOUNTPOINTS"]
+                .map(String::from)
+                .to_vec(),
+        ];
+        for block in &drive.block_devices {
+            let mount_points = block
+                .mount_points
+                .iter()
+                .map(|m| m.display().to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            rows.push(vec![
+                block.device.display().to_string(),
+                // LUKS 解锁后的明文设备没有分区号，标记为 crypt
+                match (block.partition_number, &block.crypto_backing_device) {
+                    (_, Some(_)) => "crypt".into(),
+                    (Some(n), None) => n.to_string(),
+                    (None, None) => "-".into(),
+                },
+                format_size(block.size),
+                block.fstype.clone().unwrap_or_else(|| "-".into()),
+                block.label.clone().unwrap_or_else(|| "-".into()),
+                if mount_points.is_empty() {
+                    "-".into()
+                } else {
+                    mount_points
+                },
+            ]);
+        }
+        print_table(&rows, "  ");
+    }
+}
+
+/// 按列宽对齐打印表格，第一行为表头
+fn print_table(rows: &[Vec<String>], indent: &str) {
+    let mut widths = vec![0usize; rows.first().map_or(0, Vec::len)];
+    for row in rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(column, &width)| format!("{column:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{indent}{}", line.trim_end());
+    }
+}
+
+/// 将字节数格式化为便于阅读的大小
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // 初始化 tracing 日志系统，设置合适的日志级别和格式
@@ -302,14 +1166,33 @@ async fn main() -> Result<()> {
         .with_target(false) // 不显示模块路径，简化输出
         .with_level(true) // 显示日志级别
         .with_thread_ids(false) // 不显示线程ID，简化输出
+        .with_writer(std::io::stderr) // 日志输出到 stderr，stdout 留给命令结果
         .init();
 
     let args = Args::parse();
-    let app = App::new().await?;
+    let mut app = App::new().await?;
+    // 命令行指定的后端优先于配置文件
+    if let Some(backend) = args.backend {
+        app.config.backend = backend;
+    }
+    if let Some(bus) = args.bus.clone() {
+        app.config.bus = bus;
+    }
+    app.passphrase = (&args.unlock_args).into();
+    // 命令行指定的认证模式优先于配置文件
+    set_auth_mode(args.auth.unwrap_or(app.config.auth));
 
-    if let Err(e) = app.run(args).await {
+    let result = app.run(args).await;
+    // 退出前停止 pkttyagent
+    drop(app);
+    if let Err(e) = result {
         error!("Application error: {:?}", e);
-        std::process::exit(1);
+        // UDisks2 错误给出处理建议，并用不同的退出码区分
+        let udisks_error = Udisks2Error::find(&e);
+        if let Some(udisks_error) = &udisks_error {
+            error!("Hint: {}", udisks_error.hint());
+        }
+        std::process::exit(udisks_error.map_or(1, |e| e.exit_code()));
     }
     Ok(())
 }
@@ -0,0 +1,627 @@
+use crate::backend::{
+    AttachedDevice, ImageFilesystem, MountBackend, MountDetails, MountedPartition, Udisks2Backend,
+};
+use crate::bus::{BusAddress, Udisks2ConnectionBuilder};
+use crate::loop_refs::LoopRefs;
+use crate::udisks2::auth::auth_mode;
+use crate::udisks2::{LoopDeviceInfo, LoopOptions, MountOptions, UnmountOptions};
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    ops::Deref,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::runtime::{Handle, RuntimeFlavor};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::Connection;
+
+/// High-level ISO mounting manager
+#[derive(Clone)]
+pub struct IsoMounter {
+    backend: Arc<dyn MountBackend>,
+}
+
+/// Represents a mounted image with its attached device and mounted filesystems
+pub struct MountedImage {
+    pub iso_path: PathBuf,
+    pub device: AttachedDevice,
+    pub partitions: Vec<MountedPartition>,
+    backend: Arc<dyn MountBackend>,
+    /// Users of the loop device, `None` for backends without one
+    refs: Option<LoopRefs>,
+}
+
+/// Kept for existing library users, a mounted ISO is a single-partition image
+pub type MountedIso = MountedImage;
+
+impl MountedImage {
+    /// Mount path of the first mounted filesystem
+    pub fn mount_path(&self) -> Option<&str> {
+        self.partitions.first().map(|p| p.mount_path.as_str())
+    }
+
+    /// Whether other mounts of the same image share this loop device
+    pub fn is_shared(&self) -> bool {
+        self.refs.is_some()
+    }
+
+    /// Unmount every filesystem and detach the image, carrying on past failures
+    ///
+    /// Nothing is torn down while other users of the loop device are left, and nobody else can
+    /// start using it until the teardown is over.
+    async fn teardown(self) -> Result<()> {
+        let _released = match &self.refs {
+            Some(refs) => {
+                let refs = refs.clone();
+                match refs_task(move || refs.release()).await {
+                    Ok(released) if released.left() > 0 => {
+                        info!(
+                            "{} is still used by {} other mount(s), leaving it mounted",
+                            self.iso_path.display(),
+                            released.left()
+                        );
+                        return Ok(());
+                    }
+                    Ok(released) => Some(released),
+                    Err(e) => {
+                        warn!("Failed to release {}: {e:#}", self.device.id);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        info!("Unmounting ISO: {}", self.iso_path.display());
+        let mut first_error = None;
+        for partition in &self.partitions {
+            if let Err(e) = self
+                .backend
+                .unmount(partition, &UnmountOptions::default())
+                .await
+            {
+                warn!("Failed to unmount {}: {e:#}", partition.mount_path);
+                first_error.get_or_insert(e);
+            }
+        }
+        if let Err(e) = self.backend.detach(&self.device).await {
+            warn!("Failed to detach {}: {e:#}", self.device.id);
+            first_error.get_or_insert(e);
+        }
+        if first_error.is_none()
+            && let Some(refs) = &self.refs
+        {
+            refs.clear();
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+/// Owns a mounted image and tears it down when closed or dropped
+///
+/// Prefer [`MountGuard::close`], which reports teardown errors. Dropping the guard blocks the
+/// current thread until cleanup finishes, which needs a multi-threaded tokio runtime.
+pub struct MountGuard {
+    image: Option<MountedImage>,
+}
+
+impl MountGuard {
+    pub fn new(image: MountedImage) -> Self {
+        Self { image: Some(image) }
+    }
+
+    /// Unmount every filesystem and detach the image
+    pub async fn close(mut self) -> Result<()> {
+        match self.image.take() {
+            Some(image) => image.teardown().await,
+            None => Ok(()),
+        }
+    }
+
+    fn image_mut(&mut self) -> &mut MountedImage {
+        self.image.as_mut().expect("mount guard already released")
+    }
+
+    /// Disarm the guard and hand back the image, which then stays mounted
+    pub fn into_inner(mut self) -> MountedImage {
+        self.image.take().expect("mount guard already released")
+    }
+
+    /// Leave the image mounted, set up so that it goes away on unmount where the backend can
+    pub async fn keep_mounted(self) -> Result<MountedImage> {
+        if let Err(e) = self.backend.keep_attached(&self.device).await {
+            if let Err(close_err) = self.close().await {
+                warn!("Failed to clean up after autoclear error: {close_err:#}");
+            }
+            return Err(e);
+        }
+        if let Some(refs) = &self.refs
+            && let Err(e) = refs.keep()
+        {
+            warn!("Failed to keep a reference to {}: {e:#}", self.device.id);
+        }
+        Ok(self.into_inner())
+    }
+}
+
+impl Deref for MountGuard {
+    type Target = MountedImage;
+
+    fn deref(&self) -> &Self::Target {
+        self.image.as_ref().expect("mount guard already released")
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        let Some(image) = self.image.take() else {
+            return;
+        };
+        warn!(
+            "Mount guard for {} dropped without close(), cleaning up",
+            image.iso_path.display()
+        );
+
+        let handle = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => handle,
+            _ => {
+                warn!(
+                    "No multi-threaded tokio runtime available, leaving {} mounted",
+                    image.iso_path.display()
+                );
+                return;
+            }
+        };
+        if let Err(e) = tokio::task::block_in_place(|| handle.block_on(image.teardown())) {
+            warn!("Cleanup of dropped mount failed: {e:#}");
+        }
+    }
+}
+
+impl IsoMounter {
+    /// Create a mounter using the UDisks2 backend on `connection`
+    ///
+    /// The connection is cloned, which only bumps a reference count, so the mounter and every
+    /// image it mounts can outlive the caller's handle and be moved into spawned tasks.
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        Ok(Self::with_backend(Arc::new(
+            Udisks2Backend::new(connection).await?,
+        )))
+    }
+
+    /// Connect to `bus` and create a mounter using the UDisks2 daemon there
+    pub async fn connect(bus: BusAddress) -> Result<Self> {
+        let connection = Udisks2ConnectionBuilder::new().bus(bus).build().await?;
+        Self::new(&connection).await
+    }
+
+    /// Create a mounter that attaches and mounts images through `backend`
+    pub fn with_backend(backend: Arc<dyn MountBackend>) -> Self {
+        Self { backend }
+    }
+
+    pub fn backend(&self) -> &dyn MountBackend {
+        self.backend.as_ref()
+    }
+
+    /// Mount every filesystem in an image, to be unmounted with [`IsoMounter::unmount_iso`]
+    ///
+    /// Nothing is cleaned up if the caller bails out before that; [`IsoMounter::mount_guarded`]
+    /// returns a guard that is.
+    pub async fn mount_iso<P: AsRef<Path>>(
+        &self,
+        iso_path: P,
+        loop_options: &LoopOptions,
+        mount_options: &MountOptions,
+    ) -> Result<MountedIso> {
+        self.mount_guarded(iso_path, loop_options, mount_options)
+            .await
+            .map(MountGuard::into_inner)
+    }
+
+    /// Mount every filesystem in an image, returning a guard that unmounts it again
+    pub async fn mount_guarded<P: AsRef<Path>>(
+        &self,
+        iso_path: P,
+        loop_options: &LoopOptions,
+        mount_options: &MountOptions,
+    ) -> Result<MountGuard> {
+        let path = iso_path.as_ref();
+        if !path.exists() {
+            return Err(anyhow::anyhow!(
+                "ISO file does not exist: {}",
+                path.display()
+            ));
+        }
+
+        let supported_filesystems = match mount_options.fstype {
+            Some(_) => match self.backend.supported_filesystems().await {
+                Ok(filesystems) => Some(filesystems),
+                // Older daemons lack SupportedFilesystems, leave the check to Mount itself
+                Err(e) => {
+                    warn!("Cannot check the filesystem type: {e:#}");
+                    None
+                }
+            },
+            None => None,
+        };
+        mount_options.validate(supported_filesystems.as_deref())?;
+
+        // The loop device follows the fd: read-only for ISO images, writable unless asked otherwise
+        let (file, read_only) = open_image(path, loop_options.read_only)?;
+        let loop_options = &LoopOptions {
+            read_only,
+            ..loop_options.clone()
+        };
+        if let Some(offset) = loop_options.offset {
+            let file_len = file
+                .metadata()
+                .with_context(|| format!("Failed to stat ISO file: {}", path.display()))?
+                .len();
+            if offset >= file_len {
+                return Err(anyhow::anyhow!(
+                    "Loop offset {offset} is beyond the end of {} ({file_len} bytes)",
+                    path.display()
+                ));
+            }
+        }
+
+        // Mounting an image twice shares the loop device and mounts of the first time
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to stat ISO file: {}", path.display()))?;
+        if let Some(image) = self
+            .reuse_mounted(path, &metadata, loop_options, mount_options)
+            .await
+        {
+            return Ok(MountGuard::new(image));
+        }
+
+        // Attach the image and mount every filesystem on it
+        let device = self.backend.attach(path, &file, loop_options).await?;
+        info!(
+            "Attached {} with the {} backend as {}",
+            path.display(),
+            self.backend.kind(),
+            device.id
+        );
+
+        // Guarded from here on, so that a caller giving up mid-way (e.g. on Ctrl-C) does not leave
+        // the device and the filesystems mounted so far behind
+        let mut guard = MountGuard::new(MountedImage {
+            iso_path: path.to_path_buf(),
+            device,
+            partitions: Vec::new(),
+            backend: self.backend.clone(),
+            refs: None,
+        });
+        if let Err(e) = self
+            .mount_filesystems(guard.image_mut(), mount_options)
+            .await
+        {
+            if let Err(close_err) = guard.close().await {
+                warn!("Failed to clean up after mount error: {close_err:#}");
+            }
+            return Err(e);
+        }
+
+        let device = &guard.device;
+        let refs = match device.device {
+            Some(_) => match LoopRefs::new(&device.id, &metadata)
+                .and_then(|refs| refs.create().map(|()| refs))
+            {
+                Ok(refs) => Some(refs),
+                Err(e) => {
+                    warn!("Failed to count references to {}: {e:#}", device.id);
+                    None
+                }
+            },
+            None => None,
+        };
+        guard.image_mut().refs = refs;
+        Ok(guard)
+    }
+
+    /// A loop device with the same image (same device and inode) already mounted, so that symlinks
+    /// and bind mounts of the image are recognized too
+    ///
+    /// Only whole-image loop devices are shared, an offset or size limit always gets its own, and
+    /// so does a different read-only setting or a mount that lacks a requested mount option.
+    async fn reuse_mounted(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        loop_options: &LoopOptions,
+        mount_options: &MountOptions,
+    ) -> Option<MountedImage> {
+        if loop_options.offset.is_some() || loop_options.size.is_some() {
+            return None;
+        }
+        let devices = match self.backend.loop_devices().await {
+            Ok(devices) => devices,
+            Err(e) => {
+                debug!("Not looking for an existing mount: {e:#}");
+                return None;
+            }
+        };
+        let mount_table = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        let device = devices.into_iter().find(|device| {
+            device.mount_points().next().is_some()
+                && device.size / 512 == metadata.len() / 512
+                && device.read_only == loop_options.read_only
+                && device.is_backed_by(metadata)
+                && device
+                    .filesystems
+                    .iter()
+                    .flat_map(|fs| &fs.mount_points)
+                    .all(|mount_point| mounted_with(&mount_table, mount_point, mount_options))
+        })?;
+
+        // Waits while a last user tears the device down, and fails if it did
+        let refs = match LoopRefs::new(device.object_path.as_str(), metadata) {
+            Ok(refs) => refs,
+            Err(e) => {
+                warn!("Not reusing {}: {e:#}", device.device.display());
+                return None;
+            }
+        };
+        let acquiring = refs.clone();
+        if let Err(e) = refs_task(move || acquiring.acquire()).await {
+            warn!("Not reusing {}: {e:#}", device.device.display());
+            return None;
+        }
+        // Unmounted or detached by something that does not count references, like udisksctl
+        let still_mounted = self.backend.loop_devices().await.is_ok_and(|devices| {
+            devices
+                .iter()
+                .any(|d| d.object_path == device.object_path && d.mount_points().next().is_some())
+        });
+        if !still_mounted {
+            debug!("{} went away, not reusing it", device.device.display());
+            let releasing = refs.clone();
+            if let Err(e) = refs_task(move || releasing.release().map(drop)).await {
+                warn!("Failed to release {}: {e:#}", device.device.display());
+            }
+            return None;
+        }
+        info!(
+            "{} is already mounted from {}, reusing it",
+            path.display(),
+            device.device.display()
+        );
+        let partitions = device
+            .filesystems
+            .iter()
+            .filter_map(|fs| {
+                // From the loop device listing, the filesystem was not mounted here
+                let details = MountDetails {
+                    mount_path: fs.mount_points.first()?.clone(),
+                    device: Some(fs.device.clone()),
+                    fstype: fs.fstype.clone(),
+                    label: fs.label.clone(),
+                    uuid: fs.uuid.clone(),
+                    size: Some(fs.size),
+                    read_only: device.read_only,
+                    backing_file: Some(device.backing_file.clone()),
+                    object_path: Some(fs.object_path.to_string()),
+                    loop_object_path: Some(device.object_path.to_string()),
+                };
+                let found = ImageFilesystem {
+                    id: fs.object_path.to_string(),
+                    number: fs.number,
+                    type_guid: fs.type_guid.clone(),
+                    label: fs.label.clone(),
+                    fstype: fs.fstype.clone(),
+                };
+                Some(MountedPartition::new(found, details))
+            })
+            .collect();
+        Some(MountedImage {
+            iso_path: path.to_path_buf(),
+            device: AttachedDevice {
+                id: device.object_path.to_string(),
+                device: Some(device.device),
+            },
+            partitions,
+            backend: self.backend.clone(),
+            refs: Some(refs),
+        })
+    }
+
+    /// Mount each filesystem of an attached image into `image`, skipping the ones that fail
+    ///
+    /// In [`AuthMode::FailFast`](crate::AuthMode::FailFast) the first failure ends the mount instead.
+    /// Filesystems are added as they are mounted, so tearing `image` down on an error or when the
+    /// caller gives up undoes exactly what was done.
+    async fn mount_filesystems(
+        &self,
+        image: &mut MountedImage,
+        mount_options: &MountOptions,
+    ) -> Result<()> {
+        let device = &image.device;
+        let filesystems = self.backend.filesystems(device).await?;
+        if filesystems.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No mountable filesystem found on {}",
+                device.id
+            ));
+        }
+
+        let mut first_error = None;
+        for found in filesystems {
+            debug!(
+                "Found {} filesystem {} (partition {:?}, label {:?})",
+                found.fstype, found.id, found.number, found.label
+            );
+            match self.backend.mount(&found, mount_options).await {
+                Ok(details) => image.partitions.push(MountedPartition::new(found, details)),
+                // The caller's teardown unmounts what was mounted so far
+                Err(e) if auth_mode().is_fail_fast() => {
+                    return Err(e.context(format!("Failed to mount {}", found.id)));
+                }
+                Err(e) => {
+                    warn!("Failed to mount {}: {e:#}", found.id);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        if image.partitions.is_empty() {
+            // Keep the first failure as the cause, so callers can still classify it
+            let message = format!("None of the filesystems on {} could be mounted", device.id);
+            return Err(match first_error {
+                Some(e) => e.context(message),
+                None => anyhow::anyhow!(message),
+            });
+        }
+        Ok(())
+    }
+
+    /// Unmount every filesystem of an image from [`IsoMounter::mount_iso`] and detach it
+    pub async fn unmount_iso(&self, mounted_iso: MountedIso) -> Result<()> {
+        MountGuard::new(mounted_iso).close().await
+    }
+
+    /// Find the loop device for `target`, either its backing image file or a mount point
+    pub async fn find_loop_device(&self, target: &Path) -> Result<Option<LoopDeviceInfo>> {
+        Ok(self
+            .backend
+            .loop_devices()
+            .await?
+            .into_iter()
+            .find(|device| device.matches(target)))
+    }
+
+    /// Loop devices that were set up by the user with `uid`
+    pub async fn loop_devices_of(&self, uid: u32) -> Result<Vec<LoopDeviceInfo>> {
+        Ok(self
+            .backend
+            .loop_devices()
+            .await?
+            .into_iter()
+            .filter(|device| device.setup_by_uid == uid)
+            .collect())
+    }
+
+    /// Unmount every mounted filesystem on a loop device, then delete the device
+    pub async fn detach(&self, device: &LoopDeviceInfo, options: &UnmountOptions) -> Result<()> {
+        info!(
+            "Detaching {} ({})",
+            device.device.display(),
+            device.backing_file.display()
+        );
+        self.backend.detach_loop_device(device, options).await?;
+        // An explicit unmount ends every shared use of the device
+        if let Some(refs) =
+            LoopRefs::for_backing_file(device.object_path.as_str(), &device.backing_file)
+        {
+            refs.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Run a reference count update on the blocking pool, as it may wait for another user's lock
+async fn refs_task<T: Send + 'static>(
+    task: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .context("Reference counting task failed")?
+}
+
+/// Whether the filesystem at `mount_point` is mounted as `options` ask for, going by
+/// `/proc/self/mountinfo`
+///
+/// Default options match any mount; otherwise the type and every option must show up in the mount
+/// or superblock options, and a mount point missing from the table never matches.
+fn mounted_with(mount_table: &str, mount_point: &Path, options: &MountOptions) -> bool {
+    if options.fstype.is_none() && options.options.is_none() {
+        return true;
+    }
+    mount_table.lines().any(|line| {
+        // ID PARENT MAJ:MIN ROOT MOUNT_POINT MOUNT_OPTIONS [OPTIONAL...] - FSTYPE SOURCE SUPER_OPTIONS
+        let Some((mount, filesystem)) = line.split_once(" - ") else {
+            return false;
+        };
+        let mount: Vec<&str> = mount.split(' ').collect();
+        let filesystem: Vec<&str> = filesystem.split(' ').collect();
+        let (Some(point), Some(mount_options), Some(fstype), Some(super_options)) = (
+            mount.get(4),
+            mount.get(5),
+            filesystem.first(),
+            filesystem.get(2),
+        ) else {
+            return false;
+        };
+        if unescape_mount_path(point) != mount_point {
+            return false;
+        }
+        let present: Vec<&str> = mount_options
+            .split(',')
+            .chain(super_options.split(','))
+            .collect();
+        options.fstype.as_deref().is_none_or(|t| t == *fstype)
+            && options
+                .options
+                .iter()
+                .flat_map(|o| o.split(','))
+                .all(|o| present.contains(&o))
+    })
+}
+
+/// Undo the octal escapes (`\040` for a space) of a path in the mount table
+fn unescape_mount_path(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escape = bytes
+            .get(index + 1..index + 4)
+            .filter(|_| bytes[index] == b'\\');
+        match escape.and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok()) {
+            Some(byte) => {
+                out.push(byte);
+                index += 4;
+            }
+            None => {
+                out.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+/// Open an image for `LoopSetup`, which makes the loop device read-only for an `O_RDONLY` fd
+///
+/// ISO 9660 images are always opened read-only, like images that cannot be written, such as ones
+/// on read-only media; other images are writable unless `read_only` is set. Returns the file and
+/// whether it was opened read-only.
+fn open_image(path: &Path, read_only: bool) -> Result<(File, bool)> {
+    // By the resolved name, so that a symlink opens the image like its target
+    let is_iso = fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("iso"));
+    let read_only = read_only || is_iso;
+    let open = |write| OpenOptions::new().read(true).write(write).open(path);
+    let result = match open(!read_only) {
+        Err(e)
+            if !read_only
+                && matches!(
+                    e.kind(),
+                    std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem
+                ) =>
+        {
+            warn!("{} is not writable, attaching it read-only", path.display());
+            open(false).map(|file| (file, true))
+        }
+        result => result.map(|file| (file, read_only)),
+    };
+    result.with_context(|| format!("Failed to open ISO file: {}", path.display()))
+}
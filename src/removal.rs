@@ -0,0 +1,134 @@
+//! Safely removing drives: unmount every filesystem, lock LUKS containers, then power off or eject
+
+use crate::udisks2::auth::auth_mode;
+use crate::udisks2::{
+    UDisks2Drive, UDisks2Encrypted, UDisks2Filesystem, UDisks2ObjectManager, UnmountOptions,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::Connection;
+
+/// What was done to the drive once nothing on it was in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalAction {
+    /// `Drive.PowerOff`, the usual way for USB drives
+    PoweredOff,
+    /// `Drive.Eject`, for drives that cannot be powered off such as optical drives
+    Ejected,
+    /// Neither is supported, the drive can be unplugged as it is
+    None,
+}
+
+/// Outcome of [`SafeRemover::safely_remove`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RemovalReport {
+    /// Whole-disk device node of the drive
+    pub device: PathBuf,
+    /// Block devices whose filesystems were unmounted
+    pub unmounted: Vec<PathBuf>,
+    /// LUKS containers that were locked
+    pub locked: Vec<PathBuf>,
+    pub action: RemovalAction,
+}
+
+/// Prepares drives known to UDisks2 for unplugging
+#[derive(Clone)]
+pub struct SafeRemover {
+    connection: Connection,
+    object_manager: UDisks2ObjectManager,
+}
+
+impl SafeRemover {
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            object_manager: UDisks2ObjectManager::new(connection).await?,
+        })
+    }
+
+    /// Unmount and lock everything on the drive `target`, then power it off or eject it
+    ///
+    /// When a filesystem cannot be unmounted, the error lists the processes holding it and
+    /// nothing is locked or powered off. In [`AuthMode::FailFast`](crate::AuthMode::FailFast) the
+    /// first failed unmount is returned right away, without trying the other filesystems.
+    pub async fn safely_remove(&self, target: &Path) -> Result<RemovalReport> {
+        let drive = self.object_manager.find_drive(target).await?;
+        let device = drive.display_name();
+        if drive.system {
+            return Err(anyhow::anyhow!(
+                "Refusing to remove {device}: it holds the running system's root filesystem"
+            ));
+        }
+
+        let mut unmounted = Vec::new();
+        let mut busy = Vec::new();
+        for block in drive
+            .block_devices
+            .iter()
+            .filter(|b| !b.mount_points.is_empty())
+        {
+            let result = async {
+                UDisks2Filesystem::new(&self.connection, block.object_path.clone().into_inner())
+                    .await?
+                    .unmount(&UnmountOptions::default())
+                    .await
+            }
+            .await;
+            match result {
+                Ok(()) => unmounted.push(block.device.clone()),
+                Err(e) if auth_mode().is_fail_fast() => {
+                    return Err(e.context(format!(
+                        "Failed to unmount {}, leaving {device} as it is",
+                        block.device.display()
+                    )));
+                }
+                // The unmount error already lists the processes holding a busy mount point
+                Err(e) => busy.push(format!("{}: {e:#}", block.device.display())),
+            }
+        }
+        if !busy.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{device} is still in use, close these and try again:\n{}",
+                busy.join("\n")
+            ));
+        }
+
+        let mut locked = Vec::new();
+        for container in drive.block_devices.iter().filter(|b| {
+            b.encrypted
+                && drive
+                    .block_devices
+                    .iter()
+                    .any(|c| c.crypto_backing_device.as_ref() == Some(&b.object_path))
+        }) {
+            UDisks2Encrypted::new(&self.connection, container.object_path.clone().into_inner())
+                .await?
+                .lock()
+                .await?;
+            locked.push(container.device.clone());
+        }
+
+        let udisks_drive =
+            UDisks2Drive::new(&self.connection, drive.object_path.clone().into_inner()).await?;
+        let action = if drive.can_power_off {
+            udisks_drive.power_off().await?;
+            RemovalAction::PoweredOff
+        } else if drive.ejectable {
+            udisks_drive.eject().await?;
+            RemovalAction::Ejected
+        } else {
+            RemovalAction::None
+        };
+
+        Ok(RemovalReport {
+            device: drive.device().map(Path::to_path_buf).unwrap_or_default(),
+            unmounted,
+            locked,
+            action,
+        })
+    }
+}
@@ -1,143 +1,894 @@
+use crate::busy::busy_message;
+use crate::luks::LuksKey;
 use anyhow::{Context, Result};
+use futures_util::{Stream, StreamExt, stream};
+use nix::libc;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::File,
-    os::fd::AsFd,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
     path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
 };
 #[allow(unused_imports)]
 use tracing::{debug, info, warn};
 use zbus::{
-    Connection, Proxy,
-    zvariant::{Fd, ObjectPath, OwnedObjectPath, Value},
+    Connection, MatchRule, MessageStream,
+    fdo::{ManagedObjects, ObjectManagerProxy},
+    names::OwnedInterfaceName,
+    zvariant::{Fd, ObjectPath, OwnedObjectPath, OwnedValue, Value},
+};
+
+pub mod auth;
+pub mod capabilities;
+pub mod error;
+pub mod policy;
+pub mod proxies;
+
+use auth::{auth_options, with_auth};
+use error::Udisks2Error;
+
+use proxies::{
+    BlockProxy, DriveProxy, EncryptedProxy, FilesystemProxy, LoopProxy, ManagerProxy,
+    PartitionTableProxy,
 };
 
 const UDISKS2_SERVICE: &str = "org.freedesktop.UDisks2";
-const UDISKS2_MANAGER_PATH: &str = "/org/freedesktop/UDisks2/Manager";
-const UDISKS2_MANAGER_INTERFACE: &str = "org.freedesktop.UDisks2.Manager";
+const UDISKS2_ROOT_PATH: &str = "/org/freedesktop/UDisks2";
 const UDISKS2_FILESYSTEM_INTERFACE: &str = "org.freedesktop.UDisks2.Filesystem";
 const UDISKS2_LOOP_INTERFACE: &str = "org.freedesktop.UDisks2.Loop";
+const UDISKS2_BLOCK_INTERFACE: &str = "org.freedesktop.UDisks2.Block";
+const UDISKS2_PARTITION_INTERFACE: &str = "org.freedesktop.UDisks2.Partition";
+const UDISKS2_PARTITION_TABLE_INTERFACE: &str = "org.freedesktop.UDisks2.PartitionTable";
+const UDISKS2_DRIVE_INTERFACE: &str = "org.freedesktop.UDisks2.Drive";
+const UDISKS2_ENCRYPTED_INTERFACE: &str = "org.freedesktop.UDisks2.Encrypted";
+
+const DBUS_PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// Interfaces whose property changes are reported by [`UDisks2ObjectManager::events`]
+const MONITORED_INTERFACES: [&str; 3] = [
+    UDISKS2_BLOCK_INTERFACE,
+    UDISKS2_FILESYSTEM_INTERFACE,
+    UDISKS2_LOOP_INTERFACE,
+];
+
+/// Options passed to `Manager.LoopSetup` when attaching an image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopOptions {
+    /// Attach the image read-only (`read-only`)
+    pub read_only: bool,
+    /// Byte offset into the image where the device starts (`offset`)
+    pub offset: Option<u64>,
+    /// Size of the device in bytes, defaults to the rest of the image (`size`)
+    pub size: Option<u64>,
+    /// Do not scan the device for partitions (`no-part-scan`)
+    pub no_part_scan: bool,
+}
+
+impl LoopOptions {
+    /// Build the `a{sv}` options dictionary understood by UDisks2
+    pub fn to_dbus_options(&self) -> HashMap<String, Value<'static>> {
+        let mut options = HashMap::new();
+        if self.read_only {
+            options.insert("read-only".to_string(), Value::from(true));
+        }
+        if let Some(offset) = self.offset {
+            options.insert("offset".to_string(), Value::from(offset));
+        }
+        if let Some(size) = self.size {
+            options.insert("size".to_string(), Value::from(size));
+        }
+        if self.no_part_scan {
+            options.insert("no-part-scan".to_string(), Value::from(true));
+        }
+        options
+    }
+}
+
+/// Options passed to `Filesystem.Mount`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MountOptions {
+    /// Filesystem type to mount as, e.g. `iso9660` or `udf` (`fstype`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
+    /// Comma separated mount options, e.g. `ro,uid=1000,iocharset=utf8` (`options`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<String>,
+    /// Fail instead of asking polkit for interactive authorization (`auth.no_user_interaction`)
+    pub no_user_interaction: bool,
+}
+
+impl MountOptions {
+    /// Check the options against the filesystem types the UDisks2 daemon supports
+    ///
+    /// With `None`, when the daemon cannot tell, any filesystem type is accepted.
+    pub fn validate(&self, supported_filesystems: Option<&[String]>) -> Result<()> {
+        if let Some(fstype) = &self.fstype
+            && let Some(supported_filesystems) = supported_filesystems
+            && !supported_filesystems.iter().any(|fs| fs == fstype)
+        {
+            return Err(anyhow::anyhow!(
+                "Filesystem type '{fstype}' is not supported by UDisks2 (supported: {})",
+                supported_filesystems.join(", ")
+            ));
+        }
+
+        if let Some(options) = &self.options {
+            for option in options.split(',') {
+                if option.is_empty() || option.contains(char::is_whitespace) {
+                    return Err(anyhow::anyhow!(
+                        "Invalid mount option '{option}' in '{options}'"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the `a{sv}` options dictionary understood by UDisks2
+    pub fn to_dbus_options(&self) -> HashMap<String, Value<'static>> {
+        let mut options = HashMap::new();
+        if let Some(fstype) = &self.fstype {
+            options.insert("fstype".to_string(), Value::from(fstype.clone()));
+        }
+        if let Some(mount_options) = &self.options {
+            options.insert("options".to_string(), Value::from(mount_options.clone()));
+        }
+        if self.no_user_interaction {
+            options.insert("auth.no_user_interaction".to_string(), Value::from(true));
+        }
+        options
+    }
+}
 
 /// Represents a UDisks2 device manager
-pub struct UDisks2Manager<'a> {
-    connection: &'a Connection,
-    proxy: Proxy<'a>,
+#[derive(Clone)]
+pub struct UDisks2Manager {
+    connection: Connection,
+    proxy: ManagerProxy<'static>,
+}
+
+/// Represents a UDisks2 block device
+#[derive(Clone)]
+pub struct UDisks2Block {
+    proxy: BlockProxy<'static>,
+    object_path: ObjectPath<'static>,
 }
 
 /// Represents a UDisks2 filesystem device
-pub struct UDisks2Filesystem<'a> {
-    proxy: Proxy<'a>,
+pub struct UDisks2Filesystem {
+    proxy: FilesystemProxy<'static>,
     object_path: ObjectPath<'static>,
 }
 
-/// High-level ISO mounting manager
-pub struct IsoMounter<'a> {
-    manager: UDisks2Manager<'a>,
+/// Represents a UDisks2 loop device
+pub struct UDisks2Loop {
+    proxy: LoopProxy<'static>,
+    object_path: ObjectPath<'static>,
 }
 
-/// Represents a mounted ISO with its metadata
-pub struct MountedIso<'a> {
-    pub mount_path: String,
-    pub filesystem: UDisks2Filesystem<'a>,
-    pub iso_path: PathBuf,
+/// Represents a UDisks2 partition table on a block device
+pub struct UDisks2PartitionTable {
+    proxy: PartitionTableProxy<'static>,
+    object_path: ObjectPath<'static>,
 }
 
-impl<'a> UDisks2Manager<'a> {
-    pub async fn new(connection: &'a Connection) -> Result<Self> {
-        let proxy = Proxy::new(
-            connection,
-            UDISKS2_SERVICE,
-            UDISKS2_MANAGER_PATH,
-            UDISKS2_MANAGER_INTERFACE,
-        )
-        .await
-        .context("Failed to create UDisks2 manager proxy")?;
+/// Represents a UDisks2 drive
+#[derive(Clone)]
+pub struct UDisks2Drive {
+    proxy: DriveProxy<'static>,
+    object_path: ObjectPath<'static>,
+}
+
+/// Represents a UDisks2 LUKS container
+pub struct UDisks2Encrypted {
+    proxy: EncryptedProxy<'static>,
+    object_path: ObjectPath<'static>,
+}
+
+/// Client for the UDisks2 ObjectManager rooted at `/org/freedesktop/UDisks2`
+#[derive(Clone)]
+pub struct UDisks2ObjectManager {
+    proxy: ObjectManagerProxy<'static>,
+}
+
+/// A filesystem found on a loop device or on one of its partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FilesystemObject {
+    pub object_path: OwnedObjectPath,
+    /// Device node, e.g. `/dev/loop0p1` (`Block.Device`)
+    pub device: PathBuf,
+    /// Partition number, `None` when the filesystem spans the whole device
+    pub number: Option<u32>,
+    /// Partition type: a GUID on GPT, `0xNN` on MBR
+    pub type_guid: Option<String>,
+    /// Filesystem label (`IdLabel`), falling back to the partition name
+    pub label: Option<String>,
+    /// Filesystem type (`IdType`)
+    pub fstype: String,
+    /// Filesystem UUID (`IdUUID`)
+    pub uuid: Option<String>,
+    /// Size of the block device in bytes (`Block.Size`)
+    pub size: u64,
+    /// Current mount points (`MountPoints`)
+    pub mount_points: Vec<PathBuf>,
+}
+
+/// A filesystem that UDisks2 just mounted, read back from the daemon after `Filesystem.Mount`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MountInfo {
+    /// Device node of the filesystem, e.g. `/dev/loop0p1` (`Block.Device`)
+    pub device: PathBuf,
+    pub object_path: OwnedObjectPath,
+    /// Loop device the filesystem sits on, directly, as a partition or in a LUKS container;
+    /// `None` for other block devices
+    pub loop_object_path: Option<OwnedObjectPath>,
+    /// Mount point returned by `Mount`, checked against `MountPoints`
+    pub mount_path: PathBuf,
+    /// Filesystem type (`IdType`)
+    pub fstype: String,
+    /// Filesystem label (`IdLabel`)
+    pub label: Option<String>,
+    /// Filesystem UUID (`IdUUID`)
+    pub uuid: Option<String>,
+    /// Size of the block device in bytes (`Block.Size`)
+    pub size: u64,
+    /// Whether the block device is read-only (`Block.ReadOnly`)
+    pub read_only: bool,
+    /// Image file behind the loop device (`Loop.BackingFile`)
+    pub backing_file: Option<PathBuf>,
+}
+
+/// A LUKS container on a loop device or on one of its partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EncryptedObject {
+    pub object_path: OwnedObjectPath,
+    /// Partition number, `None` when the container spans the whole device
+    pub number: Option<u32>,
+    /// Device node of the container
+    pub device: PathBuf,
+    /// Cleartext device while unlocked (`CleartextDevice`)
+    pub cleartext_device: Option<OwnedObjectPath>,
+}
+
+/// A loop device known to UDisks2, with the filesystems on it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoopDeviceInfo {
+    pub object_path: OwnedObjectPath,
+    /// Device node, e.g. `/dev/loop0` (`Block.Device`)
+    pub device: PathBuf,
+    /// Image file backing the device (`Loop.BackingFile`)
+    pub backing_file: PathBuf,
+    /// UID of the user that set up the device (`Loop.SetupByUID`)
+    pub setup_by_uid: u32,
+    /// Whether the device is cleared once the last mount goes away (`Loop.Autoclear`)
+    pub autoclear: bool,
+    /// Size of the device in bytes (`Block.Size`)
+    pub size: u64,
+    /// Whether the device is read-only (`Block.ReadOnly`)
+    pub read_only: bool,
+    pub filesystems: Vec<FilesystemObject>,
+}
 
-        Ok(Self { connection, proxy })
+impl LoopDeviceInfo {
+    /// Every mount point of every filesystem on the device
+    pub fn mount_points(&self) -> impl Iterator<Item = &Path> {
+        self.filesystems
+            .iter()
+            .flat_map(|fs| fs.mount_points.iter().map(PathBuf::as_path))
     }
 
-    pub async fn setup_loop_device(&self, iso_fd: Fd<'_>) -> Result<UDisks2Filesystem<'a>> {
-        let options = HashMap::<String, Value>::new();
-        let object_path: OwnedObjectPath = self
-            .proxy
-            .call_method("LoopSetup", &(iso_fd, options))
-            .await?
-            .body()
-            .deserialize()
-            .context("Failed to deserialize loop device object path")?;
+    /// Whether the backing file is the file with `metadata`, under whatever name it was opened
+    pub fn is_backed_by(&self, metadata: &fs::Metadata) -> bool {
+        fs::metadata(&self.backing_file)
+            .is_ok_and(|m| m.dev() == metadata.dev() && m.ino() == metadata.ino())
+    }
+
+    /// Whether `target` is the backing file or one of the mount points of this device
+    pub fn matches(&self, target: &Path) -> bool {
+        let target = canonical(target);
+        canonical(&self.backing_file) == target
+            || self.mount_points().any(|m| canonical(m) == target)
+    }
+}
+
+/// A block device belonging to a drive: the whole disk or one of its partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DriveBlockDevice {
+    pub object_path: OwnedObjectPath,
+    /// Device node, e.g. `/dev/sdb1` (`Block.Device`)
+    pub device: PathBuf,
+    /// Size of the device in bytes (`Block.Size`)
+    pub size: u64,
+    /// Partition number, `None` for the whole disk (`Partition.Number`)
+    pub partition_number: Option<u32>,
+    /// Filesystem or content type, e.g. `vfat` or `crypto_LUKS` (`Block.IdType`)
+    pub fstype: Option<String>,
+    /// Filesystem label (`Block.IdLabel`)
+    pub label: Option<String>,
+    /// Current mount points, empty when not mounted (`Filesystem.MountPoints`)
+    pub mount_points: Vec<PathBuf>,
+    /// Whether UDisks2 considers the device a system device (`Block.HintSystem`)
+    pub hint_system: bool,
+    /// Whether the device is a LUKS container (has the `Encrypted` interface)
+    pub encrypted: bool,
+    /// For an unlocked LUKS device, the container it was unlocked from (`Block.CryptoBackingDevice`)
+    pub crypto_backing_device: Option<OwnedObjectPath>,
+}
+
+/// A physical drive known to UDisks2, with its block devices and partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DriveInfo {
+    pub object_path: OwnedObjectPath,
+    pub vendor: String,
+    pub model: String,
+    pub serial: String,
+    /// Size in bytes, 0 without media (`Drive.Size`)
+    pub size: u64,
+    /// Physical connection, e.g. `usb`, `sdio` or `ata`; empty if unknown (`Drive.ConnectionBus`)
+    pub connection_bus: String,
+    /// Whether the drive or its media can be removed (`Drive.Removable`)
+    pub removable: bool,
+    /// Whether the media can be taken out of the drive (`Drive.MediaRemovable`)
+    pub media_removable: bool,
+    /// Whether the drive can be ejected (`Drive.Ejectable`)
+    pub ejectable: bool,
+    /// Whether the drive can be powered off (`Drive.CanPowerOff`)
+    pub can_power_off: bool,
+    /// Kind of media inserted, e.g. `thumb` or `optical_cd`; empty if unknown (`Drive.Media`)
+    pub media: String,
+    /// Whether the running system's root filesystem lives on this drive
+    pub system: bool,
+    /// The whole-disk block device first, then partitions by number, then unlocked LUKS devices
+    pub block_devices: Vec<DriveBlockDevice>,
+}
+
+impl DriveInfo {
+    /// The whole-disk block device
+    pub fn disk(&self) -> Option<&DriveBlockDevice> {
+        self.block_devices
+            .iter()
+            .find(|block| block.partition_number.is_none() && block.crypto_backing_device.is_none())
+    }
+
+    /// Device node of the whole disk, e.g. `/dev/sdb`
+    pub fn device(&self) -> Option<&Path> {
+        self.disk().map(|block| block.device.as_path())
+    }
+
+    /// Whether `target` is the whole-disk device node (or a symlink to it) or the drive object path
+    pub fn matches(&self, target: &Path) -> bool {
+        if target == Path::new(self.object_path.as_str()) {
+            return true;
+        }
+        let target = canonical(target);
+        self.device()
+            .is_some_and(|device| canonical(device) == target)
+    }
+
+    /// Whole-disk device node for messages, the object path if the drive has no block device
+    pub fn display_name(&self) -> String {
+        self.device()
+            .map_or_else(|| self.object_path.to_string(), |d| d.display().to_string())
+    }
+
+    /// Vendor and model joined for display
+    pub fn name(&self) -> String {
+        [self.vendor.trim(), self.model.trim()]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// A device or mount change reported by UDisks2
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Udisks2Event {
+    /// An object appeared or gained interfaces
+    InterfacesAdded {
+        object_path: OwnedObjectPath,
+        interfaces: Vec<String>,
+    },
+    /// An object disappeared or lost interfaces
+    InterfacesRemoved {
+        object_path: OwnedObjectPath,
+        interfaces: Vec<String>,
+    },
+    /// Properties of a Block, Filesystem or Loop interface changed
+    PropertiesChanged {
+        object_path: OwnedObjectPath,
+        interface: String,
+        /// New values, rendered in D-Bus text notation
+        changed: BTreeMap<String, String>,
+        invalidated: Vec<String>,
+    },
+}
+
+/// Stream of events returned by [`UDisks2ObjectManager::events`]
+pub type Udisks2EventStream = Pin<Box<dyn Stream<Item = Udisks2Event> + Send + 'static>>;
+
+/// Strip the `org.freedesktop.UDisks2.` prefix for display
+fn short_interface(interface: &str) -> &str {
+    interface
+        .strip_prefix("org.freedesktop.UDisks2.")
+        .unwrap_or(interface)
+}
+
+impl fmt::Display for Udisks2Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |interfaces: &[String]| {
+            interfaces
+                .iter()
+                .map(|i| short_interface(i))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            Self::InterfacesAdded {
+                object_path,
+                interfaces,
+            } => write!(f, "added    {object_path} [{}]", join(interfaces)),
+            Self::InterfacesRemoved {
+                object_path,
+                interfaces,
+            } => write!(f, "removed  {object_path} [{}]", join(interfaces)),
+            Self::PropertiesChanged {
+                object_path,
+                interface,
+                changed,
+                invalidated,
+            } => {
+                write!(f, "changed  {object_path} {}:", short_interface(interface))?;
+                for (name, value) in changed {
+                    write!(f, " {name}={value}")?;
+                }
+                for name in invalidated {
+                    write!(f, " {name}=<invalidated>")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Options passed to `Filesystem.Unmount`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnmountOptions {
+    /// Unmount even if the filesystem is busy (`force`)
+    pub force: bool,
+    /// Try again while the filesystem is busy, instead of failing right away
+    pub retry: Option<UnmountRetry>,
+}
+
+/// Retry policy for unmounting a busy filesystem, with the delay doubling after every attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmountRetry {
+    /// Retries after the first attempt
+    pub attempts: u32,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+}
+
+impl Default for UnmountRetry {
+    /// 5 retries over about 8 seconds
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+impl UnmountRetry {
+    /// The delay before each retry
+    pub fn delays(&self) -> impl Iterator<Item = Duration> + use<> {
+        let initial_delay = self.initial_delay;
+        (0..self.attempts).map(move |attempt| initial_delay.saturating_mul(1 << attempt.min(16)))
+    }
+}
+
+impl UnmountOptions {
+    /// Build the `a{sv}` options dictionary understood by UDisks2
+    pub fn to_dbus_options(&self) -> HashMap<String, Value<'static>> {
+        let mut options = HashMap::new();
+        if self.force {
+            options.insert("force".to_string(), Value::from(true));
+        }
+        options
+    }
+}
+
+/// Outcome of `Filesystem.Check` or `Filesystem.Repair`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsckStatus {
+    /// `Check` found the filesystem consistent
+    Clean,
+    /// `Check` found errors that `Repair` may fix
+    Damaged,
+    /// `Repair` fixed the filesystem
+    Repaired,
+    /// `Repair` ran but the filesystem is still inconsistent
+    Unrepaired,
+}
+
+impl FsckStatus {
+    /// Whether the filesystem is consistent now
+    pub fn is_ok(self) -> bool {
+        matches!(self, Self::Clean | Self::Repaired)
+    }
+}
+
+impl fmt::Display for FsckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Clean => "clean",
+            Self::Damaged => "damaged",
+            Self::Repaired => "repaired",
+            Self::Unrepaired => "unrepaired",
+        })
+    }
+}
+
+/// Options passed to `Block.Format`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Filesystem label (`label`)
+    pub label: Option<String>,
+    /// Make the root directory of the new filesystem owned by the caller (`take-ownership`)
+    pub take_ownership: bool,
+    /// Wipe the device first, `zero` or `ata-secure-erase` (`erase`)
+    pub erase: Option<String>,
+}
+
+impl FormatOptions {
+    /// Build the `a{sv}` options dictionary understood by UDisks2
+    pub fn to_dbus_options(&self) -> HashMap<String, Value<'static>> {
+        let mut options = HashMap::new();
+        if let Some(label) = &self.label {
+            options.insert("label".to_string(), Value::from(label.clone()));
+        }
+        if self.take_ownership {
+            options.insert("take-ownership".to_string(), Value::from(true));
+        }
+        if let Some(erase) = &self.erase {
+            options.insert("erase".to_string(), Value::from(erase.clone()));
+        }
+        options
+    }
+}
+
+impl UDisks2Manager {
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        let proxy = ManagerProxy::new(connection)
+            .await
+            .context("Failed to create UDisks2 manager proxy")?;
+
+        Ok(Self {
+            connection: connection.clone(),
+            proxy,
+        })
+    }
+
+    /// The typed `org.freedesktop.UDisks2.Manager` proxy
+    pub fn proxy(&self) -> &ManagerProxy<'static> {
+        &self.proxy
+    }
+
+    /// Version of the running daemon (`Version`)
+    pub async fn version(&self) -> Result<String> {
+        policy::read(|| self.proxy.version())
+            .await
+            .context("Failed to get UDisks2 version")
+    }
+
+    /// Filesystem types the daemon can mount (`SupportedFilesystems`)
+    pub async fn supported_filesystems(&self) -> Result<Vec<String>> {
+        policy::read(|| self.proxy.supported_filesystems())
+            .await
+            .context("Failed to get supported filesystems")
+    }
+
+    pub async fn setup_loop_device(
+        &self,
+        iso_fd: Fd<'_>,
+        loop_options: &LoopOptions,
+    ) -> Result<UDisks2Loop> {
+        debug!("LoopSetup options: {loop_options:?}");
+        let object_path = policy::call(
+            self.proxy
+                .loop_setup(iso_fd, &with_auth(loop_options.to_dbus_options())),
+        )
+        .await
+        .context("Failed to set up loop device")?;
 
         info!("Loop device created: {object_path}");
 
-        UDisks2Filesystem::new(self.connection, object_path.into()).await
+        UDisks2Loop::new(&self.connection, object_path.into()).await
     }
 }
 
-impl<'a> UDisks2Filesystem<'a> {
-    pub async fn new(connection: &'a Connection, object_path: ObjectPath<'static>) -> Result<Self> {
-        let proxy = Proxy::new(
-            connection,
-            UDISKS2_SERVICE,
-            object_path.clone(),
-            UDISKS2_FILESYSTEM_INTERFACE,
+impl UDisks2Block {
+    pub async fn new(connection: &Connection, object_path: ObjectPath<'static>) -> Result<Self> {
+        let proxy = BlockProxy::builder(connection)
+            .path(object_path.clone())?
+            .build()
+            .await
+            .context("Failed to create block device proxy")?;
+
+        Ok(Self { proxy, object_path })
+    }
+
+    /// The typed `org.freedesktop.UDisks2.Block` proxy
+    pub fn proxy(&self) -> &BlockProxy<'static> {
+        &self.proxy
+    }
+
+    pub fn object_path(&self) -> &ObjectPath<'static> {
+        &self.object_path
+    }
+
+    /// Device node, e.g. `/dev/loop0p1` (`Device`)
+    pub async fn device(&self) -> Result<PathBuf> {
+        Ok(bytestring_path(
+            policy::read(|| self.proxy.device())
+                .await
+                .context("Failed to get block device node")?,
+        ))
+    }
+
+    /// Size of the device in bytes (`Size`)
+    pub async fn size(&self) -> Result<u64> {
+        policy::read(|| self.proxy.size())
+            .await
+            .context("Failed to get block device size")
+    }
+
+    /// Whether the device is read-only (`ReadOnly`)
+    pub async fn read_only(&self) -> Result<bool> {
+        policy::read(|| self.proxy.read_only())
+            .await
+            .context("Failed to get block device read-only flag")
+    }
+
+    /// Detected content type, e.g. `iso9660` or `vfat` (`IdType`)
+    pub async fn id_type(&self) -> Result<String> {
+        policy::read(|| self.proxy.id_type())
+            .await
+            .context("Failed to get filesystem type")
+    }
+
+    /// Filesystem label (`IdLabel`)
+    pub async fn id_label(&self) -> Result<String> {
+        policy::read(|| self.proxy.id_label())
+            .await
+            .context("Failed to get filesystem label")
+    }
+
+    /// Filesystem UUID (`IdUUID`)
+    pub async fn id_uuid(&self) -> Result<String> {
+        policy::read(|| self.proxy.id_uuid())
+            .await
+            .context("Failed to get filesystem UUID")
+    }
+
+    /// Create a filesystem or, with `gpt` or `dos`, an empty partition table (`Format`)
+    pub async fn format(&self, fstype: &str, options: &FormatOptions) -> Result<()> {
+        debug!("Formatting {} as {fstype}: {options:?}", self.object_path);
+        policy::job(
+            self.proxy
+                .format(fstype, &with_auth(options.to_dbus_options())),
         )
         .await
-        .context("Failed to create filesystem proxy")?;
+        .with_context(|| format!("Failed to format {} as {fstype}", self.object_path))
+    }
+
+    /// Open the device for writing a whole image to it, no root needed (`OpenForRestore`)
+    pub async fn open_for_restore(&self) -> Result<File> {
+        let fd = policy::call(self.proxy.open_for_restore(&auth_options()))
+            .await
+            .with_context(|| format!("Failed to open {} for writing", self.object_path))?;
+        Ok(File::from(std::os::fd::OwnedFd::from(fd)))
+    }
+
+    /// Open the device read-only for reading a whole image from it (`OpenForBackup`)
+    pub async fn open_for_backup(&self) -> Result<File> {
+        let fd = policy::call(self.proxy.open_for_backup(&auth_options()))
+            .await
+            .with_context(|| format!("Failed to open {} for reading", self.object_path))?;
+        Ok(File::from(std::os::fd::OwnedFd::from(fd)))
+    }
+}
+
+impl UDisks2PartitionTable {
+    pub async fn new(connection: &Connection, object_path: ObjectPath<'static>) -> Result<Self> {
+        let proxy = PartitionTableProxy::builder(connection)
+            .path(object_path.clone())?
+            .build()
+            .await
+            .context("Failed to create partition table proxy")?;
 
         Ok(Self { proxy, object_path })
     }
 
-    pub async fn mount(&self) -> Result<String> {
-        let mount_options = HashMap::<String, Value>::new();
-        let mount_path: String = self
-            .proxy
-            .call_method("Mount", &(mount_options,))
-            .await?
-            .body()
-            .deserialize()
-            .context("Failed to deserialize mount path")?;
+    /// The typed `org.freedesktop.UDisks2.PartitionTable` proxy
+    pub fn proxy(&self) -> &PartitionTableProxy<'static> {
+        &self.proxy
+    }
 
-        info!("Mounted at: {mount_path}");
-        Ok(mount_path)
+    pub fn object_path(&self) -> &ObjectPath<'static> {
+        &self.object_path
     }
 
-    pub async fn verify_mount_point(&self) -> Result<()> {
-        let mount_points: Vec<Vec<u8>> = self
-            .proxy
-            .get_property("MountPoints")
+    /// Create a partition and return its block object (`CreatePartition`)
+    ///
+    /// A `size` of 0 uses the largest free space at `offset`. `partition_type` is a GUID on GPT
+    /// and `0xNN` on MBR; `name` is ignored on MBR.
+    pub async fn create_partition(
+        &self,
+        offset: u64,
+        size: u64,
+        partition_type: &str,
+        name: &str,
+    ) -> Result<OwnedObjectPath> {
+        let partition = policy::call(self.proxy.create_partition(
+            offset,
+            size,
+            partition_type,
+            name,
+            &auth_options(),
+        ))
+        .await
+        .with_context(|| format!("Failed to create partition on {}", self.object_path))?;
+        info!("Created partition {partition}");
+        Ok(partition)
+    }
+}
+
+impl UDisks2Drive {
+    pub async fn new(connection: &Connection, object_path: ObjectPath<'static>) -> Result<Self> {
+        let proxy = DriveProxy::builder(connection)
+            .path(object_path.clone())?
+            .build()
             .await
-            .context("Failed to get mount points")?;
+            .context("Failed to create drive proxy")?;
 
-        let mount_point = mount_points.first().context("No mount points available")?;
+        Ok(Self { proxy, object_path })
+    }
 
-        let mount_point_str = String::from_utf8_lossy(mount_point);
-        debug!("Actual mount point: {mount_point_str}");
+    /// The typed `org.freedesktop.UDisks2.Drive` proxy
+    pub fn proxy(&self) -> &DriveProxy<'static> {
+        &self.proxy
+    }
+
+    pub fn object_path(&self) -> &ObjectPath<'static> {
+        &self.object_path
+    }
+
+    /// Eject the media, filesystems on it must be unmounted first (`Eject`)
+    pub async fn eject(&self) -> Result<()> {
+        policy::call(self.proxy.eject(&auth_options()))
+            .await
+            .with_context(|| format!("Failed to eject {}", self.object_path))?;
+        info!("Ejected {}", self.object_path);
         Ok(())
     }
 
-    pub async fn unmount(&self) -> Result<()> {
-        let unmount_options = HashMap::<String, Value>::new();
-        self.proxy
-            .call_method("Unmount", &(unmount_options,))
+    /// Spin the drive down and cut its power, after which it disappears (`PowerOff`)
+    pub async fn power_off(&self) -> Result<()> {
+        policy::call(self.proxy.power_off(&auth_options()))
             .await
-            .context("Failed to unmount filesystem")?;
+            .with_context(|| format!("Failed to power off {}", self.object_path))?;
+        info!("Powered off {}", self.object_path);
+        Ok(())
+    }
+}
 
-        info!("Unmounted successfully");
+impl UDisks2Encrypted {
+    pub async fn new(connection: &Connection, object_path: ObjectPath<'static>) -> Result<Self> {
+        let proxy = EncryptedProxy::builder(connection)
+            .path(object_path.clone())?
+            .build()
+            .await
+            .context("Failed to create encrypted device proxy")?;
+
+        Ok(Self { proxy, object_path })
+    }
+
+    /// The typed `org.freedesktop.UDisks2.Encrypted` proxy
+    pub fn proxy(&self) -> &EncryptedProxy<'static> {
+        &self.proxy
+    }
+
+    pub fn object_path(&self) -> &ObjectPath<'static> {
+        &self.object_path
+    }
+
+    /// Open the container with `key` and return the cleartext device (`Unlock`)
+    pub async fn unlock(&self, key: &LuksKey) -> Result<OwnedObjectPath> {
+        let mut options = auth_options();
+        let passphrase = match key {
+            LuksKey::Passphrase(passphrase) => passphrase.as_str(),
+            LuksKey::Keyfile(contents) => {
+                options.insert(
+                    "keyfile_contents".to_string(),
+                    Value::from(contents.clone()),
+                );
+                ""
+            }
+        };
+        let cleartext = policy::call(self.proxy.unlock(passphrase, &options))
+            .await
+            .with_context(|| format!("Failed to unlock {}", self.object_path))?;
+        info!("Unlocked {} as {cleartext}", self.object_path);
+        Ok(cleartext)
+    }
+
+    /// Close the cleartext device, whose filesystems must be unmounted first (`Lock`)
+    pub async fn lock(&self) -> Result<()> {
+        policy::call(self.proxy.lock(&auth_options()))
+            .await
+            .with_context(|| format!("Failed to lock {}", self.object_path))?;
+        info!("Locked {}", self.object_path);
         Ok(())
     }
+}
 
-    pub async fn delete(self) -> Result<()> {
-        let loop_proxy = Proxy::new(
-            self.proxy.connection(),
-            UDISKS2_SERVICE,
-            self.object_path.clone(),
-            UDISKS2_LOOP_INTERFACE,
-        )
-        .await
-        .context("Failed to create loop device proxy")?;
+impl UDisks2Loop {
+    pub async fn new(connection: &Connection, object_path: ObjectPath<'static>) -> Result<Self> {
+        let proxy = LoopProxy::builder(connection)
+            .path(object_path.clone())?
+            .build()
+            .await
+            .context("Failed to create loop device proxy")?;
+
+        Ok(Self { proxy, object_path })
+    }
+
+    /// The typed `org.freedesktop.UDisks2.Loop` proxy
+    pub fn proxy(&self) -> &LoopProxy<'static> {
+        &self.proxy
+    }
+
+    pub fn object_path(&self) -> &ObjectPath<'static> {
+        &self.object_path
+    }
 
-        let delete_options = HashMap::<String, Value>::new();
-        loop_proxy
-            .call_method("Delete", &(delete_options,))
+    /// Image file backing the device (`BackingFile`)
+    pub async fn backing_file(&self) -> Result<PathBuf> {
+        Ok(bytestring_path(
+            policy::read(|| self.proxy.backing_file())
+                .await
+                .context("Failed to get loop device backing file")?,
+        ))
+    }
+
+    /// Whether the device is cleared once the last mount goes away (`Autoclear`)
+    pub async fn autoclear(&self) -> Result<bool> {
+        policy::read(|| self.proxy.autoclear())
+            .await
+            .context("Failed to get loop device autoclear")
+    }
+
+    /// Clear the loop device automatically once its last user goes away (`SetAutoclear`)
+    pub async fn set_autoclear(&self, autoclear: bool) -> Result<()> {
+        policy::call(self.proxy.set_autoclear(autoclear, &auth_options()))
+            .await
+            .context("Failed to set loop device autoclear")?;
+
+        debug!("Autoclear set to {autoclear} on {}", self.object_path);
+        Ok(())
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        policy::call(self.proxy.delete(&auth_options()))
             .await
             .context("Failed to delete loop device")?;
 
@@ -146,45 +897,753 @@ impl<'a> UDisks2Filesystem<'a> {
     }
 }
 
-impl<'a> IsoMounter<'a> {
-    pub async fn new(connection: &'a Connection) -> Result<Self> {
-        let manager = UDisks2Manager::new(connection).await?;
-        Ok(Self { manager })
+impl UDisks2ObjectManager {
+    pub async fn new(connection: &Connection) -> Result<Self> {
+        let proxy = ObjectManagerProxy::builder(connection)
+            .destination(UDISKS2_SERVICE)?
+            .path(UDISKS2_ROOT_PATH)?
+            .build()
+            .await
+            .context("Failed to create UDisks2 object manager proxy")?;
+
+        Ok(Self { proxy })
+    }
+
+    /// Fetch every UDisks2 object with its interfaces and properties
+    pub async fn managed_objects(&self) -> Result<ManagedObjects> {
+        policy::read(|| async { Ok(self.proxy.get_managed_objects().await?) })
+            .await
+            .context("Failed to get UDisks2 managed objects")
+    }
+
+    /// Subscribe to objects being added or removed and to Block/Filesystem/Loop property changes
+    pub async fn events(&self) -> Result<Udisks2EventStream> {
+        let added = self
+            .proxy
+            .receive_interfaces_added()
+            .await
+            .context("Failed to subscribe to InterfacesAdded")?
+            .filter_map(|signal| async move {
+                let args = signal.args().ok()?;
+                Some(Udisks2Event::InterfacesAdded {
+                    object_path: args.object_path().clone().into(),
+                    interfaces: args
+                        .interfaces_and_properties()
+                        .keys()
+                        .map(|i| i.to_string())
+                        .collect(),
+                })
+            });
+
+        let removed = self
+            .proxy
+            .receive_interfaces_removed()
+            .await
+            .context("Failed to subscribe to InterfacesRemoved")?
+            .filter_map(|signal| async move {
+                let args = signal.args().ok()?;
+                Some(Udisks2Event::InterfacesRemoved {
+                    object_path: args.object_path().clone().into(),
+                    interfaces: args.interfaces().iter().map(|i| i.to_string()).collect(),
+                })
+            });
+
+        let rule = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender(UDISKS2_SERVICE)?
+            .interface(DBUS_PROPERTIES_INTERFACE)?
+            .member("PropertiesChanged")?
+            .path_namespace(UDISKS2_ROOT_PATH)?
+            .build();
+        let changed = MessageStream::for_match_rule(rule, self.proxy.inner().connection(), None)
+            .await
+            .context("Failed to subscribe to PropertiesChanged")?
+            .filter_map(|message| async move {
+                let message = message.ok()?;
+                let object_path: OwnedObjectPath = message.header().path()?.clone().into();
+                let (interface, changed, invalidated): (
+                    String,
+                    HashMap<String, OwnedValue>,
+                    Vec<String>,
+                ) = message.body().deserialize().ok()?;
+                if !MONITORED_INTERFACES.contains(&interface.as_str()) {
+                    return None;
+                }
+                Some(Udisks2Event::PropertiesChanged {
+                    object_path,
+                    interface,
+                    changed: changed
+                        .into_iter()
+                        .map(|(name, value)| (name, value.to_string()))
+                        .collect(),
+                    invalidated,
+                })
+            });
+
+        Ok(Box::pin(stream::select(
+            stream::select(added, removed),
+            changed,
+        )))
     }
 
-    /// Mount an ISO file and return the mount path and filesystem handler
-    pub async fn mount_iso<P: AsRef<Path>>(&self, iso_path: P) -> Result<MountedIso<'a>> {
-        let path = iso_path.as_ref();
-        if !path.exists() {
+    /// Every file-backed loop device with its filesystems and mount points
+    pub async fn loop_devices(&self) -> Result<Vec<LoopDeviceInfo>> {
+        Ok(loop_devices(&self.managed_objects().await?))
+    }
+
+    /// Every drive with its block devices, marking the one holding the root filesystem
+    pub async fn drives(&self) -> Result<Vec<DriveInfo>> {
+        Ok(drives(&self.managed_objects().await?))
+    }
+
+    /// The drive whose whole-disk device node or object path is `target`
+    ///
+    /// Naming a partition instead of the whole drive is an error that points at the drive.
+    pub async fn find_drive(&self, target: &Path) -> Result<DriveInfo> {
+        let drives = self.drives().await?;
+        if let Some(drive) = drives.iter().find(|d| d.matches(target)) {
+            return Ok(drive.clone());
+        }
+
+        let target = canonical(target);
+        if let Some(drive) = drives.iter().find(|d| {
+            d.block_devices
+                .iter()
+                .any(|b| b.partition_number.is_some() && canonical(&b.device) == target)
+        }) {
             return Err(anyhow::anyhow!(
-                "ISO file does not exist: {}",
-                path.display()
+                "{} is a partition, give the whole drive {} instead",
+                target.display(),
+                drive.display_name()
             ));
         }
+        Err(anyhow::anyhow!("No drive found for {}", target.display()))
+    }
+
+    /// The block device whose device node or object path is `target`
+    pub async fn find_block(&self, target: &Path) -> Result<OwnedObjectPath> {
+        find_block(&self.managed_objects().await?, target)
+            .with_context(|| format!("No block device found for {}", target.display()))
+    }
+}
+
+/// Look up the properties of one interface in a `GetManagedObjects` entry
+fn interface_properties<'m>(
+    interfaces: &'m HashMap<OwnedInterfaceName, HashMap<String, OwnedValue>>,
+    interface: &str,
+) -> Option<&'m HashMap<String, OwnedValue>> {
+    interfaces
+        .iter()
+        .find(|(name, _)| name.as_str() == interface)
+        .map(|(_, properties)| properties)
+}
+
+/// Read a typed property out of a `GetManagedObjects` property map
+fn property<T: TryFrom<OwnedValue>>(
+    properties: &HashMap<String, OwnedValue>,
+    name: &str,
+) -> Option<T> {
+    properties.get(name)?.try_clone().ok()?.try_into().ok()
+}
 
-        // Open the ISO file
-        let file = File::open(path)
-            .with_context(|| format!("Failed to open ISO file: {}", path.display()))?;
-        let iso_fd = Fd::from(file.as_fd());
-        debug!("Opening ISO file: {} (fd: {})", path.display(), iso_fd);
+/// The partition number and type of `object_path` if it is `block` or one of its partitions
+///
+/// The outer `None` means the object does not belong to `block`; `Some((None, None))` is `block`
+/// itself.
+fn member_of(
+    objects: &ManagedObjects,
+    object_path: &OwnedObjectPath,
+    block: &ObjectPath<'_>,
+) -> Option<(Option<u32>, Option<String>)> {
+    if object_path.as_ref() == *block {
+        return Some((None, None));
+    }
+    let partition = interface_properties(objects.get(object_path)?, UDISKS2_PARTITION_INTERFACE)?;
+    let table: OwnedObjectPath = property(partition, "Table")?;
+    (table.as_ref() == *block).then(|| (property(partition, "Number"), property(partition, "Type")))
+}
+
+/// Find every filesystem on `block`: the block itself, any partition whose table is `block`, and
+/// the cleartext devices of unlocked LUKS containers among them
+pub fn find_filesystems(objects: &ManagedObjects, block: &ObjectPath<'_>) -> Vec<FilesystemObject> {
+    let mut filesystems: Vec<FilesystemObject> = objects
+        .iter()
+        .filter_map(|(object_path, interfaces)| {
+            interface_properties(interfaces, UDISKS2_FILESYSTEM_INTERFACE)?;
+            let block_properties = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE);
 
-        // Setup loop device and mount
-        let filesystem = self.manager.setup_loop_device(iso_fd).await?;
-        let mount_path = filesystem.mount().await?;
-        filesystem.verify_mount_point().await?;
+            // A cleartext device counts as the partition its container sits on
+            let (number, type_guid) = member_of(objects, object_path, block).or_else(|| {
+                let backing: OwnedObjectPath = property(block_properties?, "CryptoBackingDevice")?;
+                member_of(objects, &backing, block)
+            })?;
 
-        Ok(MountedIso {
-            mount_path,
-            filesystem,
-            iso_path: path.to_path_buf(),
+            let partition = interface_properties(interfaces, UDISKS2_PARTITION_INTERFACE);
+            let label = block_properties
+                .and_then(|b| property::<String>(b, "IdLabel"))
+                .filter(|l| !l.is_empty())
+                .or_else(|| partition.and_then(|p| property::<String>(p, "Name")))
+                .filter(|l| !l.is_empty());
+
+            Some(FilesystemObject {
+                object_path: object_path.clone(),
+                device: block_properties
+                    .and_then(|b| property::<Vec<u8>>(b, "Device"))
+                    .map(bytestring_path)
+                    .unwrap_or_default(),
+                number,
+                type_guid,
+                label,
+                fstype: block_properties
+                    .and_then(|b| property(b, "IdType"))
+                    .unwrap_or_default(),
+                uuid: block_properties
+                    .and_then(|b| property::<String>(b, "IdUUID"))
+                    .filter(|u| !u.is_empty()),
+                size: block_properties
+                    .and_then(|b| property(b, "Size"))
+                    .unwrap_or_default(),
+                mount_points: interface_properties(interfaces, UDISKS2_FILESYSTEM_INTERFACE)
+                    .and_then(|f| property::<Vec<Vec<u8>>>(f, "MountPoints"))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(bytestring_path)
+                    .collect(),
+            })
         })
+        .collect();
+
+    filesystems.sort_by_key(|fs| fs.number);
+    filesystems
+}
+
+/// Describe the filesystem `object_path` that `Mount` reported at `mount_path`
+///
+/// Fails if the filesystem is gone or its `MountPoints` do not include `mount_path`.
+pub fn mount_info(
+    objects: &ManagedObjects,
+    object_path: &ObjectPath<'_>,
+    mount_path: &Path,
+) -> Result<MountInfo> {
+    let object_path = OwnedObjectPath::from(object_path.clone());
+    let interfaces = objects
+        .get(&object_path)
+        .with_context(|| format!("{object_path} disappeared after mounting"))?;
+    let mount_points: Vec<PathBuf> = interface_properties(interfaces, UDISKS2_FILESYSTEM_INTERFACE)
+        .and_then(|f| property::<Vec<Vec<u8>>>(f, "MountPoints"))
+        .unwrap_or_default()
+        .into_iter()
+        .map(bytestring_path)
+        .collect();
+    let canonical_mount_path = canonical(mount_path);
+    if !mount_points
+        .iter()
+        .any(|m| m == mount_path || canonical(m) == canonical_mount_path)
+    {
+        return Err(anyhow::anyhow!(
+            "UDisks2 mounted {object_path} at {}, but its MountPoints are {mount_points:?}",
+            mount_path.display()
+        ));
     }
 
-    /// Unmount and cleanup an ISO filesystem
-    pub async fn unmount_iso(&self, mounted_iso: MountedIso<'a>) -> Result<()> {
-        info!("Unmounting ISO: {}", mounted_iso.iso_path.display());
-        mounted_iso.filesystem.unmount().await?;
-        mounted_iso.filesystem.delete().await?;
+    let block = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)
+        .with_context(|| format!("{object_path} is not a block device"))?;
+    let text = |name| property::<String>(block, name).filter(|s| !s.is_empty());
+    let loop_object_path = loop_of(objects, &object_path);
+    let backing_file = loop_object_path
+        .as_ref()
+        .and_then(|l| interface_properties(objects.get(l)?, UDISKS2_LOOP_INTERFACE))
+        .and_then(|l| property::<Vec<u8>>(l, "BackingFile"))
+        .map(bytestring_path)
+        .filter(|p| !p.as_os_str().is_empty());
+
+    Ok(MountInfo {
+        device: property::<Vec<u8>>(block, "Device")
+            .map(bytestring_path)
+            .unwrap_or_default(),
+        object_path,
+        loop_object_path,
+        mount_path: mount_path.to_path_buf(),
+        fstype: text("IdType").unwrap_or_default(),
+        label: text("IdLabel"),
+        uuid: text("IdUUID"),
+        size: property(block, "Size").unwrap_or_default(),
+        read_only: property(block, "ReadOnly").unwrap_or_default(),
+        backing_file,
+    })
+}
+
+/// The loop device under `object_path`, following `Partition.Table` and `CryptoBackingDevice`
+fn loop_of(objects: &ManagedObjects, object_path: &OwnedObjectPath) -> Option<OwnedObjectPath> {
+    let interfaces = objects.get(object_path)?;
+    if interface_properties(interfaces, UDISKS2_LOOP_INTERFACE).is_some() {
+        return Some(object_path.clone());
+    }
+    let parent = interface_properties(interfaces, UDISKS2_PARTITION_INTERFACE)
+        .and_then(|p| property::<OwnedObjectPath>(p, "Table"))
+        .or_else(|| {
+            interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)
+                .and_then(|b| property::<OwnedObjectPath>(b, "CryptoBackingDevice"))
+                .filter(|p| p.as_str() != "/")
+        })?;
+    loop_of(objects, &parent)
+}
+
+/// Find every LUKS container on `block`: the block itself and any partition whose table is `block`
+pub fn find_encrypted(objects: &ManagedObjects, block: &ObjectPath<'_>) -> Vec<EncryptedObject> {
+    let mut containers: Vec<EncryptedObject> = objects
+        .iter()
+        .filter_map(|(object_path, interfaces)| {
+            let encrypted = interface_properties(interfaces, UDISKS2_ENCRYPTED_INTERFACE)?;
+            let (number, _) = member_of(objects, object_path, block)?;
+            Some(EncryptedObject {
+                object_path: object_path.clone(),
+                number,
+                device: interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)
+                    .and_then(|b| property::<Vec<u8>>(b, "Device"))
+                    .map(bytestring_path)
+                    .unwrap_or_default(),
+                cleartext_device: property::<OwnedObjectPath>(encrypted, "CleartextDevice")
+                    .filter(|p| p.as_str() != "/"),
+            })
+        })
+        .collect();
+
+    containers.sort_by_key(|c| c.number);
+    containers
+}
+
+/// The block device whose object path, `Device` or `PreferredDevice` is `target`
+pub fn find_block(objects: &ManagedObjects, target: &Path) -> Option<OwnedObjectPath> {
+    let canonical_target = canonical(target);
+    objects.iter().find_map(|(object_path, interfaces)| {
+        let block = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)?;
+        let matches = Path::new(object_path.as_str()) == target
+            || ["Device", "PreferredDevice"].iter().any(|name| {
+                property::<Vec<u8>>(block, name)
+                    .map(bytestring_path)
+                    .is_some_and(|device| canonical(&device) == canonical_target)
+            });
+        matches.then(|| object_path.clone())
+    })
+}
+
+/// Every loop device that is backed by a file, with the filesystems on it
+pub fn loop_devices(objects: &ManagedObjects) -> Vec<LoopDeviceInfo> {
+    let mut devices: Vec<LoopDeviceInfo> = objects
+        .iter()
+        .filter_map(|(object_path, interfaces)| {
+            let loop_properties = interface_properties(interfaces, UDISKS2_LOOP_INTERFACE)?;
+            let backing_file =
+                bytestring_path(property::<Vec<u8>>(loop_properties, "BackingFile")?);
+            if backing_file.as_os_str().is_empty() {
+                return None;
+            }
+            let block_properties = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE);
+            let device = block_properties
+                .and_then(|b| property::<Vec<u8>>(b, "Device"))
+                .map(bytestring_path)
+                .unwrap_or_default();
+
+            Some(LoopDeviceInfo {
+                object_path: object_path.clone(),
+                device,
+                backing_file,
+                setup_by_uid: property(loop_properties, "SetupByUID").unwrap_or_default(),
+                autoclear: property(loop_properties, "Autoclear").unwrap_or_default(),
+                size: block_properties
+                    .and_then(|b| property(b, "Size"))
+                    .unwrap_or_default(),
+                read_only: block_properties
+                    .and_then(|b| property(b, "ReadOnly"))
+                    .unwrap_or_default(),
+                filesystems: find_filesystems(objects, object_path),
+            })
+        })
+        .collect();
+
+    devices.sort_by(|a, b| a.device.cmp(&b.device));
+    devices
+}
+
+/// Every drive with its block devices and partitions
+pub fn drives(objects: &ManagedObjects) -> Vec<DriveInfo> {
+    let system = system_drives(objects);
+    let mut drives: Vec<(String, DriveInfo)> = objects
+        .iter()
+        .filter_map(|(object_path, interfaces)| {
+            let drive = interface_properties(interfaces, UDISKS2_DRIVE_INTERFACE)?;
+            let text = |name| property::<String>(drive, name).unwrap_or_default();
+            let flag = |name| property::<bool>(drive, name).unwrap_or_default();
+
+            let info = DriveInfo {
+                object_path: object_path.clone(),
+                vendor: text("Vendor"),
+                model: text("Model"),
+                serial: text("Serial"),
+                size: property(drive, "Size").unwrap_or_default(),
+                connection_bus: text("ConnectionBus"),
+                removable: flag("Removable"),
+                media_removable: flag("MediaRemovable"),
+                ejectable: flag("Ejectable"),
+                can_power_off: flag("CanPowerOff"),
+                media: text("Media"),
+                system: system.contains(object_path),
+                block_devices: drive_block_devices(objects, object_path),
+            };
+            Some((text("SortKey"), info))
+        })
+        .collect();
+
+    drives.sort_by(|(a_key, a), (b_key, b)| (a_key, a.device()).cmp(&(b_key, b.device())));
+    drives.into_iter().map(|(_, drive)| drive).collect()
+}
+
+/// Block devices whose `Block.Drive` is `drive`
+fn drive_block_devices(objects: &ManagedObjects, drive: &OwnedObjectPath) -> Vec<DriveBlockDevice> {
+    let mut blocks: Vec<DriveBlockDevice> = objects
+        .iter()
+        .filter_map(|(object_path, interfaces)| {
+            let block = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)?;
+            if property::<OwnedObjectPath>(block, "Drive").as_ref() != Some(drive) {
+                return None;
+            }
+            Some(drive_block_device(object_path, interfaces))
+        })
+        .collect();
+
+    // Unlocked LUKS devices have no drive of their own, they hang off their backing device
+    let cleartext: Vec<DriveBlockDevice> = objects
+        .iter()
+        .filter_map(|(object_path, interfaces)| {
+            let block = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)?;
+            let backing: OwnedObjectPath = property(block, "CryptoBackingDevice")?;
+            let already_listed = blocks.iter().any(|b| &b.object_path == object_path);
+            (!already_listed && blocks.iter().any(|b| b.object_path == backing))
+                .then(|| drive_block_device(object_path, interfaces))
+        })
+        .collect();
+    blocks.extend(cleartext);
+
+    blocks.sort_by(|a, b| {
+        (
+            a.crypto_backing_device.is_some(),
+            a.partition_number,
+            &a.device,
+        )
+            .cmp(&(
+                b.crypto_backing_device.is_some(),
+                b.partition_number,
+                &b.device,
+            ))
+    });
+    blocks
+}
+
+fn drive_block_device(
+    object_path: &OwnedObjectPath,
+    interfaces: &HashMap<OwnedInterfaceName, HashMap<String, OwnedValue>>,
+) -> DriveBlockDevice {
+    let block = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE);
+    let text = |name| {
+        block
+            .and_then(|b| property::<String>(b, name))
+            .filter(|s| !s.is_empty())
+    };
+
+    DriveBlockDevice {
+        object_path: object_path.clone(),
+        device: block
+            .and_then(|b| property::<Vec<u8>>(b, "Device"))
+            .map(bytestring_path)
+            .unwrap_or_default(),
+        size: block.and_then(|b| property(b, "Size")).unwrap_or_default(),
+        partition_number: interface_properties(interfaces, UDISKS2_PARTITION_INTERFACE)
+            .and_then(|p| property(p, "Number")),
+        fstype: text("IdType"),
+        label: text("IdLabel"),
+        mount_points: interface_properties(interfaces, UDISKS2_FILESYSTEM_INTERFACE)
+            .and_then(|f| property::<Vec<Vec<u8>>>(f, "MountPoints"))
+            .unwrap_or_default()
+            .into_iter()
+            .map(bytestring_path)
+            .collect(),
+        hint_system: block
+            .and_then(|b| property(b, "HintSystem"))
+            .unwrap_or_default(),
+        encrypted: interface_properties(interfaces, UDISKS2_ENCRYPTED_INTERFACE).is_some(),
+        crypto_backing_device: block
+            .and_then(|b| property::<OwnedObjectPath>(b, "CryptoBackingDevice"))
+            .filter(|p| p.as_str() != "/"),
+    }
+}
+
+/// Drives holding the root filesystem
+///
+/// Starts from the block device mounted at `/` and walks down through LUKS
+/// (`CryptoBackingDevice`), partitions (`Partition.Table`) and device-mapper or MD RAID members
+/// (`/sys/dev/block/MAJ:MIN/slaves`) until blocks with a `Drive` are reached. Root on LVM or RAID
+/// can span several drives.
+fn system_drives(objects: &ManagedObjects) -> HashSet<OwnedObjectPath> {
+    let block_of_devnum = |devnum: u64| {
+        objects.iter().find_map(|(object_path, interfaces)| {
+            let block = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE)?;
+            (property::<u64>(block, "DeviceNumber") == Some(devnum)).then(|| object_path.clone())
+        })
+    };
+
+    let mut pending: Vec<OwnedObjectPath> = objects
+        .iter()
+        .filter(|(_, interfaces)| {
+            interface_properties(interfaces, UDISKS2_FILESYSTEM_INTERFACE)
+                .and_then(|f| property::<Vec<Vec<u8>>>(f, "MountPoints"))
+                .is_some_and(|points| {
+                    points
+                        .into_iter()
+                        .any(|p| bytestring_path(p) == Path::new("/"))
+                })
+        })
+        .map(|(object_path, _)| object_path.clone())
+        .collect();
+    // The root filesystem is not a UDisks2 Filesystem object when it is, say, btrfs on a subvolume
+    if pending.is_empty()
+        && let Ok(root) = fs::metadata("/")
+        && let Some(block) = block_of_devnum(root.dev())
+    {
+        pending.push(block);
+    }
+
+    let mut drives = HashSet::new();
+    let mut seen = HashSet::new();
+    while let Some(object_path) = pending.pop() {
+        if !seen.insert(object_path.clone()) {
+            continue;
+        }
+        let Some(interfaces) = objects.get(&object_path) else {
+            continue;
+        };
+        if let Some(partition) = interface_properties(interfaces, UDISKS2_PARTITION_INTERFACE)
+            && let Some(table) = property::<OwnedObjectPath>(partition, "Table")
+        {
+            pending.push(table);
+        }
+        let Some(block) = interface_properties(interfaces, UDISKS2_BLOCK_INTERFACE) else {
+            continue;
+        };
+        if let Some(drive) = property::<OwnedObjectPath>(block, "Drive")
+            && drive.as_str() != "/"
+        {
+            drives.insert(drive);
+        }
+        if let Some(backing) = property::<OwnedObjectPath>(block, "CryptoBackingDevice")
+            && backing.as_str() != "/"
+        {
+            pending.push(backing);
+        }
+        if let Some(devnum) = property::<u64>(block, "DeviceNumber") {
+            pending.extend(sysfs_slaves(devnum).into_iter().filter_map(block_of_devnum));
+        }
+    }
+    drives
+}
+
+/// Device numbers of the devices stacked below `devnum`, e.g. the LVM physical volumes
+fn sysfs_slaves(devnum: u64) -> Vec<u64> {
+    let (major, minor) = (libc::major(devnum), libc::minor(devnum));
+    let Ok(entries) = fs::read_dir(format!("/sys/dev/block/{major}:{minor}/slaves")) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let dev = fs::read_to_string(entry.ok()?.path().join("dev")).ok()?;
+            let (major, minor) = dev.trim().split_once(':')?;
+            Some(libc::makedev(major.parse().ok()?, minor.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Decode a NUL terminated `ay` property such as `BackingFile` or a mount point
+fn bytestring_path(mut bytes: Vec<u8>) -> PathBuf {
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    PathBuf::from(OsStr::from_bytes(&bytes))
+}
+
+/// Resolve symlinks where possible so different spellings of a path compare equal
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether every partition listed in the partition table of `block` has been exported yet
+pub(crate) fn partition_table_settled(objects: &ManagedObjects, block: &ObjectPath<'_>) -> bool {
+    let Some(interfaces) = objects.get(&OwnedObjectPath::from(block.clone())) else {
+        return false;
+    };
+    let Some(table) = interface_properties(interfaces, UDISKS2_PARTITION_TABLE_INTERFACE) else {
+        return true;
+    };
+    // `Partitions` only exists since UDisks2 2.7.2, older daemons are treated as settled
+    let partitions: Vec<OwnedObjectPath> = property(table, "Partitions").unwrap_or_default();
+    partitions.iter().all(|p| objects.contains_key(p))
+}
+
+impl UDisks2Filesystem {
+    pub async fn new(connection: &Connection, object_path: ObjectPath<'static>) -> Result<Self> {
+        let proxy = FilesystemProxy::builder(connection)
+            .path(object_path.clone())?
+            .build()
+            .await
+            .context("Failed to create filesystem proxy")?;
+
+        Ok(Self { proxy, object_path })
+    }
+
+    /// The typed `org.freedesktop.UDisks2.Filesystem` proxy
+    pub fn proxy(&self) -> &FilesystemProxy<'static> {
+        &self.proxy
+    }
+
+    /// Mount the filesystem and describe the result
+    ///
+    /// The mount point returned by UDisks2 has to be listed in `MountPoints`; if it is not, the
+    /// filesystem is unmounted again and an error names both.
+    pub async fn mount(&self, options: &MountOptions) -> Result<MountInfo> {
+        debug!("Mount options: {options:?}");
+        let mount_path = policy::call(self.proxy.mount(&with_auth(options.to_dbus_options())))
+            .await
+            .context("Failed to mount filesystem")?;
+        info!("Mounted at: {mount_path}");
+
+        match self.mount_info(Path::new(&mount_path)).await {
+            Ok(mount_info) => Ok(mount_info),
+            Err(e) => {
+                if let Err(unmount_err) = self.unmount(&UnmountOptions::default()).await {
+                    warn!("Failed to unmount {}: {unmount_err:#}", self.object_path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Describe this filesystem as mounted at `mount_path`, failing unless `MountPoints` lists it
+    ///
+    /// Reads every property through `GetManagedObjects`, so nothing comes from a stale cache.
+    pub async fn mount_info(&self, mount_path: &Path) -> Result<MountInfo> {
+        let objects = UDisks2ObjectManager::new(self.proxy.inner().connection())
+            .await?
+            .managed_objects()
+            .await?;
+        mount_info(&objects, &self.object_path, mount_path)
+    }
+
+    /// Current mount points (`MountPoints`)
+    pub async fn mount_points(&self) -> Result<Vec<PathBuf>> {
+        Ok(policy::read(|| self.proxy.mount_points())
+            .await
+            .context("Failed to get mount points")?
+            .into_iter()
+            .map(bytestring_path)
+            .collect())
+    }
+
+    /// Size of the filesystem in bytes, 0 if unknown (`Size`)
+    pub async fn size(&self) -> Result<u64> {
+        policy::read(|| self.proxy.size())
+            .await
+            .context("Failed to get filesystem size")
+    }
+
+    /// Unmount the filesystem, retrying while it is busy if `options.retry` is set
+    ///
+    /// When it stays busy, the error lists the processes still using the mount point.
+    pub async fn unmount(&self, options: &UnmountOptions) -> Result<()> {
+        let dbus_options = with_auth(options.to_dbus_options());
+        let mut delays = options.retry.iter().flat_map(UnmountRetry::delays);
+        loop {
+            let error = match policy::call(self.proxy.unmount(&dbus_options)).await {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            if !matches!(
+                Udisks2Error::from_zbus(&error),
+                Some(Udisks2Error::DeviceBusy { .. })
+            ) {
+                return Err(error).context("Failed to unmount filesystem");
+            }
+            if let Some(delay) = delays.next() {
+                warn!("{} is busy, trying again in {delay:?}", self.object_path);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+
+            let mount_points = self.mount_points().await.unwrap_or_default();
+            let message = match mount_points.first() {
+                Some(mount_point) => format!(
+                    "Failed to unmount filesystem, {}",
+                    busy_message(mount_point)
+                ),
+                None => "Failed to unmount filesystem".to_string(),
+            };
+            return Err(error).context(message);
+        }
+
+        info!("Unmounted successfully");
         Ok(())
     }
+
+    pub fn object_path(&self) -> &ObjectPath<'static> {
+        &self.object_path
+    }
+
+    /// Check the filesystem without modifying it (`Check`), it must not be mounted
+    pub async fn check(&self) -> Result<FsckStatus> {
+        let consistent = policy::job(self.proxy.check(&auth_options()))
+            .await
+            .with_context(|| format!("Failed to check filesystem {}", self.object_path))?;
+        Ok(if consistent {
+            FsckStatus::Clean
+        } else {
+            FsckStatus::Damaged
+        })
+    }
+
+    /// Repair the filesystem (`Repair`), it must not be mounted
+    pub async fn repair(&self) -> Result<FsckStatus> {
+        let repaired = policy::job(self.proxy.repair(&auth_options()))
+            .await
+            .with_context(|| format!("Failed to repair filesystem {}", self.object_path))?;
+        Ok(if repaired {
+            FsckStatus::Repaired
+        } else {
+            FsckStatus::Unrepaired
+        })
+    }
+
+    /// Grow or shrink the filesystem to `size` bytes, or to fill its block device (`Resize`)
+    pub async fn resize(&self, size: Option<u64>) -> Result<()> {
+        policy::job(self.proxy.resize(size.unwrap_or(0), &auth_options()))
+            .await
+            .with_context(|| format!("Failed to resize filesystem {}", self.object_path))?;
+
+        info!("Resized {}", self.object_path);
+        Ok(())
+    }
+
+    /// Change the filesystem label (`SetLabel`)
+    pub async fn set_label(&self, label: &str) -> Result<()> {
+        policy::call(self.proxy.set_label(label, &auth_options()))
+            .await
+            .with_context(|| format!("Failed to set label of {}", self.object_path))?;
+
+        info!("Set label of {} to {label:?}", self.object_path);
+        Ok(())
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        UDisks2Loop::new(self.proxy.inner().connection(), self.object_path)
+            .await?
+            .delete()
+            .await
+    }
 }
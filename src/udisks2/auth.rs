@@ -0,0 +1,184 @@
+//! Polkit authorization modes for UDisks2 calls
+//!
+//! UDisks2 asks polkit before most operations. On a desktop a graphical agent shows a password
+//! dialog; in cron jobs and SSH sessions there is none, and a call that needs one waits until it
+//! times out. The process-wide [`AuthMode`] decides whether calls may ask at all: every options
+//! dictionary built in [`crate::udisks2`] goes through [`with_auth`]. [`TtyAgent`] provides the
+//! prompt on a terminal with `pkttyagent`.
+
+use super::{policy::call_policy, proxies::Options};
+use anyhow::{Context, Result};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{IsTerminal, Read},
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    sync::RwLock,
+    time::Duration,
+};
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::zvariant::Value;
+
+/// Whether UDisks2 operations may prompt for authorization
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMode {
+    /// Let polkit ask for a password, through the desktop agent or `pkttyagent` on a terminal
+    #[default]
+    Interactive,
+    /// Never prompt (`auth.no_user_interaction`): operations that need a password fail with
+    /// `NotAuthorizedCanObtain`, operations allowed by polkit rules still work
+    NonInteractive,
+    /// Never prompt, and stop at the first failed operation instead of carrying on with the other
+    /// filesystems or drives
+    FailFast,
+}
+
+impl AuthMode {
+    pub const ALL: [AuthMode; 3] = [Self::Interactive, Self::NonInteractive, Self::FailFast];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Interactive => "interactive",
+            Self::NonInteractive => "non-interactive",
+            Self::FailFast => "fail-fast",
+        }
+    }
+
+    /// Whether polkit may ask the user
+    pub fn allows_interaction(self) -> bool {
+        self == Self::Interactive
+    }
+
+    /// Whether the first failure ends a multi-step operation
+    pub fn is_fail_fast(self) -> bool {
+        self == Self::FailFast
+    }
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown auth mode '{s}' (expected one of: {})",
+                    Self::ALL.map(Self::as_str).join(", ")
+                )
+            })
+    }
+}
+
+static MODE: RwLock<AuthMode> = RwLock::new(AuthMode::Interactive);
+
+/// Use `mode` for every following UDisks2 call in this process
+pub fn set_auth_mode(mode: AuthMode) {
+    *MODE.write().unwrap_or_else(|e| e.into_inner()) = mode;
+}
+
+/// The mode set with [`set_auth_mode`]
+pub fn auth_mode() -> AuthMode {
+    *MODE.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Add the authorization options of the current mode to a UDisks2 options dictionary
+pub(crate) fn with_auth(mut options: Options<'_>) -> Options<'_> {
+    if !auth_mode().allows_interaction() {
+        options.insert("auth.no_user_interaction".to_string(), Value::from(true));
+    }
+    options
+}
+
+/// Options with nothing but the authorization options of the current mode
+pub(crate) fn auth_options() -> Options<'static> {
+    with_auth(Options::new())
+}
+
+/// A `pkttyagent` answering polkit prompts for this process on its terminal
+///
+/// The agent is stopped when this is dropped.
+pub struct TtyAgent {
+    child: Child,
+}
+
+impl TtyAgent {
+    /// Start `pkttyagent` for this process if stdin is a terminal
+    ///
+    /// Returns `None` without a terminal, or when `pkttyagent` is missing or another agent is
+    /// already registered, like the desktop one (`--fallback`). Waits for the agent to register
+    /// for at most the call timeout of the [`CallPolicy`](super::policy::CallPolicy).
+    pub async fn spawn() -> Result<Option<Self>> {
+        if !std::io::stdin().is_terminal() {
+            return Ok(None);
+        }
+
+        // The agent closes the notify fd once it is registered, so the fd has to survive exec
+        let (mut ready, notify) = std::io::pipe().context("Failed to create a pipe")?;
+        let notify_fd = notify.as_raw_fd();
+        let mut command = Command::new("pkttyagent");
+        command
+            .arg("--process")
+            .arg(std::process::id().to_string())
+            .arg("--notify-fd")
+            .arg(notify_fd.to_string())
+            .arg("--fallback")
+            .stdout(Stdio::null());
+        // SAFETY: fcntl is async-signal-safe and only changes the child's copy of the fd
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(notify_fd, libc::F_SETFD, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn();
+        drop(notify);
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("pkttyagent is not installed, polkit cannot prompt on this terminal");
+                return Ok(None);
+            }
+            Err(e) => return Err(e).context("Failed to start pkttyagent"),
+        };
+
+        // EOF once the agent is registered, or once it exited
+        let registered = tokio::task::spawn_blocking(move || ready.read_to_end(&mut Vec::new()));
+        let timeout_secs = call_policy().call_timeout_secs;
+        if timeout_secs == 0 {
+            let _ = registered.await;
+        } else if tokio::time::timeout(Duration::from_secs(timeout_secs), registered)
+            .await
+            .is_err()
+        {
+            warn!("pkttyagent did not register within {timeout_secs}s, keeping it running");
+        }
+        if let Some(status) = child.try_wait().context("Failed to wait for pkttyagent")? {
+            debug!("pkttyagent exited with {status}, using the existing agent");
+            return Ok(None);
+        }
+        debug!("Started pkttyagent (pid {})", child.id());
+        Ok(Some(Self { child }))
+    }
+}
+
+impl Drop for TtyAgent {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
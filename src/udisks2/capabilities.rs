@@ -0,0 +1,170 @@
+//! Preflight checks: is UDisks2 there at all, which version is it and what can it do
+//!
+//! [`Udisks2Capabilities::probe`] runs before the first real call, so a missing daemon is
+//! reported as such instead of as an opaque `LoopSetup` failure, and features that need a newer
+//! daemon can be refused up front with [`Udisks2Capabilities::require`].
+
+use super::{UDISKS2_SERVICE, UDisks2Manager, error::Udisks2Error, policy};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fmt;
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::{Connection, fdo::DBusProxy, names::BusName};
+
+/// A UDisks2 version such as `2.10.1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Udisks2Version {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl Udisks2Version {
+    pub const fn new(major: u32, minor: u32, micro: u32) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+        }
+    }
+
+    /// Parse `Manager.Version`, ignoring suffixes like `-git`; missing parts count as 0
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.').map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(part, |end| &part[..end]);
+            digits.parse::<u32>().ok()
+        });
+        let major = parts.next()??;
+        Some(Self::new(
+            major,
+            parts.next().flatten().unwrap_or(0),
+            parts.next().flatten().unwrap_or(0),
+        ))
+    }
+}
+
+impl fmt::Display for Udisks2Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+/// Functionality that is missing from older UDisks2 releases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Udisks2Feature {
+    /// `Filesystem.Check`, `Repair` and `Resize`
+    FilesystemMaintenance,
+}
+
+impl Udisks2Feature {
+    /// First release that has the feature
+    pub fn since(&self) -> Udisks2Version {
+        match self {
+            Self::FilesystemMaintenance => Udisks2Version::new(2, 7, 2),
+        }
+    }
+}
+
+impl fmt::Display for Udisks2Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::FilesystemMaintenance => "Filesystem check, repair and resize",
+        })
+    }
+}
+
+/// What the running UDisks2 daemon offers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Udisks2Capabilities {
+    /// `Manager.Version` as reported
+    pub version_string: String,
+    /// Parsed version, `None` if the daemon reported something unexpected
+    pub version: Option<Udisks2Version>,
+    /// `Manager.SupportedFilesystems`, `None` if the daemon does not have the property
+    pub supported_filesystems: Option<Vec<String>>,
+}
+
+impl Udisks2Capabilities {
+    /// Check that UDisks2 is running or can be activated, then read its version and filesystems
+    ///
+    /// On a peer-to-peer connection there is no bus to ask, so only the manager is queried.
+    pub async fn probe(connection: &Connection) -> Result<Self> {
+        if connection.unique_name().is_some() {
+            check_activatable(connection).await?;
+        }
+
+        let manager = UDisks2Manager::new(connection).await?;
+        let version_string = manager.version().await?;
+        let version = Udisks2Version::parse(&version_string);
+        if version.is_none() {
+            warn!("Unrecognized UDisks2 version {version_string:?}");
+        }
+        let supported_filesystems = match manager.supported_filesystems().await {
+            Ok(filesystems) => Some(filesystems),
+            Err(e) => {
+                warn!("Cannot tell which filesystems UDisks2 supports: {e:#}");
+                None
+            }
+        };
+        debug!("UDisks2 {version_string}, filesystems: {supported_filesystems:?}");
+
+        Ok(Self {
+            version_string,
+            version,
+            supported_filesystems,
+        })
+    }
+
+    /// Whether the daemon has `feature`, assuming it does when the version is unknown
+    pub fn supports(&self, feature: Udisks2Feature) -> bool {
+        self.version
+            .is_none_or(|version| version >= feature.since())
+    }
+
+    /// Fail with [`Udisks2Error::NotSupported`] if the daemon is too old for `feature`
+    pub fn require(&self, feature: Udisks2Feature) -> Result<()> {
+        if self.supports(feature) {
+            return Ok(());
+        }
+        Err(Udisks2Error::NotSupported {
+            message: format!(
+                "{feature} needs UDisks2 {} or newer, the running daemon is {}",
+                feature.since(),
+                self.version_string
+            ),
+        }
+        .into())
+    }
+}
+
+/// Fail with [`Udisks2Error::ServiceUnavailable`] unless udisksd owns its name or can be
+/// started by the bus
+async fn check_activatable(connection: &Connection) -> Result<()> {
+    let dbus = DBusProxy::new(connection)
+        .await
+        .context("Failed to create D-Bus proxy")?;
+    let name = BusName::try_from(UDISKS2_SERVICE)?;
+    if policy::read(|| async { Ok(dbus.name_has_owner(name.clone()).await?) })
+        .await
+        .context("Failed to look up the UDisks2 service")?
+    {
+        return Ok(());
+    }
+    let activatable = policy::read(|| async { Ok(dbus.list_activatable_names().await?) })
+        .await
+        .context("Failed to list activatable D-Bus services")?;
+    if activatable
+        .iter()
+        .any(|name| name.as_str() == UDISKS2_SERVICE)
+    {
+        debug!("{UDISKS2_SERVICE} is not running yet but can be activated");
+        return Ok(());
+    }
+    Err(Udisks2Error::ServiceUnavailable {
+        message: format!("{UDISKS2_SERVICE} is neither running nor activatable on this bus"),
+    }
+    .into())
+}
@@ -0,0 +1,170 @@
+//! Typed UDisks2 failures, classified from the D-Bus error name of a method reply
+//!
+//! The client wrappers keep returning `anyhow` errors with context. [`Udisks2Error::find`] looks
+//! through such an error chain for the D-Bus error underneath, so callers can tell a missing
+//! polkit authorization from a busy device without matching on message strings.
+
+use thiserror::Error;
+use zbus::DBusError;
+
+/// A failed UDisks2 call, by D-Bus error name
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Udisks2Error {
+    /// `org.freedesktop.UDisks2.Error.NotAuthorized*`: polkit denied the action
+    #[error("Not authorized: {message}")]
+    NotAuthorized {
+        message: String,
+        /// `NotAuthorizedCanObtain`: an authentication agent could have granted it
+        can_obtain: bool,
+    },
+    /// `org.freedesktop.UDisks2.Error.Cancelled` or `NotAuthorizedDismissed`
+    #[error("Cancelled: {message}")]
+    Cancelled { message: String },
+    /// `org.freedesktop.UDisks2.Error.DeviceBusy`
+    #[error("Device is busy: {message}")]
+    DeviceBusy { message: String },
+    /// `org.freedesktop.UDisks2.Error.AlreadyMounted`
+    #[error("Already mounted: {message}")]
+    AlreadyMounted { message: String },
+    /// `org.freedesktop.UDisks2.Error.NotMounted`
+    #[error("Not mounted: {message}")]
+    NotMounted { message: String },
+    /// `org.freedesktop.UDisks2.Error.NotSupported` and `OptionNotPermitted`
+    #[error("Not supported: {message}")]
+    NotSupported { message: String },
+    /// `org.freedesktop.DBus.Error.ServiceUnknown` and friends: udisksd is not installed or not
+    /// running
+    #[error("UDisks2 is not available: {message}")]
+    ServiceUnavailable { message: String },
+    /// `org.freedesktop.DBus.Error.NoReply` or `Timeout`
+    #[error("UDisks2 did not answer: {message}")]
+    Timeout { message: String },
+    /// `org.freedesktop.UDisks2.Error.Failed` and every other UDisks2 error
+    #[error("{name}: {message}")]
+    Failed { name: String, message: String },
+}
+
+impl Udisks2Error {
+    /// Classify a D-Bus error reply by its error name
+    pub fn from_dbus(name: &str, message: &str) -> Self {
+        let message = message.to_string();
+        match name.strip_prefix("org.freedesktop.UDisks2.Error.") {
+            Some("NotAuthorized") => Self::NotAuthorized {
+                message,
+                can_obtain: false,
+            },
+            Some("NotAuthorizedCanObtain") => Self::NotAuthorized {
+                message,
+                can_obtain: true,
+            },
+            Some("NotAuthorizedDismissed" | "Cancelled") => Self::Cancelled { message },
+            Some("DeviceBusy") => Self::DeviceBusy { message },
+            Some("AlreadyMounted") => Self::AlreadyMounted { message },
+            Some("NotMounted") => Self::NotMounted { message },
+            Some("NotSupported" | "OptionNotPermitted") => Self::NotSupported { message },
+            _ => match name {
+                "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+                | "org.freedesktop.DBus.Error.Spawn.ServiceNotFound"
+                | "org.freedesktop.DBus.Error.Spawn.ChildExited" => {
+                    Self::ServiceUnavailable { message }
+                }
+                "org.freedesktop.DBus.Error.NoReply" | "org.freedesktop.DBus.Error.Timeout" => {
+                    Self::Timeout { message }
+                }
+                _ => Self::Failed {
+                    name: name.to_string(),
+                    message,
+                },
+            },
+        }
+    }
+
+    /// Classify a zbus error, `None` if it is not a D-Bus error reply
+    pub fn from_zbus(error: &zbus::Error) -> Option<Self> {
+        match error {
+            zbus::Error::MethodError(name, message, _) => Some(Self::from_dbus(
+                name.as_str(),
+                message.as_deref().unwrap_or_default(),
+            )),
+            zbus::Error::FDO(error) => Self::from_fdo(error),
+            _ => None,
+        }
+    }
+
+    fn from_fdo(error: &zbus::fdo::Error) -> Option<Self> {
+        match error {
+            zbus::fdo::Error::ZBus(error) => Self::from_zbus(error),
+            _ => Some(Self::from_dbus(
+                error.name().as_str(),
+                error.description().unwrap_or_default(),
+            )),
+        }
+    }
+
+    /// The first UDisks2 failure in the chain of `error`, looking through `anyhow` context
+    pub fn find(error: &anyhow::Error) -> Option<Self> {
+        error.chain().find_map(|cause| {
+            if let Some(error) = cause.downcast_ref::<Self>() {
+                Some(error.clone())
+            } else if let Some(error) = cause.downcast_ref::<zbus::Error>() {
+                Self::from_zbus(error)
+            } else {
+                cause
+                    .downcast_ref::<zbus::fdo::Error>()
+                    .and_then(Self::from_fdo)
+            }
+        })
+    }
+
+    /// What the user can do about it
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::NotAuthorized {
+                can_obtain: true, ..
+            } => {
+                "Run from a desktop session with a polkit authentication agent, or start \
+                 pkttyagent in this terminal"
+            }
+            Self::NotAuthorized { .. } => {
+                "Add a polkit rule granting org.freedesktop.udisks2.* actions to this user, or \
+                 run as a user in an administrative group"
+            }
+            Self::Cancelled { .. } => "The authentication dialog was dismissed, try again",
+            Self::DeviceBusy { .. } => {
+                "Close the programs using the device, listed above when they could be found, \
+                 then try again"
+            }
+            Self::AlreadyMounted { .. } => {
+                "Unmount it first, or use the existing mount point shown by the list command"
+            }
+            Self::NotMounted { .. } => {
+                "Nothing is mounted there, the list command shows what is mounted"
+            }
+            Self::NotSupported { .. } => {
+                "The running UDisks2 version or its modules do not support this operation or option"
+            }
+            Self::ServiceUnavailable { .. } => {
+                "Install udisks2 and make sure udisksd can be started (systemctl status \
+                 udisks2), or use --backend loop or --backend userspace"
+            }
+            Self::Timeout { .. } => "udisksd is stuck or overloaded, check journalctl -u udisks2",
+            Self::Failed { .. } => "udisksd may have logged more, check journalctl -u udisks2",
+        }
+    }
+
+    /// Process exit code for the command line tool, 1 being a generic failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Failed { .. } => 1,
+            Self::NotAuthorized { .. } => 10,
+            Self::Cancelled { .. } => 11,
+            Self::DeviceBusy { .. } => 12,
+            Self::AlreadyMounted { .. } => 13,
+            Self::NotMounted { .. } => 14,
+            Self::NotSupported { .. } => 15,
+            Self::ServiceUnavailable { .. } => 16,
+            Self::Timeout { .. } => 17,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Timeouts and retries for UDisks2 D-Bus calls
+//!
+//! zbus waits for a reply forever, so a stuck or missing udisksd would hang every command. Each
+//! wrapper method in [`crate::udisks2`] runs its proxy call through [`read`], [`call`] or [`job`],
+//! which apply the process-wide [`CallPolicy`] set with [`set_call_policy`].
+
+use super::error::Udisks2Error;
+use serde::{Deserialize, Serialize};
+use std::{future::Future, sync::RwLock, time::Duration};
+use tracing::warn;
+
+/// How long to wait for UDisks2, and how often to retry reads
+///
+/// Timeouts of 0 mean waiting forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CallPolicy {
+    /// Seconds to wait for a property read or `GetManagedObjects`
+    pub timeout_secs: u64,
+    /// Seconds to wait for a method call such as `LoopSetup` or `Mount`, including the time
+    /// spent in a polkit authentication dialog
+    pub call_timeout_secs: u64,
+    /// Seconds to wait for long jobs: `Format`, `Check`, `Repair` and `Resize`
+    pub job_timeout_secs: u64,
+    /// Retries of a read that timed out or found the service still starting
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled every time
+    pub retry_delay_ms: u64,
+}
+
+impl CallPolicy {
+    const DEFAULT: Self = Self {
+        timeout_secs: 25,
+        call_timeout_secs: 120,
+        job_timeout_secs: 0,
+        retries: 2,
+        retry_delay_ms: 500,
+    };
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+static POLICY: RwLock<CallPolicy> = RwLock::new(CallPolicy::DEFAULT);
+
+/// Use `policy` for every following UDisks2 call in this process
+pub fn set_call_policy(policy: CallPolicy) {
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = policy;
+}
+
+/// The policy set with [`set_call_policy`]
+pub fn call_policy() -> CallPolicy {
+    *POLICY.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run a side-effect free call, retrying timeouts and a service that is not up yet
+pub(crate) async fn read<T, F, Fut>(mut call: F) -> zbus::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = zbus::Result<T>>,
+{
+    let policy = call_policy();
+    let mut delay = Duration::from_millis(policy.retry_delay_ms);
+    let mut retries = policy.retries;
+    loop {
+        let error = match with_timeout(policy.timeout_secs, call()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let transient = matches!(
+            Udisks2Error::from_zbus(&error),
+            Some(Udisks2Error::Timeout { .. } | Udisks2Error::ServiceUnavailable { .. })
+        );
+        if !transient || retries == 0 {
+            return Err(error);
+        }
+        warn!("UDisks2 call failed, retrying in {delay:?}: {error}");
+        tokio::time::sleep(delay).await;
+        delay = delay.saturating_mul(2);
+        retries -= 1;
+    }
+}
+
+/// Run a method call once, with the call timeout
+pub(crate) async fn call<T>(call: impl Future<Output = zbus::Result<T>>) -> zbus::Result<T> {
+    with_timeout(call_policy().call_timeout_secs, call).await
+}
+
+/// Run a long job once, with the job timeout
+pub(crate) async fn job<T>(job: impl Future<Output = zbus::Result<T>>) -> zbus::Result<T> {
+    with_timeout(call_policy().job_timeout_secs, job).await
+}
+
+async fn with_timeout<T>(
+    secs: u64,
+    call: impl Future<Output = zbus::Result<T>>,
+) -> zbus::Result<T> {
+    if secs == 0 {
+        return call.await;
+    }
+    let timeout = Duration::from_secs(secs);
+    tokio::time::timeout(timeout, call)
+        .await
+        .unwrap_or_else(|_| {
+            Err(zbus::Error::FDO(Box::new(zbus::fdo::Error::Timeout(
+                format!("UDisks2 did not reply within {timeout:?}"),
+            ))))
+        })
+}
@@ -0,0 +1,433 @@
+//! Typed D-Bus proxies for the UDisks2 interfaces
+//!
+//! See <https://storaged.org/doc/udisks2-api/latest/> for the interface reference. Every method
+//! takes the usual `a{sv}` options dictionary as its last argument.
+
+use std::collections::HashMap;
+use zbus::{
+    proxy,
+    zvariant::{Fd, OwnedFd, OwnedObjectPath, OwnedValue, Value},
+};
+
+/// Options dictionary (`a{sv}`) accepted by every UDisks2 method
+pub type Options<'a> = HashMap<String, Value<'a>>;
+
+/// `org.freedesktop.UDisks2.Manager` on `/org/freedesktop/UDisks2/Manager`
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Manager",
+    default_service = "org.freedesktop.UDisks2",
+    default_path = "/org/freedesktop/UDisks2/Manager"
+)]
+pub trait Manager {
+    /// Attach a file descriptor to a new loop device and return its object path
+    fn loop_setup(&self, fd: Fd<'_>, options: &Options<'_>) -> zbus::Result<OwnedObjectPath>;
+
+    /// Whether a filesystem type can be created, and the missing utility if not
+    fn can_format(&self, fstype: &str) -> zbus::Result<(bool, String)>;
+
+    /// Whether a filesystem type can be resized, the supported resize modes and missing utility
+    fn can_resize(&self, fstype: &str) -> zbus::Result<(bool, u64, String)>;
+
+    /// Whether a filesystem type can be checked, and the missing utility if not
+    fn can_check(&self, fstype: &str) -> zbus::Result<(bool, String)>;
+
+    /// Whether a filesystem type can be repaired, and the missing utility if not
+    fn can_repair(&self, fstype: &str) -> zbus::Result<(bool, String)>;
+
+    /// Every block device object
+    fn get_block_devices(&self, options: &Options<'_>) -> zbus::Result<Vec<OwnedObjectPath>>;
+
+    /// Block devices matching a device specification such as `{"path": "/dev/sda"}`
+    fn resolve_device(
+        &self,
+        devspec: &Options<'_>,
+        options: &Options<'_>,
+    ) -> zbus::Result<Vec<OwnedObjectPath>>;
+
+    #[zbus(property)]
+    fn version(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn supported_filesystems(&self) -> zbus::Result<Vec<String>>;
+
+    #[zbus(property)]
+    fn supported_encryption_types(&self) -> zbus::Result<Vec<String>>;
+
+    #[zbus(property)]
+    fn default_encryption_type(&self) -> zbus::Result<String>;
+}
+
+/// `org.freedesktop.UDisks2.Block`, present on every block device object
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Block",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Block {
+    /// Create a filesystem, partition table or other content on the device
+    fn format(&self, fstype: &str, options: &Options<'_>) -> zbus::Result<()>;
+
+    /// Open the device read-only for backing it up
+    fn open_for_backup(&self, options: &Options<'_>) -> zbus::Result<OwnedFd>;
+
+    /// Open the device for writing an image to it
+    fn open_for_restore(&self, options: &Options<'_>) -> zbus::Result<OwnedFd>;
+
+    /// Open the device with the given mode (`r`, `w` or `rw`)
+    fn open_device(&self, mode: &str, options: &Options<'_>) -> zbus::Result<OwnedFd>;
+
+    /// Ask the kernel to re-read the device
+    fn rescan(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn device(&self) -> zbus::Result<Vec<u8>>;
+
+    #[zbus(property)]
+    fn preferred_device(&self) -> zbus::Result<Vec<u8>>;
+
+    #[zbus(property)]
+    fn symlinks(&self) -> zbus::Result<Vec<Vec<u8>>>;
+
+    #[zbus(property)]
+    fn device_number(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn id(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn size(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn read_only(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn drive(&self) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(property)]
+    fn id_usage(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn id_type(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn id_version(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn id_label(&self) -> zbus::Result<String>;
+
+    #[zbus(property, name = "IdUUID")]
+    fn id_uuid(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn crypto_backing_device(&self) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(property)]
+    fn hint_partitionable(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn hint_system(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn hint_ignore(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn hint_auto(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn hint_name(&self) -> zbus::Result<String>;
+}
+
+/// `org.freedesktop.UDisks2.Filesystem`, present on block devices with a mountable filesystem
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Filesystem",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Filesystem {
+    /// Mount the filesystem and return the mount path
+    fn mount(&self, options: &Options<'_>) -> zbus::Result<String>;
+
+    fn unmount(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn set_label(&self, label: &str, options: &Options<'_>) -> zbus::Result<()>;
+
+    /// Resize the filesystem to `size` bytes, 0 means fill the block device
+    fn resize(&self, size: u64, options: &Options<'_>) -> zbus::Result<()>;
+
+    /// Check the filesystem, returning whether it is consistent
+    fn check(&self, options: &Options<'_>) -> zbus::Result<bool>;
+
+    /// Repair the filesystem, returning whether it was repaired
+    fn repair(&self, options: &Options<'_>) -> zbus::Result<bool>;
+
+    /// Make the calling user the owner of the filesystem root
+    fn take_ownership(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn mount_points(&self) -> zbus::Result<Vec<Vec<u8>>>;
+
+    #[zbus(property)]
+    fn size(&self) -> zbus::Result<u64>;
+}
+
+/// `org.freedesktop.UDisks2.Loop`, present on loop devices
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Loop",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Loop {
+    fn delete(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn set_autoclear(&self, value: bool, options: &Options<'_>) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn backing_file(&self) -> zbus::Result<Vec<u8>>;
+
+    #[zbus(property)]
+    fn autoclear(&self) -> zbus::Result<bool>;
+
+    #[zbus(property, name = "SetupByUID")]
+    fn setup_by_uid(&self) -> zbus::Result<u32>;
+}
+
+/// `org.freedesktop.UDisks2.Partition`, present on partitions
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Partition",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Partition {
+    fn set_type(&self, partition_type: &str, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn set_name(&self, name: &str, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn set_flags(&self, flags: u64, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn resize(&self, size: u64, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn delete(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn number(&self) -> zbus::Result<u32>;
+
+    #[zbus(property, name = "Type")]
+    fn partition_type(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn flags(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn offset(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn size(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn name(&self) -> zbus::Result<String>;
+
+    #[zbus(property, name = "UUID")]
+    fn uuid(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn table(&self) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(property)]
+    fn is_container(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn is_contained(&self) -> zbus::Result<bool>;
+}
+
+/// `org.freedesktop.UDisks2.PartitionTable`, present on partitioned block devices
+#[proxy(
+    interface = "org.freedesktop.UDisks2.PartitionTable",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait PartitionTable {
+    /// Create a partition and return its object path
+    fn create_partition(
+        &self,
+        offset: u64,
+        size: u64,
+        partition_type: &str,
+        name: &str,
+        options: &Options<'_>,
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    /// Create a partition, format it and return its object path
+    #[allow(clippy::too_many_arguments)]
+    fn create_partition_and_format(
+        &self,
+        offset: u64,
+        size: u64,
+        partition_type: &str,
+        name: &str,
+        options: &Options<'_>,
+        format_type: &str,
+        format_options: &Options<'_>,
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(property)]
+    fn partitions(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+
+    #[zbus(property, name = "Type")]
+    fn table_type(&self) -> zbus::Result<String>;
+}
+
+/// `org.freedesktop.UDisks2.Drive`, on objects below `/org/freedesktop/UDisks2/drives`
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Drive",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Drive {
+    fn eject(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn power_off(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn set_configuration(&self, value: &Options<'_>, options: &Options<'_>) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn vendor(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn model(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn revision(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn serial(&self) -> zbus::Result<String>;
+
+    #[zbus(property, name = "WWN")]
+    fn wwn(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn id(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn configuration(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+
+    #[zbus(property)]
+    fn media(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn media_compatibility(&self) -> zbus::Result<Vec<String>>;
+
+    #[zbus(property)]
+    fn media_removable(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn media_available(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn media_change_detected(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn size(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn time_detected(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn time_media_detected(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn optical(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn optical_blank(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn rotation_rate(&self) -> zbus::Result<i32>;
+
+    #[zbus(property)]
+    fn connection_bus(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn seat(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn removable(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn ejectable(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn sort_key(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn can_power_off(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn sibling_id(&self) -> zbus::Result<String>;
+}
+
+/// `org.freedesktop.UDisks2.Encrypted`, present on LUKS and other encrypted block devices
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Encrypted",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Encrypted {
+    /// Unlock the device and return the object path of the cleartext device
+    fn unlock(&self, passphrase: &str, options: &Options<'_>) -> zbus::Result<OwnedObjectPath>;
+
+    fn lock(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    fn change_passphrase(
+        &self,
+        passphrase: &str,
+        new_passphrase: &str,
+        options: &Options<'_>,
+    ) -> zbus::Result<()>;
+
+    fn resize(&self, size: u64, options: &Options<'_>) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn hint_encryption_type(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn metadata_size(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn cleartext_device(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+/// `org.freedesktop.UDisks2.Job`, on objects below `/org/freedesktop/UDisks2/jobs`
+#[proxy(
+    interface = "org.freedesktop.UDisks2.Job",
+    default_service = "org.freedesktop.UDisks2"
+)]
+pub trait Job {
+    fn cancel(&self, options: &Options<'_>) -> zbus::Result<()>;
+
+    /// Emitted when the job finishes
+    #[zbus(signal)]
+    fn completed(&self, success: bool, message: String) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn operation(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn progress(&self) -> zbus::Result<f64>;
+
+    #[zbus(property)]
+    fn progress_valid(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn bytes(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn rate(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn start_time(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn expected_end_time(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn objects(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+
+    #[zbus(property, name = "StartedByUID")]
+    fn started_by_uid(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn cancelable(&self) -> zbus::Result<bool>;
+}
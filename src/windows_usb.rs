@@ -0,0 +1,454 @@
+//! Creating bootable Windows installer USB drives from an ISO
+//!
+//! A Windows ISO cannot simply be written to a stick for UEFI boot: firmware reads FAT32, and
+//! FAT32 cannot hold files of 4 GiB or more, which `sources/install.wim` often is. Instead the
+//! drive gets a GPT with a single FAT32 partition, the ISO is mounted with [`IsoMounter`] and its
+//! tree copied over, and an oversized `install.wim` is split into `install.swm`, `install2.swm`,
+//! ... with `wimlib-imagex split`, which Windows Setup picks up on its own.
+
+use crate::flash::{check_target, unmount_drive};
+use crate::mounter::IsoMounter;
+use crate::removal::RemovalAction;
+use crate::udisks2::{
+    DriveInfo, FormatOptions, LoopOptions, MountOptions, UDisks2Block, UDisks2Drive,
+    UDisks2Filesystem, UDisks2ObjectManager, UDisks2PartitionTable, UnmountOptions,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+#[allow(unused_imports)]
+use tracing::{debug, info, warn};
+use zbus::Connection;
+
+/// Largest file FAT32 can store
+const FAT32_MAX_FILE_SIZE: u64 = (4 << 30) - 1;
+/// Longest FAT volume label
+const FAT32_MAX_LABEL_LEN: usize = 11;
+/// FAT32 files must stay below 4 GiB, so split parts can be 4095 MiB at most
+pub const FAT32_MAX_SPLIT_MIB: u64 = 4095;
+/// GPT type GUID of a Microsoft basic data partition
+const BASIC_DATA_PARTITION_TYPE: &str = "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7";
+/// Start the partition on a 1 MiB boundary
+const PARTITION_OFFSET: u64 = 1 << 20;
+/// The Windows image that outgrows FAT32, relative to the ISO root
+const INSTALL_WIM: &str = "sources/install.wim";
+
+/// Options for [`WindowsUsbCreator::create`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowsUsbOptions {
+    /// Label of the FAT32 filesystem, at most 11 characters
+    pub label: String,
+    /// Allow writing to drives that are not removable
+    pub force: bool,
+    /// Split `install.wim` into parts of at most this many MiB when it is larger than that, 1 to
+    /// [`FAT32_MAX_SPLIT_MIB`]
+    pub split_size_mib: u64,
+    /// The `wimlib-imagex` program used for splitting
+    pub wimlib_imagex: PathBuf,
+    /// Eject the drive once it is written, or power it off if it cannot be ejected
+    pub eject: bool,
+}
+
+impl Default for WindowsUsbOptions {
+    fn default() -> Self {
+        Self {
+            label: "WININSTALL".to_string(),
+            force: false,
+            split_size_mib: 3800,
+            wimlib_imagex: PathBuf::from("wimlib-imagex"),
+            eject: true,
+        }
+    }
+}
+
+/// Progress reported to the callback of [`WindowsUsbCreator::create`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyProgress {
+    /// Bytes copied or split so far
+    pub bytes: u64,
+    /// Size of every file on the ISO
+    pub total: u64,
+}
+
+/// Outcome of a successful run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WindowsUsbReport {
+    /// Whole-disk device node that was partitioned
+    pub device: PathBuf,
+    /// Device node of the new FAT32 partition
+    pub partition: PathBuf,
+    /// Files copied, not counting split parts
+    pub files: u64,
+    /// Bytes of ISO content written
+    pub bytes: u64,
+    /// The `.swm` parts `install.wim` was split into, relative to the partition root
+    pub split_parts: Vec<PathBuf>,
+    /// How the drive was made ready for unplugging, [`RemovalAction::None`] when it was left alone
+    pub removal: RemovalAction,
+}
+
+/// What to copy from the mounted ISO, relative to its root
+#[derive(Debug, Default)]
+struct CopyPlan {
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    /// `install.wim` when it has to be split instead of copied
+    split_wim: Option<(PathBuf, u64)>,
+    total: u64,
+}
+
+/// Walk the ISO tree, refusing files FAT32 cannot store other than a splittable `install.wim`
+fn plan_copy(source: &Path, split_size: u64) -> Result<CopyPlan> {
+    let mut plan = CopyPlan::default();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(source.join(&dir))
+            .with_context(|| format!("Failed to read {}", source.join(&dir).display()))?
+            .collect::<io::Result<Vec<_>>>()
+            .with_context(|| format!("Failed to read {}", source.join(&dir).display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let relative = dir.join(entry.file_name());
+            let metadata = entry
+                .metadata()
+                .with_context(|| format!("Failed to stat {}", entry.path().display()))?;
+            if metadata.is_dir() {
+                plan.dirs.push(relative.clone());
+                pending.push(relative);
+                continue;
+            }
+
+            let size = metadata.len();
+            plan.total += size;
+            let is_install_wim = relative.to_string_lossy().eq_ignore_ascii_case(INSTALL_WIM);
+            if is_install_wim && size > split_size {
+                plan.split_wim = Some((relative, size));
+            } else if size > FAT32_MAX_FILE_SIZE {
+                return Err(anyhow::anyhow!(
+                    "{} is {size} bytes, too large for FAT32 and not a WIM that can be split",
+                    relative.display()
+                ));
+            } else {
+                plan.files.push(relative);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// Copy the planned directories and files from `source` to `dest`
+fn copy_tree(
+    source: &Path,
+    dest: &Path,
+    plan: &CopyPlan,
+    progress: &mut impl FnMut(CopyProgress),
+) -> Result<u64> {
+    for dir in &plan.dirs {
+        fs::create_dir_all(dest.join(dir))
+            .with_context(|| format!("Failed to create {}", dest.join(dir).display()))?;
+    }
+
+    let mut copied = 0;
+    for relative in &plan.files {
+        let (from, to) = (source.join(relative), dest.join(relative));
+        // Plain read and write: fs::copy would also try to copy permissions, which FAT rejects
+        let mut input =
+            File::open(&from).with_context(|| format!("Failed to open {}", from.display()))?;
+        let mut output =
+            File::create(&to).with_context(|| format!("Failed to create {}", to.display()))?;
+        copied += io::copy(&mut input, &mut output)
+            .with_context(|| format!("Failed to copy {}", relative.display()))?;
+        progress(CopyProgress {
+            bytes: copied,
+            total: plan.total,
+        });
+    }
+    Ok(copied)
+}
+
+/// Fail early, before the drive is wiped, when `wimlib-imagex` cannot be run
+async fn check_wimlib(program: &Path) -> Result<()> {
+    let output = Command::new(program)
+        .arg("--version")
+        .output()
+        .await
+        .with_context(|| {
+            format!(
+                "{} is needed to split install.wim for FAT32, install wimlib (wimtools)",
+                program.display()
+            )
+        })?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} --version failed: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Split `wim` into `install.swm`, `install2.swm`, ... next to where `dest` would be
+async fn split_wim(
+    program: &Path,
+    wim: &Path,
+    dest: &Path,
+    part_size_mib: u64,
+) -> Result<Vec<PathBuf>> {
+    let first_part = dest.with_extension("swm");
+    info!(
+        "Splitting {} into {} MiB parts at {}",
+        wim.display(),
+        part_size_mib,
+        first_part.display()
+    );
+    let output = Command::new(program)
+        .arg("split")
+        .arg(wim)
+        .arg(&first_part)
+        .arg(part_size_mib.to_string())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {}", program.display()))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} split failed: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let dir = first_part.parent().unwrap_or(Path::new("."));
+    let stem = first_part
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut parts: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            name.starts_with(&stem) && name.ends_with(".swm")
+        })
+        .collect();
+    parts.sort();
+    Ok(parts)
+}
+
+/// Turns a Windows ISO into a UEFI-bootable installer drive through UDisks2
+#[derive(Clone)]
+pub struct WindowsUsbCreator {
+    connection: Connection,
+    object_manager: UDisks2ObjectManager,
+    mounter: IsoMounter,
+}
+
+impl WindowsUsbCreator {
+    /// Partition drives over `connection`, mounting ISOs with `mounter`
+    pub async fn new(connection: &Connection, mounter: IsoMounter) -> Result<Self> {
+        Ok(Self {
+            connection: connection.clone(),
+            object_manager: UDisks2ObjectManager::new(connection).await?,
+            mounter,
+        })
+    }
+
+    /// Erase the drive `target` and make it a Windows installer from `iso`
+    ///
+    /// Nothing is written to the drive until the ISO has been checked: its files must fit and
+    /// `wimlib-imagex` must be available when `install.wim` needs splitting. `progress` is called
+    /// from a blocking worker thread after every file.
+    pub async fn create<F>(
+        &self,
+        iso: &Path,
+        target: &Path,
+        options: &WindowsUsbOptions,
+        progress: F,
+    ) -> Result<WindowsUsbReport>
+    where
+        F: FnMut(CopyProgress) + Send + 'static,
+    {
+        if options.label.is_empty()
+            || options.label.len() > FAT32_MAX_LABEL_LEN
+            || !options.label.is_ascii()
+        {
+            return Err(anyhow::anyhow!(
+                "FAT32 label must be 1 to {FAT32_MAX_LABEL_LEN} ASCII characters: {:?}",
+                options.label
+            ));
+        }
+        if !(1..=FAT32_MAX_SPLIT_MIB).contains(&options.split_size_mib) {
+            return Err(anyhow::anyhow!(
+                "Split size must be 1 to {FAT32_MAX_SPLIT_MIB} MiB for FAT32, got {}",
+                options.split_size_mib
+            ));
+        }
+
+        let drive = self.object_manager.find_drive(target).await?;
+        let loop_options = LoopOptions {
+            read_only: true,
+            ..LoopOptions::default()
+        };
+        let image = self
+            .mounter
+            .mount_guarded(iso, &loop_options, &MountOptions::default())
+            .await?;
+        let source = image
+            .mount_path()
+            .map(PathBuf::from)
+            .context("The ISO has no mounted filesystem")?;
+
+        let result = self.write_drive(&drive, &source, options, progress).await;
+        let closed = image.close().await;
+        let mut report = result?;
+        closed.context("Failed to unmount the ISO")?;
+
+        if options.eject && (drive.ejectable || drive.can_power_off) {
+            let udisks_drive =
+                UDisks2Drive::new(&self.connection, drive.object_path.clone().into_inner()).await?;
+            report.removal = if drive.ejectable {
+                udisks_drive.eject().await?;
+                RemovalAction::Ejected
+            } else {
+                udisks_drive.power_off().await?;
+                RemovalAction::PoweredOff
+            };
+        } else {
+            info!("{} can be removed now", report.device.display());
+        }
+        Ok(report)
+    }
+
+    /// Partition, format and fill the drive from the mounted ISO at `source`
+    async fn write_drive<F>(
+        &self,
+        drive: &DriveInfo,
+        source: &Path,
+        options: &WindowsUsbOptions,
+        progress: F,
+    ) -> Result<WindowsUsbReport>
+    where
+        F: FnMut(CopyProgress) + Send + 'static,
+    {
+        let split_size = options.split_size_mib << 20;
+        let plan = {
+            let source = source.to_path_buf();
+            tokio::task::spawn_blocking(move || plan_copy(&source, split_size))
+                .await
+                .context("Scan task failed")??
+        };
+        if plan.split_wim.is_some() {
+            check_wimlib(&options.wimlib_imagex).await?;
+        }
+        check_target(drive, plan.total, options.force)?;
+        let disk = drive
+            .disk()
+            .with_context(|| format!("{} has no whole-disk block device", drive.object_path))?;
+
+        unmount_drive(&self.connection, drive).await?;
+        let disk_path = disk.object_path.clone().into_inner();
+        info!(
+            "Creating a GPT partition table on {}",
+            disk.device.display()
+        );
+        UDisks2Block::new(&self.connection, disk_path.clone())
+            .await?
+            .format("gpt", &FormatOptions::default())
+            .await?;
+        let partition_path = UDisks2PartitionTable::new(&self.connection, disk_path)
+            .await?
+            .create_partition(
+                PARTITION_OFFSET,
+                0,
+                BASIC_DATA_PARTITION_TYPE,
+                &options.label,
+            )
+            .await?
+            .into_inner();
+
+        let partition = UDisks2Block::new(&self.connection, partition_path.clone()).await?;
+        let format_options = FormatOptions {
+            label: Some(options.label.clone()),
+            take_ownership: true,
+            ..FormatOptions::default()
+        };
+        partition.format("vfat", &format_options).await?;
+        let partition_device = partition.device().await?;
+
+        let filesystem = UDisks2Filesystem::new(&self.connection, partition_path).await?;
+        let dest = filesystem.mount(&MountOptions::default()).await?.mount_path;
+        let filled = self.fill(source, &dest, plan, options, progress).await;
+        let unmounted = filesystem.unmount(&UnmountOptions::default()).await;
+        let (files, bytes, split_parts) = filled?;
+        unmounted.context("Failed to unmount the new partition")?;
+
+        Ok(WindowsUsbReport {
+            device: disk.device.clone(),
+            partition: partition_device,
+            files,
+            bytes,
+            split_parts,
+            removal: RemovalAction::None,
+        })
+    }
+
+    /// Copy the ISO tree to `dest` and split `install.wim` if the plan says so
+    async fn fill<F>(
+        &self,
+        source: &Path,
+        dest: &Path,
+        plan: CopyPlan,
+        options: &WindowsUsbOptions,
+        mut progress: F,
+    ) -> Result<(u64, u64, Vec<PathBuf>)>
+    where
+        F: FnMut(CopyProgress) + Send + 'static,
+    {
+        info!("Copying {} bytes to {}", plan.total, dest.display());
+        let (copied, plan, mut progress) = {
+            let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+            tokio::task::spawn_blocking(move || {
+                let copied = copy_tree(&source, &dest, &plan, &mut progress);
+                (copied, plan, progress)
+            })
+            .await
+            .context("Copy task failed")?
+        };
+        let mut bytes = copied?;
+        let files = plan.files.len() as u64;
+
+        let mut split_parts = Vec::new();
+        if let Some((wim, size)) = &plan.split_wim {
+            let parts = split_wim(
+                &options.wimlib_imagex,
+                &source.join(wim),
+                &dest.join(wim),
+                options.split_size_mib,
+            )
+            .await?;
+            bytes += size;
+            progress(CopyProgress {
+                bytes,
+                total: plan.total,
+            });
+            split_parts = parts
+                .into_iter()
+                .map(|part| {
+                    part.strip_prefix(dest)
+                        .map(Path::to_path_buf)
+                        .unwrap_or(part)
+                })
+                .collect();
+        }
+        Ok((files, bytes, split_parts))
+    }
+}
@@ -0,0 +1,190 @@
+mod common;
+
+use common::{BLOCK_DEVICES_PATH, MockDrive, MockError, MockUdisks2, fake_iso, partition};
+use rust_system_tools::{
+    AuthMode, IsoMounter, LoopOptions, MountOptions, SafeRemover, Udisks2Error, set_auth_mode,
+};
+use std::path::Path;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// The auth mode is process-wide, so tests that change it take turns
+static MODE_LOCK: Mutex<()> = Mutex::const_new(());
+
+async fn use_mode(mode: AuthMode) -> MutexGuard<'static, ()> {
+    let guard = MODE_LOCK.lock().await;
+    set_auth_mode(mode);
+    guard
+}
+
+#[tokio::test]
+async fn test_non_interactive_mode_marks_every_call() {
+    let _mode = use_mode(AuthMode::NonInteractive).await;
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    mounted.close().await.unwrap();
+
+    let calls = mock.calls();
+    for method in [
+        "Manager.LoopSetup",
+        "Filesystem.Mount",
+        "Filesystem.Unmount",
+        "Loop.Delete",
+    ] {
+        assert!(calls.iter().any(|c| c.starts_with(method)), "{calls:?}");
+    }
+    assert_eq!(mock.non_interactive_calls(), calls);
+}
+
+#[tokio::test]
+async fn test_interactive_mode_lets_polkit_prompt() {
+    let _mode = use_mode(AuthMode::Interactive).await;
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.require_auth("Filesystem.Mount");
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    mounted.close().await.unwrap();
+
+    assert!(mock.non_interactive_calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_non_interactive_mount_fails_instead_of_prompting() {
+    let _mode = use_mode(AuthMode::NonInteractive).await;
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.require_auth("Filesystem.Mount");
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let error = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+    let classified = Udisks2Error::find(&error).unwrap();
+    assert!(
+        matches!(
+            classified,
+            Udisks2Error::NotAuthorized {
+                can_obtain: true,
+                ..
+            }
+        ),
+        "{error:#}"
+    );
+    assert_eq!(classified.exit_code(), 10);
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_fail_fast_mount_stops_at_first_filesystem() {
+    let _mode = use_mode(AuthMode::FailFast).await;
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_next_partitions(vec![partition(1, "vfat", ""), partition(2, "ext4", "")]);
+    mock.fail_next(
+        "Filesystem.Mount",
+        MockError::Failed("Error mounting: wrong fs type".into()),
+    );
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let error = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+    assert!(
+        error.to_string().starts_with("Failed to mount"),
+        "{error:#}"
+    );
+    let mounts = mock
+        .calls()
+        .into_iter()
+        .filter(|c| c.starts_with("Filesystem.Mount"))
+        .count();
+    assert_eq!(mounts, 1);
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_fail_fast_removal_stops_at_first_unmount() {
+    let _mode = use_mode(AuthMode::FailFast).await;
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.add_drive(MockDrive {
+        id: "SanDisk_Cruzer_4C530001".into(),
+        connection_bus: "usb".into(),
+        removable: true,
+        ejectable: true,
+        block_name: "sdb".into(),
+        partitions: vec![partition(1, "vfat", ""), partition(2, "ext4", "")],
+        mounts: vec![
+            (1, "/media/user/USB".into()),
+            (2, "/media/user/DATA".into()),
+        ],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    mock.require_auth("Filesystem.Unmount");
+
+    let remover = SafeRemover::new(mock.connection()).await.unwrap();
+    let error = remover
+        .safely_remove(Path::new("/dev/sdb"))
+        .await
+        .unwrap_err();
+
+    // The error keeps its D-Bus name instead of being folded into a busy list
+    assert!(matches!(
+        Udisks2Error::find(&error),
+        Some(Udisks2Error::NotAuthorized { .. })
+    ));
+    assert_eq!(
+        mock.calls(),
+        [format!("Filesystem.Unmount {BLOCK_DEVICES_PATH}/sdb1")]
+    );
+}
+
+#[test]
+fn test_auth_mode_parsing() {
+    assert_eq!(
+        "non-interactive".parse::<AuthMode>(),
+        Ok(AuthMode::NonInteractive)
+    );
+    assert_eq!("fail-fast".parse::<AuthMode>(), Ok(AuthMode::FailFast));
+    assert!(
+        "never"
+            .parse::<AuthMode>()
+            .unwrap_err()
+            .contains("interactive")
+    );
+    assert_eq!(AuthMode::default(), AuthMode::Interactive);
+    assert!(!AuthMode::FailFast.allows_interaction());
+}
@@ -0,0 +1,258 @@
+mod common;
+
+use common::iso::build_iso;
+use rust_system_tools::{
+    BackendKind, IsoMounter, LoopDeviceBackend, LoopOptions, MountBackend, MountOptions,
+    UserspaceBackend,
+};
+use std::{fs, io::Write, path::Path, process::Command, sync::Arc};
+use tempfile::{NamedTempFile, TempDir};
+
+/// An ISO 9660 image laid out like a Windows installer
+fn windows_like_iso() -> NamedTempFile {
+    let image = build_iso(
+        "WIN_TEST",
+        &[
+            ("", "README.TXT;1", b"hello from the image\n"),
+            ("SOURCES", "IDWBINFO.TXT;1", b"BuildArch=amd64\n"),
+        ],
+    );
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&image).unwrap();
+    file
+}
+
+#[test]
+fn test_backend_kind_parsing() {
+    for kind in BackendKind::ALL {
+        assert_eq!(kind.to_string().parse::<BackendKind>(), Ok(kind));
+    }
+    assert!("fuse".parse::<BackendKind>().is_err());
+    assert_eq!(BackendKind::default(), BackendKind::Udisks2);
+}
+
+#[tokio::test]
+async fn test_userspace_backend_extracts_iso() {
+    let iso = windows_like_iso();
+    let temp_root = TempDir::new().unwrap();
+    let mounter =
+        IsoMounter::with_backend(Arc::new(UserspaceBackend::with_temp_root(temp_root.path())));
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(mounted.device.device, None);
+    assert_eq!(mounted.partitions.len(), 1);
+    let partition = &mounted.partitions[0];
+    assert_eq!(partition.fstype, "iso9660");
+    assert_eq!(partition.label.as_deref(), Some("WIN_TEST"));
+    assert_eq!(partition.details.device, None);
+    assert_eq!(partition.details.backing_file.as_deref(), Some(iso.path()));
+    let root = Path::new(&partition.mount_path);
+    assert_eq!(
+        fs::read_to_string(root.join("readme.txt")).unwrap(),
+        "hello from the image\n"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("sources/idwbinfo.txt")).unwrap(),
+        "BuildArch=amd64\n"
+    );
+
+    mounted.close().await.unwrap();
+    assert_eq!(fs::read_dir(temp_root.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_userspace_backend_rejects_non_iso() {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&[0u8; 64 * 1024]).unwrap();
+    let temp_root = TempDir::new().unwrap();
+    let mounter =
+        IsoMounter::with_backend(Arc::new(UserspaceBackend::with_temp_root(temp_root.path())));
+
+    let result = mounter
+        .mount_guarded(
+            file.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await;
+
+    assert!(result.is_err());
+    assert_eq!(fs::read_dir(temp_root.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_userspace_backend_rejects_other_fstypes() {
+    let iso = windows_like_iso();
+    let mounter = IsoMounter::with_backend(Arc::new(UserspaceBackend::new()));
+    let mount_options = MountOptions {
+        fstype: Some("udf".into()),
+        ..Default::default()
+    };
+
+    let result = mounter
+        .mount_guarded(iso.path(), &LoopOptions::default(), &mount_options)
+        .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_userspace_backend_rejects_truncated_iso() {
+    let image = build_iso(
+        "WIN_TEST",
+        &[("", "README.TXT;1", b"hello from the image\n")],
+    );
+    // The file contents are in the last sector
+    let truncated = &image[..image.len() - 2048];
+    // A root directory claiming nearly 4 GiB, more than the whole image
+    let mut corrupt = image.clone();
+    corrupt[16 * 2048 + 156 + 10..16 * 2048 + 156 + 14]
+        .copy_from_slice(&0xffff_f000u32.to_le_bytes());
+
+    for contents in [truncated, &corrupt[..]] {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents).unwrap();
+        let temp_root = TempDir::new().unwrap();
+        let mounter =
+            IsoMounter::with_backend(Arc::new(UserspaceBackend::with_temp_root(temp_root.path())));
+
+        let error = mounter
+            .mount_guarded(
+                file.path(),
+                &LoopOptions::default(),
+                &MountOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+
+        assert!(
+            format!("{error:#}").contains("Truncated ISO image"),
+            "{error:#}"
+        );
+        assert_eq!(fs::read_dir(temp_root.path()).unwrap().count(), 0);
+    }
+}
+
+/// An 8 MiB ext4 image holding `hello.txt`
+fn ext4_image() -> NamedTempFile {
+    ext_image("ext4")
+}
+
+/// An 8 MiB image of `fstype` (ext2, ext3 or ext4) holding `hello.txt`
+fn ext_image(fstype: &str) -> NamedTempFile {
+    let content = TempDir::new().unwrap();
+    fs::write(content.path().join("hello.txt"), "hello\n").unwrap();
+    let image = NamedTempFile::new().unwrap();
+    let status = Command::new(format!("mkfs.{fstype}"))
+        .args(["-q", "-F", "-d"])
+        .arg(content.path())
+        .arg(image.path())
+        .arg("8M")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    image
+}
+
+#[tokio::test]
+#[ignore = "needs root, /dev/loop-control and mkfs.ext4"]
+async fn test_loop_backend_mounts_ext4_image() {
+    let image = ext4_image();
+
+    let mount_root = TempDir::new().unwrap();
+    let mounter = IsoMounter::with_backend(Arc::new(LoopDeviceBackend::with_mount_root(
+        mount_root.path(),
+    )));
+    let mounted = mounter
+        .mount_guarded(
+            image.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let device = mounted.device.device.clone().unwrap();
+    assert_eq!(mounted.partitions[0].fstype, "ext4");
+    let details = &mounted.partitions[0].details;
+    assert_eq!(details.device.as_ref(), Some(&device));
+    assert_eq!(details.size, Some(8 << 20));
+    assert!(!details.read_only);
+    assert_eq!(
+        details.backing_file.as_deref(),
+        Some(image.path().canonicalize().unwrap().as_path())
+    );
+    assert_eq!(
+        fs::read_to_string(Path::new(mounted.mount_path().unwrap()).join("hello.txt")).unwrap(),
+        "hello\n"
+    );
+
+    mounted.close().await.unwrap();
+    let backing_file = Path::new("/sys/block")
+        .join(device.file_name().unwrap())
+        .join("loop/backing_file");
+    assert!(!backing_file.exists());
+    assert_eq!(fs::read_dir(mount_root.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+#[ignore = "needs root, /dev/loop-control, mkfs.ext2 and mkfs.ext3"]
+async fn test_loop_backend_tells_ext2_and_ext3_from_ext4() {
+    let mount_root = TempDir::new().unwrap();
+    let mounter = IsoMounter::with_backend(Arc::new(LoopDeviceBackend::with_mount_root(
+        mount_root.path(),
+    )));
+
+    for fstype in ["ext2", "ext3"] {
+        let image = ext_image(fstype);
+        let mounted = mounter
+            .mount_guarded(
+                image.path(),
+                &LoopOptions::default(),
+                &MountOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(mounted.partitions[0].fstype, fstype);
+        assert_eq!(mounted.partitions[0].details.fstype, fstype);
+        mounted.close().await.unwrap();
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs root, /dev/loop-control and mkfs.ext4"]
+async fn test_loop_backend_sets_read_only_and_partscan_flags() {
+    let image = ext4_image();
+    // Opened for writing, so only the read-only option can make the device read-only
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image.path())
+        .unwrap();
+    let backend = LoopDeviceBackend::new();
+
+    for (read_only, no_part_scan) in [(false, false), (true, true)] {
+        let options = LoopOptions {
+            read_only,
+            no_part_scan,
+            ..LoopOptions::default()
+        };
+        let device = backend.attach(image.path(), &file, &options).await.unwrap();
+        let sysfs = Path::new("/sys/block").join(device.id.trim_start_matches("/dev/"));
+        let flag = |name: &str| fs::read_to_string(sysfs.join(name)).unwrap().trim() == "1";
+
+        assert_eq!(flag("ro"), read_only, "{}", device.id);
+        assert_eq!(flag("loop/partscan"), !no_part_scan, "{}", device.id);
+        backend.detach(&device).await.unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use rust_system_tools::{
+    BusAddress, IsoMounter, Udisks2Capabilities, Udisks2ConnectionBuilder, Udisks2Error,
+    bus::SYSTEM_BUS_ADDRESS_ENV,
+};
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+};
+use tempfile::TempDir;
+use zbus::fdo::DBusProxy;
+
+/// A private `dbus-daemon` with no UDisks2 on it, stopped when dropped
+struct PrivateBus {
+    daemon: Child,
+    address: String,
+    _dir: TempDir,
+}
+
+impl PrivateBus {
+    fn start() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .arg(format!(
+                "--address=unix:path={}",
+                dir.path().join("bus").display()
+            ))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed for the bus tests");
+        // The address is printed once the daemon listens
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_string(),
+            _dir: dir,
+        }
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+#[test]
+fn test_bus_address_parsing() {
+    assert_eq!("system".parse::<BusAddress>(), Ok(BusAddress::System));
+    assert_eq!("session".parse::<BusAddress>(), Ok(BusAddress::Session));
+    assert_eq!(
+        "unix:path=/tmp/remote-bus".parse::<BusAddress>(),
+        Ok(BusAddress::Address("unix:path=/tmp/remote-bus".into()))
+    );
+    assert!("nonsense".parse::<BusAddress>().is_err());
+    assert_eq!(BusAddress::default().to_string(), "system");
+}
+
+#[tokio::test]
+async fn test_explicit_address_and_system_bus_override() {
+    let bus = PrivateBus::start();
+
+    let connection = Udisks2ConnectionBuilder::new()
+        .bus(BusAddress::Address(bus.address.clone()))
+        .build()
+        .await
+        .unwrap();
+    assert!(connection.unique_name().is_some());
+    // Connected to the private bus, which has no UDisks2
+    let error = Udisks2Capabilities::probe(&connection).await.unwrap_err();
+    assert!(matches!(
+        Udisks2Error::find(&error),
+        Some(Udisks2Error::ServiceUnavailable { .. })
+    ));
+
+    // Only this test touches the environment in this binary
+    unsafe { std::env::set_var(SYSTEM_BUS_ADDRESS_ENV, &bus.address) };
+    let system = Udisks2ConnectionBuilder::new().build().await;
+    unsafe { std::env::remove_var(SYSTEM_BUS_ADDRESS_ENV) };
+    let system = system.unwrap();
+
+    // The "system" connection is a peer of the first one on the private bus
+    let dbus = DBusProxy::new(&connection).await.unwrap();
+    let name = system.unique_name().unwrap().to_owned();
+    assert!(dbus.name_has_owner(name.into()).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_unreachable_address_names_it() {
+    let dir = tempfile::tempdir().unwrap();
+    let address = format!("unix:path={}", dir.path().join("missing").display());
+
+    let error = IsoMounter::connect(BusAddress::Address(address.clone()))
+        .await
+        .err()
+        .unwrap();
+
+    assert!(
+        error
+            .to_string()
+            .contains(&format!("Failed to connect to D-Bus at {address}")),
+        "{error:#}"
+    );
+}
@@ -0,0 +1,110 @@
+//! Builds tiny ISO 9660 images in memory for tests
+
+const SECTOR: usize = 2048;
+
+/// A directory record pointing at `extent`
+fn dir_record(name: &[u8], extent: u32, size: u32, directory: bool) -> Vec<u8> {
+    let padding = usize::from(name.len().is_multiple_of(2));
+    let mut record = vec![0u8; 33 + name.len() + padding];
+    record[0] = record.len() as u8;
+    record[2..6].copy_from_slice(&extent.to_le_bytes());
+    record[6..10].copy_from_slice(&extent.to_be_bytes());
+    record[10..14].copy_from_slice(&size.to_le_bytes());
+    record[14..18].copy_from_slice(&size.to_be_bytes());
+    record[25] = if directory { 0x02 } else { 0 };
+    record[28] = 1;
+    record[31] = 1;
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+/// A plain ISO 9660 image without Joliet or Rock Ridge
+///
+/// `files` holds `(directory, name, contents)`, where an empty directory means the root. Names are
+/// stored as given, so use the upper case `NAME.EXT;1` form.
+pub fn build_iso(volume_id: &str, files: &[(&str, &str, &[u8])]) -> Vec<u8> {
+    let mut directories: Vec<&str> = files
+        .iter()
+        .map(|(dir, _, _)| *dir)
+        .filter(|dir| !dir.is_empty())
+        .collect();
+    directories.sort();
+    directories.dedup();
+
+    // Sector 16 holds the primary descriptor, 17 the terminator, 18 the root directory, then one
+    // sector per subdirectory, then the file contents
+    let root_sector = 18u32;
+    let dir_sector = |dir: &str| -> u32 {
+        if dir.is_empty() {
+            root_sector
+        } else {
+            root_sector + 1 + directories.iter().position(|d| *d == dir).unwrap() as u32
+        }
+    };
+    let mut next_sector = root_sector + 1 + directories.len() as u32;
+    let mut file_sectors = Vec::new();
+    for (_, _, contents) in files {
+        file_sectors.push(next_sector);
+        next_sector += contents.len().div_ceil(SECTOR).max(1) as u32;
+    }
+
+    let mut image = vec![0u8; next_sector as usize * SECTOR];
+
+    let write_dir = |dir: &str, image: &mut Vec<u8>| {
+        let sector = dir_sector(dir);
+        // Only one level of directories, so the parent is always the root
+        let mut records = dir_record(&[0], sector, SECTOR as u32, true);
+        records.extend(dir_record(&[1], root_sector, SECTOR as u32, true));
+        if dir.is_empty() {
+            for sub in &directories {
+                records.extend(dir_record(
+                    sub.as_bytes(),
+                    dir_sector(sub),
+                    SECTOR as u32,
+                    true,
+                ));
+            }
+        }
+        for ((file_dir, name, contents), extent) in files.iter().zip(&file_sectors) {
+            if *file_dir == dir {
+                records.extend(dir_record(
+                    name.as_bytes(),
+                    *extent,
+                    contents.len() as u32,
+                    false,
+                ));
+            }
+        }
+        let start = sector as usize * SECTOR;
+        image[start..start + records.len()].copy_from_slice(&records);
+    };
+    write_dir("", &mut image);
+    for dir in &directories {
+        write_dir(dir, &mut image);
+    }
+
+    for ((_, _, contents), extent) in files.iter().zip(&file_sectors) {
+        let start = *extent as usize * SECTOR;
+        image[start..start + contents.len()].copy_from_slice(contents);
+    }
+
+    let primary = 16 * SECTOR;
+    image[primary] = 1;
+    image[primary + 1..primary + 6].copy_from_slice(b"CD001");
+    image[primary + 6] = 1;
+    let mut label = [b' '; 32];
+    label[..volume_id.len()].copy_from_slice(volume_id.as_bytes());
+    image[primary + 40..primary + 72].copy_from_slice(&label);
+    image[primary + 80..primary + 84].copy_from_slice(&next_sector.to_le_bytes());
+    image[primary + 128..primary + 130].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    let root = dir_record(&[0], root_sector, SECTOR as u32, true);
+    image[primary + 156..primary + 156 + root.len()].copy_from_slice(&root);
+
+    let terminator = 17 * SECTOR;
+    image[terminator] = 255;
+    image[terminator + 1..terminator + 6].copy_from_slice(b"CD001");
+    image[terminator + 6] = 1;
+
+    image
+}
@@ -0,0 +1,1379 @@
+//! In-process fake `org.freedesktop.UDisks2` service for integration tests
+//!
+//! The service runs on a private peer-to-peer connection, so neither a system bus nor root is
+//! needed. It implements the subset of UDisks2 that the crate talks to: `Manager.LoopSetup`,
+//! `Filesystem.Mount`/`Unmount`/`Check`/`Repair`/`Resize`/`SetLabel`, `Loop.Delete`/`SetAutoclear`,
+//! `Encrypted.Unlock`/`Lock`, `Block.Format`, `PartitionTable.CreatePartition`,
+//! `Drive.Eject`/`PowerOff` and the ObjectManager.
+//!
+//! Drives with partitions and unlocked LUKS containers can be added with
+//! [`MockUdisks2::add_drive`]; loop device partitions of type `crypto_LUKS` are locked containers
+//! that `Encrypted.Unlock` opens. Failures can be injected per method with
+//! [`MockUdisks2::fail_next`], and [`MockUdisks2::require_auth`] refuses a method called with
+//! `auth.no_user_interaction`, as polkit does. [`MockUdisks2::stall_mount`] holds back a
+//! `Filesystem.Mount` answer, like a polkit prompt nobody answers.
+
+#![allow(dead_code)]
+
+pub mod iso;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rust_system_tools::{FilesystemTool, Udisks2Capabilities};
+use tempfile::{NamedTempFile, TempDir};
+use zbus::{
+    Connection, ObjectServer, connection, fdo, interface,
+    object_server::SignalEmitter,
+    zvariant::{OwnedFd, OwnedObjectPath, OwnedValue},
+};
+
+pub const ROOT_PATH: &str = "/org/freedesktop/UDisks2";
+pub const MANAGER_PATH: &str = "/org/freedesktop/UDisks2/Manager";
+pub const BLOCK_DEVICES_PATH: &str = "/org/freedesktop/UDisks2/block_devices";
+pub const DRIVES_PATH: &str = "/org/freedesktop/UDisks2/drives";
+
+/// Errors the mock returns, named like the real UDisks2 errors
+#[derive(Debug, Clone, zbus::DBusError)]
+#[zbus(prefix = "org.freedesktop.UDisks2.Error")]
+pub enum MockError {
+    Failed(String),
+    NotAuthorized(String),
+    NotAuthorizedCanObtain(String),
+    DeviceBusy(String),
+    AlreadyMounted(String),
+    NotMounted(String),
+}
+
+/// A partition to expose on the next loop device
+#[derive(Debug, Clone)]
+pub struct MockPartition {
+    pub number: u32,
+    pub type_guid: String,
+    pub name: String,
+    pub fstype: String,
+    pub label: String,
+}
+
+/// A drive to export with [`MockUdisks2::add_drive`]
+#[derive(Debug, Clone, Default)]
+pub struct MockDrive {
+    /// Last element of the drive object path, e.g. `SanDisk_Cruzer_4C530001`
+    pub id: String,
+    pub vendor: String,
+    pub model: String,
+    pub serial: String,
+    pub size: u64,
+    pub connection_bus: String,
+    pub removable: bool,
+    pub ejectable: bool,
+    pub media: String,
+    /// Kernel name of the whole-disk block device, e.g. `sdb`
+    pub block_name: String,
+    /// Regular file standing in for the whole-disk device node, opened by `OpenForRestore`
+    pub device_file: Option<PathBuf>,
+    pub partitions: Vec<MockPartition>,
+    /// Mount points by partition number, `(2, "/")` makes this the system drive
+    ///
+    /// For unlocked partitions the mount points belong to the cleartext device.
+    pub mounts: Vec<(u32, String)>,
+    /// Partitions that are unlocked LUKS containers, with the filesystem type of the cleartext
+    /// device exported as `dm-<number>`
+    pub unlocked: Vec<(u32, String)>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    mount_root: PathBuf,
+    next_loop: u32,
+    /// Partition layout used by the next `LoopSetup`, empty for a whole-device filesystem
+    next_partitions: Vec<MockPartition>,
+    failures: HashMap<String, MockError>,
+    /// Object paths of filesystems that `Check` reports as inconsistent until repaired
+    damaged: HashSet<String>,
+    /// Passphrase, or keyfile contents, that `Encrypted.Unlock` accepts
+    luks_passphrase: String,
+    next_dm: u32,
+    /// `Manager.Version`, `2.10.1` when unset
+    version: Option<String>,
+    /// Never answer reads of `Manager.Version`, like a hung daemon
+    stalled: bool,
+    /// Object paths whose next `Filesystem.Mount` fails after a delay, like a polkit prompt
+    /// that is left to time out
+    stalled_mounts: HashMap<String, Duration>,
+    /// Return a different path from `Mount` than the one put into `MountPoints`
+    misreport_mounts: bool,
+    /// Every call the service received, as `Interface.Method object_path`
+    calls: Vec<String>,
+    /// Calls that came with `auth.no_user_interaction`, in the same format
+    non_interactive_calls: Vec<String>,
+    /// Methods that polkit refuses without a prompt, like actions that need a password
+    requires_auth: HashSet<String>,
+}
+
+impl MockState {
+    fn record(&mut self, method: &str, path: &str) -> Result<(), MockError> {
+        self.calls.push(format!("{method} {path}"));
+        match self.failures.remove(method) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Record a call whose authorization depends on `auth.no_user_interaction`
+    ///
+    /// Interactive calls are always allowed, as if the user typed the password into an agent.
+    fn record_auth(
+        &mut self,
+        method: &str,
+        path: &str,
+        options: &HashMap<String, OwnedValue>,
+    ) -> Result<(), MockError> {
+        self.record(method, path)?;
+        let no_user_interaction = options
+            .get("auth.no_user_interaction")
+            .and_then(|v| bool::try_from(v).ok())
+            .unwrap_or(false);
+        if !no_user_interaction {
+            return Ok(());
+        }
+        self.non_interactive_calls.push(format!("{method} {path}"));
+        if self.requires_auth.contains(method) {
+            return Err(MockError::NotAuthorizedCanObtain(format!(
+                "Not authorized to perform {method} without user interaction"
+            )));
+        }
+        Ok(())
+    }
+}
+
+type SharedState = Arc<Mutex<MockState>>;
+
+/// A running mock UDisks2 service and a client connection to it
+pub struct MockUdisks2 {
+    client: Connection,
+    /// Serves the mock objects, kept alive so the service keeps answering
+    server: Connection,
+    state: SharedState,
+    mount_root: TempDir,
+}
+
+impl MockUdisks2 {
+    pub async fn start() -> anyhow::Result<Self> {
+        let mount_root = tempfile::tempdir()?;
+        let state = Arc::new(Mutex::new(MockState {
+            mount_root: mount_root.path().to_path_buf(),
+            ..Default::default()
+        }));
+
+        let (server_socket, client_socket) = tokio::net::UnixStream::pair()?;
+        let server = connection::Builder::unix_stream(server_socket)
+            .server(zbus::Guid::generate())?
+            .p2p()
+            .serve_at(ROOT_PATH, fdo::ObjectManager)?
+            .serve_at(
+                MANAGER_PATH,
+                MockManager {
+                    state: state.clone(),
+                },
+            )?
+            .build();
+        let client = connection::Builder::unix_stream(client_socket)
+            .p2p()
+            .build();
+        let (server, client) = tokio::try_join!(server, client)?;
+
+        Ok(Self {
+            client,
+            server,
+            state,
+            mount_root,
+        })
+    }
+
+    /// Client side of the private connection, to hand to `IsoMounter::new`
+    pub fn connection(&self) -> &Connection {
+        &self.client
+    }
+
+    /// Make the next call to `method` (e.g. `"Filesystem.Mount"`) fail with `error`
+    pub fn fail_next(&self, method: &str, error: MockError) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .insert(method.to_string(), error);
+    }
+
+    /// Report `version` as `Manager.Version`
+    pub fn set_version(&self, version: &str) {
+        self.state.lock().unwrap().version = Some(version.to_string());
+    }
+
+    /// Refuse `method` with `auth.no_user_interaction`, as polkit does for actions needing a
+    /// password
+    pub fn require_auth(&self, method: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .requires_auth
+            .insert(method.to_string());
+    }
+
+    /// Make `Mount` return a path that is not in `MountPoints`
+    pub fn set_misreport_mounts(&self, misreport: bool) {
+        self.state.lock().unwrap().misreport_mounts = misreport;
+    }
+
+    /// Stop answering reads of `Manager.Version`
+    pub fn set_stalled(&self, stalled: bool) {
+        self.state.lock().unwrap().stalled = stalled;
+    }
+
+    /// Make the next `Filesystem.Mount` of `object_path` fail, but only after `delay`
+    pub fn stall_mount(&self, object_path: &str, delay: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .stalled_mounts
+            .insert(object_path.to_string(), delay);
+    }
+
+    /// Make `Encrypted.Unlock` accept `passphrase`, or a keyfile with exactly these bytes
+    pub fn set_luks_passphrase(&self, passphrase: &str) {
+        self.state.lock().unwrap().luks_passphrase = passphrase.to_string();
+    }
+
+    /// Make `Filesystem.Check` on `object_path` report errors until it is repaired
+    pub fn set_damaged(&self, object_path: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .damaged
+            .insert(object_path.to_string());
+    }
+
+    /// Expose `partitions` instead of a whole-device filesystem on the next loop device
+    pub fn set_next_partitions(&self, partitions: Vec<MockPartition>) {
+        self.state.lock().unwrap().next_partitions = partitions;
+    }
+
+    /// Export a drive with its whole-disk block device and partitions, returning the drive path
+    pub async fn add_drive(&self, drive: MockDrive) -> anyhow::Result<String> {
+        let server = self.server.object_server();
+        let drive_path = format!("{DRIVES_PATH}/{}", drive.id);
+        let disk_path = format!("{BLOCK_DEVICES_PATH}/{}", drive.block_name);
+        let block = |name: &str, size, id_type: &str, id_label: &str| MockBlock {
+            state: self.state.clone(),
+            device: format!("/dev/{name}"),
+            size,
+            read_only: false,
+            id_type: id_type.into(),
+            id_label: id_label.into(),
+            drive: object_path(&drive_path),
+            crypto_backing_device: object_path("/"),
+            hint_system: !drive.removable,
+        };
+        let partition_paths: Vec<OwnedObjectPath> = drive
+            .partitions
+            .iter()
+            .map(|p| object_path(&format!("{disk_path}{}", p.number)))
+            .collect();
+
+        server
+            .at(
+                drive_path.as_str(),
+                MockDriveObject {
+                    state: self.state.clone(),
+                    drive: drive.clone(),
+                },
+            )
+            .await?;
+        server
+            .at(
+                disk_path.as_str(),
+                MockBlock {
+                    device: drive.device_file.as_ref().map_or_else(
+                        || format!("/dev/{}", drive.block_name),
+                        |file| file.to_string_lossy().into_owned(),
+                    ),
+                    ..block(&drive.block_name, drive.size, "", "")
+                },
+            )
+            .await?;
+        server
+            .at(
+                disk_path.as_str(),
+                MockPartitionTable {
+                    state: self.state.clone(),
+                    partitions: partition_paths,
+                    drive: object_path(&drive_path),
+                },
+            )
+            .await?;
+
+        for partition in &drive.partitions {
+            let part_name = format!("{}{}", drive.block_name, partition.number);
+            let part_path = format!("{BLOCK_DEVICES_PATH}/{part_name}");
+            server
+                .at(
+                    part_path.as_str(),
+                    block(&part_name, 0, &partition.fstype, &partition.label),
+                )
+                .await?;
+            server
+                .at(
+                    part_path.as_str(),
+                    MockPartitionObject {
+                        number: partition.number,
+                        type_guid: partition.type_guid.clone(),
+                        name: partition.name.clone(),
+                        table: object_path(&disk_path),
+                    },
+                )
+                .await?;
+            let mount_points = drive
+                .mounts
+                .iter()
+                .filter(|(number, _)| *number == partition.number)
+                .map(|(_, mount_point)| bytestring(mount_point))
+                .collect();
+
+            let Some((_, cleartext_fstype)) = drive
+                .unlocked
+                .iter()
+                .find(|(number, _)| *number == partition.number)
+            else {
+                let mut filesystem = MockFilesystem::new(self.state.clone(), &part_name);
+                filesystem.mount_points = mount_points;
+                server.at(part_path.as_str(), filesystem).await?;
+                continue;
+            };
+
+            let cleartext_name = format!("dm-{}", partition.number);
+            let cleartext_path = format!("{BLOCK_DEVICES_PATH}/dm_2d{}", partition.number);
+            server
+                .at(
+                    part_path.as_str(),
+                    MockEncrypted {
+                        state: self.state.clone(),
+                        cleartext: object_path(&cleartext_path),
+                        cleartext_fstype: cleartext_fstype.clone(),
+                    },
+                )
+                .await?;
+            server
+                .at(
+                    cleartext_path.as_str(),
+                    MockBlock {
+                        drive: object_path("/"),
+                        crypto_backing_device: object_path(&part_path),
+                        hint_system: false,
+                        ..block(&cleartext_name, 0, cleartext_fstype, "")
+                    },
+                )
+                .await?;
+            let mut filesystem = MockFilesystem::new(self.state.clone(), &cleartext_name);
+            filesystem.mount_points = mount_points;
+            server.at(cleartext_path.as_str(), filesystem).await?;
+        }
+        Ok(drive_path)
+    }
+
+    /// Calls received so far, as `Interface.Method object_path`
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Calls that asked polkit not to prompt, as `Interface.Method object_path`
+    pub fn non_interactive_calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().non_interactive_calls.clone()
+    }
+
+    /// Directory under which mount points are created
+    pub fn mount_root(&self) -> &std::path::Path {
+        self.mount_root.path()
+    }
+
+    /// Object paths currently exported below `/org/freedesktop/UDisks2/block_devices`
+    pub async fn block_devices(&self) -> Vec<String> {
+        let object_manager = fdo::ObjectManagerProxy::builder(&self.client)
+            .destination("org.freedesktop.UDisks2")
+            .unwrap()
+            .path(ROOT_PATH)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let mut paths: Vec<String> = object_manager
+            .get_managed_objects()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|(_, interfaces)| !interfaces.is_empty())
+            .map(|(path, _)| path.to_string())
+            .filter(|path| path.starts_with(&format!("{BLOCK_DEVICES_PATH}/")))
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// A Linux filesystem partition to hand to [`MockUdisks2::set_next_partitions`] or a drive
+pub fn partition(number: u32, fstype: &str, label: &str) -> MockPartition {
+    MockPartition {
+        number,
+        type_guid: "0fc63daf-8483-4772-8e79-3d69d8477de4".into(),
+        name: String::new(),
+        fstype: fstype.into(),
+        label: label.into(),
+    }
+}
+
+/// A small file standing in for an ISO image
+pub fn fake_iso() -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&[0u8; 4096]).unwrap();
+    file
+}
+
+/// A VM seed image; its content does not matter to the mock
+pub fn seed_image() -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    file.as_file().set_len(1 << 20).unwrap();
+    file
+}
+
+/// A [`FilesystemTool`] for the mock, with the capabilities it reports
+pub async fn filesystem_tool(mock: &MockUdisks2) -> FilesystemTool {
+    let capabilities = Udisks2Capabilities::probe(mock.connection()).await.unwrap();
+    FilesystemTool::new(mock.connection(), capabilities)
+        .await
+        .unwrap()
+}
+
+struct MockManager {
+    state: SharedState,
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Manager")]
+impl MockManager {
+    async fn loop_setup(
+        &self,
+        fd: OwnedFd,
+        options: HashMap<String, OwnedValue>,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> Result<OwnedObjectPath, MockError> {
+        let (name, partitions) = {
+            let mut state = self.state.lock().unwrap();
+            state.record_auth("Manager.LoopSetup", MANAGER_PATH, &options)?;
+            let name = format!("loop{}", state.next_loop);
+            state.next_loop += 1;
+            (name, std::mem::take(&mut state.next_partitions))
+        };
+
+        let backing_file = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+            .map_err(|e| MockError::Failed(format!("Cannot resolve backing file: {e}")))?;
+        let size = fs::metadata(&backing_file).map(|m| m.len()).unwrap_or(0);
+        // Like udisksd, a read-only fd gives a read-only device whatever the options say
+        let read_only = options
+            .get("read-only")
+            .and_then(|v| bool::try_from(v).ok())
+            .unwrap_or(false)
+            || fd_is_read_only(&fd);
+
+        let loop_path = format!("{BLOCK_DEVICES_PATH}/{name}");
+        let whole_device_fs = partitions.is_empty();
+        let partition_paths: Vec<OwnedObjectPath> = partitions
+            .iter()
+            .map(|p| object_path(&format!("{loop_path}p{}", p.number)))
+            .collect();
+
+        let add = |result: zbus::Result<bool>| {
+            result.map_err(|e| MockError::Failed(format!("Failed to export object: {e}")))
+        };
+
+        add(server
+            .at(
+                loop_path.as_str(),
+                MockBlock {
+                    state: self.state.clone(),
+                    device: format!("/dev/{name}"),
+                    size,
+                    read_only,
+                    id_type: if whole_device_fs { "iso9660" } else { "" }.into(),
+                    id_label: if whole_device_fs { "MOCK_ISO" } else { "" }.into(),
+                    drive: object_path("/"),
+                    crypto_backing_device: object_path("/"),
+                    hint_system: false,
+                },
+            )
+            .await)?;
+        add(server
+            .at(
+                loop_path.as_str(),
+                MockLoop {
+                    state: self.state.clone(),
+                    backing_file: backing_file.clone(),
+                    autoclear: false,
+                    partitions: partition_paths.clone(),
+                },
+            )
+            .await)?;
+        if whole_device_fs {
+            add(server
+                .at(
+                    loop_path.as_str(),
+                    MockFilesystem::new(self.state.clone(), &name),
+                )
+                .await)?;
+        } else {
+            add(server
+                .at(
+                    loop_path.as_str(),
+                    MockPartitionTable {
+                        state: self.state.clone(),
+                        partitions: partition_paths.clone(),
+                        drive: object_path("/"),
+                    },
+                )
+                .await)?;
+        }
+
+        for partition in partitions {
+            let part_name = format!("{name}p{}", partition.number);
+            let part_path = format!("{BLOCK_DEVICES_PATH}/{part_name}");
+            add(server
+                .at(
+                    part_path.as_str(),
+                    MockBlock {
+                        state: self.state.clone(),
+                        device: format!("/dev/{part_name}"),
+                        size: 0,
+                        read_only,
+                        id_type: partition.fstype.clone(),
+                        id_label: partition.label.clone(),
+                        drive: object_path("/"),
+                        crypto_backing_device: object_path("/"),
+                        hint_system: false,
+                    },
+                )
+                .await)?;
+            add(server
+                .at(
+                    part_path.as_str(),
+                    MockPartitionObject {
+                        number: partition.number,
+                        type_guid: partition.type_guid.clone(),
+                        name: partition.name.clone(),
+                        table: object_path(&loop_path),
+                    },
+                )
+                .await)?;
+            if partition.fstype == "crypto_LUKS" {
+                add(server
+                    .at(
+                        part_path.as_str(),
+                        MockEncrypted {
+                            state: self.state.clone(),
+                            cleartext: object_path("/"),
+                            cleartext_fstype: "ext4".into(),
+                        },
+                    )
+                    .await)?;
+            } else {
+                add(server
+                    .at(
+                        part_path.as_str(),
+                        MockFilesystem::new(self.state.clone(), &part_name),
+                    )
+                    .await)?;
+            }
+        }
+
+        Ok(object_path(&loop_path))
+    }
+
+    #[zbus(property)]
+    async fn version(&self) -> String {
+        let (version, stalled) = {
+            let state = self.state.lock().unwrap();
+            (state.version.clone(), state.stalled)
+        };
+        if stalled {
+            std::future::pending::<()>().await;
+        }
+        version.unwrap_or_else(|| "2.10.1".into())
+    }
+
+    #[zbus(property)]
+    fn supported_filesystems(&self) -> Vec<String> {
+        ["ext4", "vfat", "ntfs", "exfat", "udf", "iso9660"]
+            .map(String::from)
+            .to_vec()
+    }
+}
+
+struct MockBlock {
+    state: SharedState,
+    device: String,
+    size: u64,
+    read_only: bool,
+    id_type: String,
+    id_label: String,
+    /// `/` for devices without a drive, like loop devices and cleartext devices
+    drive: OwnedObjectPath,
+    /// LUKS container of a cleartext device, `/` otherwise
+    crypto_backing_device: OwnedObjectPath,
+    hint_system: bool,
+}
+
+impl MockBlock {
+    /// Open the device node, which only works when it is a real file
+    fn open(&self, method: &str, path: &str, write: bool) -> Result<OwnedFd, MockError> {
+        self.state.lock().unwrap().record(method, path)?;
+        let file = fs::OpenOptions::new()
+            .read(!write)
+            .write(write)
+            .open(&self.device)
+            .map_err(|e| MockError::Failed(format!("Cannot open {}: {e}", self.device)))?;
+        Ok(std::os::fd::OwnedFd::from(file).into())
+    }
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Block")]
+impl MockBlock {
+    /// Replace the content with a filesystem, or with an empty `gpt` or `dos` partition table
+    async fn format(
+        &mut self,
+        fstype: String,
+        options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), MockError> {
+        let path = header.path().expect("method call without path").to_string();
+        self.state.lock().unwrap().record("Block.Format", &path)?;
+
+        if let Ok(table) = server
+            .interface::<_, MockPartitionTable>(path.as_str())
+            .await
+        {
+            let partitions = table.get().await.partitions.clone();
+            for partition in partitions {
+                remove_block_object(server, partition.as_str()).await;
+            }
+            let _ = server.remove::<MockPartitionTable, _>(path.as_str()).await;
+        }
+        let _ = server.remove::<MockFilesystem, _>(path.as_str()).await;
+
+        let add = |result: zbus::Result<bool>| {
+            result.map_err(|e| MockError::Failed(format!("Failed to export object: {e}")))
+        };
+        if fstype == "gpt" || fstype == "dos" {
+            add(server
+                .at(
+                    path.as_str(),
+                    MockPartitionTable {
+                        state: self.state.clone(),
+                        partitions: Vec::new(),
+                        drive: self.drive.clone(),
+                    },
+                )
+                .await)?;
+            self.id_type = String::new();
+            self.id_label = String::new();
+        } else {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            add(server
+                .at(path.as_str(), MockFilesystem::new(self.state.clone(), name))
+                .await)?;
+            self.id_type = fstype;
+            self.id_label = options
+                .get("label")
+                .and_then(|v| String::try_from(v.try_clone().ok()?).ok())
+                .unwrap_or_default();
+        }
+        let _ = self.id_type_changed(&emitter).await;
+        let _ = self.id_label_changed(&emitter).await;
+        Ok(())
+    }
+
+    fn open_for_restore(
+        &self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+    ) -> Result<OwnedFd, MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.open("Block.OpenForRestore", &path, true)
+    }
+
+    fn open_for_backup(
+        &self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+    ) -> Result<OwnedFd, MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.open("Block.OpenForBackup", &path, false)
+    }
+
+    #[zbus(property)]
+    fn device(&self) -> Vec<u8> {
+        bytestring(&self.device)
+    }
+
+    #[zbus(property)]
+    fn preferred_device(&self) -> Vec<u8> {
+        bytestring(&self.device)
+    }
+
+    #[zbus(property)]
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[zbus(property)]
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    #[zbus(property)]
+    fn id_usage(&self) -> String {
+        if self.id_type.is_empty() {
+            String::new()
+        } else {
+            "filesystem".into()
+        }
+    }
+
+    #[zbus(property)]
+    fn id_type(&self) -> String {
+        self.id_type.clone()
+    }
+
+    #[zbus(property)]
+    fn id_label(&self) -> String {
+        self.id_label.clone()
+    }
+
+    #[zbus(property, name = "IdUUID")]
+    fn id_uuid(&self) -> String {
+        if self.id_type.is_empty() {
+            return String::new();
+        }
+        format!("mock-uuid-{}", self.device.trim_start_matches("/dev/"))
+    }
+
+    #[zbus(property)]
+    fn drive(&self) -> OwnedObjectPath {
+        self.drive.clone()
+    }
+
+    #[zbus(property)]
+    fn crypto_backing_device(&self) -> OwnedObjectPath {
+        self.crypto_backing_device.clone()
+    }
+
+    #[zbus(property)]
+    fn hint_system(&self) -> bool {
+        self.hint_system
+    }
+}
+
+struct MockDriveObject {
+    state: SharedState,
+    drive: MockDrive,
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Drive")]
+impl MockDriveObject {
+    fn eject(
+        &self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+    ) -> Result<(), MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state.lock().unwrap().record("Drive.Eject", &path)
+    }
+
+    fn power_off(
+        &self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+    ) -> Result<(), MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state.lock().unwrap().record("Drive.PowerOff", &path)
+    }
+
+    #[zbus(property)]
+    fn vendor(&self) -> String {
+        self.drive.vendor.clone()
+    }
+
+    #[zbus(property)]
+    fn model(&self) -> String {
+        self.drive.model.clone()
+    }
+
+    #[zbus(property)]
+    fn serial(&self) -> String {
+        self.drive.serial.clone()
+    }
+
+    #[zbus(property)]
+    fn size(&self) -> u64 {
+        self.drive.size
+    }
+
+    #[zbus(property)]
+    fn connection_bus(&self) -> String {
+        self.drive.connection_bus.clone()
+    }
+
+    #[zbus(property)]
+    fn removable(&self) -> bool {
+        self.drive.removable
+    }
+
+    #[zbus(property)]
+    fn media_removable(&self) -> bool {
+        self.drive.removable
+    }
+
+    #[zbus(property)]
+    fn ejectable(&self) -> bool {
+        self.drive.ejectable
+    }
+
+    #[zbus(property)]
+    fn can_power_off(&self) -> bool {
+        self.drive.connection_bus == "usb"
+    }
+
+    #[zbus(property)]
+    fn media(&self) -> String {
+        self.drive.media.clone()
+    }
+
+    #[zbus(property)]
+    fn sort_key(&self) -> String {
+        format!("00coldplug/{}", self.drive.block_name)
+    }
+}
+
+struct MockLoop {
+    state: SharedState,
+    backing_file: PathBuf,
+    autoclear: bool,
+    partitions: Vec<OwnedObjectPath>,
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Loop")]
+impl MockLoop {
+    async fn delete(
+        &self,
+        options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> Result<(), MockError> {
+        let path = header.path().expect("method call without path").to_owned();
+        self.state
+            .lock()
+            .unwrap()
+            .record_auth("Loop.Delete", path.as_str(), &options)?;
+
+        // The cleartext device keeps the loop device open until the container is locked
+        for block in self
+            .partitions
+            .iter()
+            .map(|p| p.as_str())
+            .chain([path.as_str()])
+        {
+            if let Ok(encrypted) = server.interface::<_, MockEncrypted>(block).await
+                && encrypted.get().await.cleartext.as_str() != "/"
+            {
+                return Err(MockError::DeviceBusy(format!(
+                    "{block} is unlocked, lock it first"
+                )));
+            }
+        }
+
+        for partition in &self.partitions {
+            remove_block_object(server, partition.as_str()).await;
+        }
+        remove_block_object(server, path.as_str()).await;
+        Ok(())
+    }
+
+    async fn set_autoclear(
+        &mut self,
+        value: bool,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state
+            .lock()
+            .unwrap()
+            .record("Loop.SetAutoclear", &path)?;
+        self.autoclear = value;
+        let _ = self.autoclear_changed(&emitter).await;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn backing_file(&self) -> Vec<u8> {
+        bytestring(&self.backing_file.to_string_lossy())
+    }
+
+    #[zbus(property)]
+    fn autoclear(&self) -> bool {
+        self.autoclear
+    }
+
+    #[zbus(property, name = "SetupByUID")]
+    fn setup_by_uid(&self) -> u32 {
+        nix::unistd::getuid().as_raw()
+    }
+}
+
+struct MockPartitionTable {
+    state: SharedState,
+    partitions: Vec<OwnedObjectPath>,
+    /// Drive of the block device the table is on, inherited by new partitions
+    drive: OwnedObjectPath,
+}
+
+#[interface(name = "org.freedesktop.UDisks2.PartitionTable")]
+impl MockPartitionTable {
+    #[allow(clippy::too_many_arguments)]
+    async fn create_partition(
+        &mut self,
+        _offset: u64,
+        size: u64,
+        partition_type: String,
+        name: String,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<OwnedObjectPath, MockError> {
+        let table_path = header.path().expect("method call without path").to_string();
+        self.state
+            .lock()
+            .unwrap()
+            .record("PartitionTable.CreatePartition", &table_path)?;
+
+        let number = self.partitions.len() as u32 + 1;
+        let table_name = table_path.rsplit('/').next().unwrap_or_default();
+        // Like the kernel: sdb1, but loop0p1 and nvme0n1p1
+        let separator = if table_name.ends_with(|c: char| c.is_ascii_digit()) {
+            "p"
+        } else {
+            ""
+        };
+        let part_name = format!("{table_name}{separator}{number}");
+        let part_path = format!("{BLOCK_DEVICES_PATH}/{part_name}");
+        let add = |result: zbus::Result<bool>| {
+            result.map_err(|e| MockError::Failed(format!("Failed to export object: {e}")))
+        };
+        add(server
+            .at(
+                part_path.as_str(),
+                MockBlock {
+                    state: self.state.clone(),
+                    device: format!("/dev/{part_name}"),
+                    size,
+                    read_only: false,
+                    id_type: String::new(),
+                    id_label: String::new(),
+                    drive: self.drive.clone(),
+                    crypto_backing_device: object_path("/"),
+                    hint_system: false,
+                },
+            )
+            .await)?;
+        add(server
+            .at(
+                part_path.as_str(),
+                MockPartitionObject {
+                    number,
+                    type_guid: partition_type,
+                    name,
+                    table: object_path(&table_path),
+                },
+            )
+            .await)?;
+
+        self.partitions.push(object_path(&part_path));
+        let _ = self.partitions_changed(&emitter).await;
+        Ok(object_path(&part_path))
+    }
+
+    #[zbus(property)]
+    fn partitions(&self) -> Vec<OwnedObjectPath> {
+        self.partitions.clone()
+    }
+
+    #[zbus(property, name = "Type")]
+    fn table_type(&self) -> String {
+        "gpt".into()
+    }
+}
+
+struct MockPartitionObject {
+    number: u32,
+    type_guid: String,
+    name: String,
+    table: OwnedObjectPath,
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Partition")]
+impl MockPartitionObject {
+    #[zbus(property)]
+    fn number(&self) -> u32 {
+        self.number
+    }
+
+    #[zbus(property, name = "Type")]
+    fn partition_type(&self) -> String {
+        self.type_guid.clone()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[zbus(property)]
+    fn table(&self) -> OwnedObjectPath {
+        self.table.clone()
+    }
+}
+
+struct MockEncrypted {
+    state: SharedState,
+    /// Cleartext device while unlocked, `/` once locked
+    cleartext: OwnedObjectPath,
+    /// Filesystem on the cleartext device
+    cleartext_fstype: String,
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Encrypted")]
+impl MockEncrypted {
+    /// Export a cleartext device `dm-N` with a filesystem, if the key matches
+    async fn unlock(
+        &mut self,
+        passphrase: String,
+        options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<OwnedObjectPath, MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        let name = {
+            let mut state = self.state.lock().unwrap();
+            state.record("Encrypted.Unlock", &path)?;
+            if self.cleartext.as_str() != "/" {
+                return Err(MockError::Failed(format!(
+                    "Device {path} is already unlocked"
+                )));
+            }
+            let keyfile = options
+                .get("keyfile_contents")
+                .and_then(|v| Vec::<u8>::try_from(v.try_clone().ok()?).ok());
+            let accepted = match keyfile {
+                Some(contents) => contents == state.luks_passphrase.as_bytes(),
+                None => passphrase == state.luks_passphrase,
+            };
+            if !accepted {
+                return Err(MockError::Failed(format!(
+                    "Error unlocking {path}: Failed to activate device: Operation not permitted"
+                )));
+            }
+            let name = format!("dm-{}", state.next_dm);
+            state.next_dm += 1;
+            name
+        };
+
+        let read_only = match server.interface::<_, MockBlock>(path.as_str()).await {
+            Ok(block) => block.get().await.read_only,
+            Err(_) => false,
+        };
+        let cleartext_path = format!("{BLOCK_DEVICES_PATH}/{}", name.replace('-', "_2d"));
+        let add = |result: zbus::Result<bool>| {
+            result.map_err(|e| MockError::Failed(format!("Failed to export object: {e}")))
+        };
+        add(server
+            .at(
+                cleartext_path.as_str(),
+                MockBlock {
+                    state: self.state.clone(),
+                    device: format!("/dev/{name}"),
+                    size: 0,
+                    read_only,
+                    id_type: self.cleartext_fstype.clone(),
+                    id_label: String::new(),
+                    drive: object_path("/"),
+                    crypto_backing_device: object_path(&path),
+                    hint_system: false,
+                },
+            )
+            .await)?;
+        add(server
+            .at(
+                cleartext_path.as_str(),
+                MockFilesystem::new(self.state.clone(), &name),
+            )
+            .await)?;
+
+        self.cleartext = object_path(&cleartext_path);
+        let _ = self.cleartext_device_changed(&emitter).await;
+        Ok(self.cleartext.clone())
+    }
+
+    /// Remove the cleartext device, refusing while its filesystem is mounted
+    async fn lock(
+        &mut self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state.lock().unwrap().record("Encrypted.Lock", &path)?;
+        if self.cleartext.as_str() == "/" {
+            return Err(MockError::Failed("Device is not unlocked".into()));
+        }
+        if let Ok(filesystem) = server
+            .interface::<_, MockFilesystem>(self.cleartext.as_str())
+            .await
+            && !filesystem.get().await.mount_points.is_empty()
+        {
+            return Err(MockError::DeviceBusy(
+                "Cleartext device is still mounted".into(),
+            ));
+        }
+
+        remove_block_object(server, self.cleartext.as_str()).await;
+        self.cleartext = object_path("/");
+        let _ = self.cleartext_device_changed(&emitter).await;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn cleartext_device(&self) -> OwnedObjectPath {
+        self.cleartext.clone()
+    }
+
+    #[zbus(property)]
+    fn hint_encryption_type(&self) -> String {
+        "luks2".into()
+    }
+}
+
+struct MockFilesystem {
+    state: SharedState,
+    name: String,
+    mount_points: Vec<Vec<u8>>,
+    size: u64,
+}
+
+impl MockFilesystem {
+    fn new(state: SharedState, name: &str) -> Self {
+        Self {
+            state,
+            name: name.to_string(),
+            mount_points: Vec::new(),
+            size: 0,
+        }
+    }
+
+    /// Refuse to modify a filesystem on a read-only block device, like the real tools do
+    async fn require_writable(server: &ObjectServer, path: &str) -> Result<(), MockError> {
+        match server.interface::<_, MockBlock>(path).await {
+            Ok(block) if block.get().await.read_only => {
+                Err(MockError::Failed("Device is read-only".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[interface(name = "org.freedesktop.UDisks2.Filesystem")]
+impl MockFilesystem {
+    async fn mount(
+        &mut self,
+        options: HashMap<String, OwnedValue>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<String, MockError> {
+        let (mount_path, misreport, stalled) = {
+            let mut state = self.state.lock().unwrap();
+            state.record_auth("Filesystem.Mount", emitter.path().as_str(), &options)?;
+            (
+                state.mount_root.join(&self.name),
+                state.misreport_mounts,
+                state.stalled_mounts.remove(emitter.path().as_str()),
+            )
+        };
+        if let Some(delay) = stalled {
+            tokio::time::sleep(delay).await;
+            return Err(MockError::NotAuthorized("Authentication timed out".into()));
+        }
+        if !self.mount_points.is_empty() {
+            return Err(MockError::AlreadyMounted(format!(
+                "Device is already mounted at {}",
+                mount_path.display()
+            )));
+        }
+
+        fs::create_dir_all(&mount_path)
+            .map_err(|e| MockError::Failed(format!("Cannot create mount point: {e}")))?;
+        let mount_path = mount_path.to_string_lossy().into_owned();
+        self.mount_points = vec![bytestring(&mount_path)];
+        let _ = self.mount_points_changed(&emitter).await;
+        if misreport {
+            return Ok(format!("{mount_path}-elsewhere"));
+        }
+        Ok(mount_path)
+    }
+
+    async fn unmount(
+        &mut self,
+        options: HashMap<String, OwnedValue>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), MockError> {
+        self.state.lock().unwrap().record_auth(
+            "Filesystem.Unmount",
+            emitter.path().as_str(),
+            &options,
+        )?;
+        if self.mount_points.is_empty() {
+            return Err(MockError::NotMounted("Device is not mounted".into()));
+        }
+        self.mount_points.clear();
+        let _ = self.mount_points_changed(&emitter).await;
+        Ok(())
+    }
+
+    fn check(
+        &self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+    ) -> Result<bool, MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        state.record("Filesystem.Check", &path)?;
+        Ok(!state.damaged.contains(&path))
+    }
+
+    async fn repair(
+        &self,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> Result<bool, MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state
+            .lock()
+            .unwrap()
+            .record("Filesystem.Repair", &path)?;
+        Self::require_writable(server, &path).await?;
+        self.state.lock().unwrap().damaged.remove(&path);
+        Ok(true)
+    }
+
+    async fn resize(
+        &mut self,
+        size: u64,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state
+            .lock()
+            .unwrap()
+            .record("Filesystem.Resize", &path)?;
+        Self::require_writable(server, &path).await?;
+        self.size = match (size, server.interface::<_, MockBlock>(path.as_str()).await) {
+            (0, Ok(block)) => block.get().await.size,
+            _ => size,
+        };
+        let _ = self.size_changed(&emitter).await;
+        Ok(())
+    }
+
+    async fn set_label(
+        &self,
+        label: String,
+        _options: HashMap<String, OwnedValue>,
+        #[zbus(header)] header: zbus::message::Header<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> Result<(), MockError> {
+        let path = header.path().map(|p| p.to_string()).unwrap_or_default();
+        self.state
+            .lock()
+            .unwrap()
+            .record("Filesystem.SetLabel", &path)?;
+        Self::require_writable(server, &path).await?;
+        let block = server
+            .interface::<_, MockBlock>(path.as_str())
+            .await
+            .map_err(|e| MockError::Failed(format!("No block device at {path}: {e}")))?;
+        block.get_mut().await.id_label = label;
+        let emitter = block.signal_emitter();
+        let _ = block.get().await.id_label_changed(emitter).await;
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn mount_points(&self) -> Vec<Vec<u8>> {
+        self.mount_points.clone()
+    }
+
+    #[zbus(property)]
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+async fn remove_block_object(server: &ObjectServer, path: &str) {
+    let _ = server.remove::<MockFilesystem, _>(path).await;
+    let _ = server.remove::<MockEncrypted, _>(path).await;
+    let _ = server.remove::<MockPartitionObject, _>(path).await;
+    let _ = server.remove::<MockPartitionTable, _>(path).await;
+    let _ = server.remove::<MockLoop, _>(path).await;
+    let _ = server.remove::<MockBlock, _>(path).await;
+}
+
+/// Encode a path as the NUL terminated `ay` UDisks2 uses for paths
+fn bytestring(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn object_path(path: &str) -> OwnedObjectPath {
+    OwnedObjectPath::try_from(path.to_string()).expect("valid object path")
+}
+
+/// Whether `fd` was opened `O_RDONLY`, from the octal `flags` line of `/proc/self/fdinfo`
+fn fd_is_read_only(fd: &OwnedFd) -> bool {
+    fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))
+        .ok()
+        .and_then(|info| {
+            let flags = info.lines().find_map(|l| l.strip_prefix("flags:"))?;
+            u32::from_str_radix(flags.trim(), 8).ok()
+        })
+        .is_some_and(|flags| flags & 0o3 == 0)
+}
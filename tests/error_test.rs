@@ -0,0 +1,107 @@
+mod common;
+
+use common::{MockError, MockUdisks2, fake_iso};
+use rust_system_tools::{IsoMounter, LoopOptions, MountOptions, Udisks2Error};
+use std::collections::HashSet;
+
+/// Mount a fake ISO with `method` failing once with `error`, returning the classified error
+async fn mount_failing(method: &str, error: MockError) -> (anyhow::Error, Option<Udisks2Error>) {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.fail_next(method, error);
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let error = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+    let classified = Udisks2Error::find(&error);
+    (error, classified)
+}
+
+#[tokio::test]
+async fn test_not_authorized_is_found_through_context() {
+    let (error, classified) = mount_failing(
+        "Filesystem.Mount",
+        MockError::NotAuthorizedCanObtain("Authentication is required".into()),
+    )
+    .await;
+
+    let classified = classified.unwrap();
+    assert_eq!(
+        classified,
+        Udisks2Error::NotAuthorized {
+            message: "Authentication is required".into(),
+            can_obtain: true,
+        }
+    );
+    assert!(classified.hint().contains("pkttyagent"));
+    assert_eq!(classified.exit_code(), 10);
+    // The anyhow context is kept for the log
+    assert!(format!("{error:#}").contains("Authentication is required"));
+}
+
+#[tokio::test]
+async fn test_device_busy_and_already_mounted_are_classified() {
+    let (_, busy) = mount_failing(
+        "Manager.LoopSetup",
+        MockError::DeviceBusy("Device is busy".into()),
+    )
+    .await;
+    assert!(matches!(busy, Some(Udisks2Error::DeviceBusy { .. })));
+    // The same error comes from fsck, flash and safely-remove, which have no --retry
+    assert!(!busy.unwrap().hint().contains("--retry"));
+
+    let (_, mounted) = mount_failing(
+        "Filesystem.Mount",
+        MockError::AlreadyMounted("Device is already mounted".into()),
+    )
+    .await;
+    assert!(matches!(mounted, Some(Udisks2Error::AlreadyMounted { .. })));
+}
+
+#[tokio::test]
+async fn test_plain_errors_are_not_classified() {
+    let error = anyhow::anyhow!("Image file not found");
+    assert_eq!(Udisks2Error::find(&error), None);
+}
+
+#[test]
+fn test_dbus_names_map_to_distinct_exit_codes() {
+    let names = [
+        "org.freedesktop.UDisks2.Error.NotAuthorized",
+        "org.freedesktop.UDisks2.Error.NotAuthorizedDismissed",
+        "org.freedesktop.UDisks2.Error.DeviceBusy",
+        "org.freedesktop.UDisks2.Error.AlreadyMounted",
+        "org.freedesktop.UDisks2.Error.NotMounted",
+        "org.freedesktop.UDisks2.Error.NotSupported",
+        "org.freedesktop.DBus.Error.ServiceUnknown",
+        "org.freedesktop.DBus.Error.NoReply",
+        "org.freedesktop.UDisks2.Error.Failed",
+    ];
+    let codes: HashSet<i32> = names
+        .iter()
+        .map(|name| Udisks2Error::from_dbus(name, "").exit_code())
+        .collect();
+    assert_eq!(codes.len(), names.len());
+
+    let unavailable = Udisks2Error::from_dbus(
+        "org.freedesktop.DBus.Error.ServiceUnknown",
+        "The name org.freedesktop.UDisks2 was not provided by any .service files",
+    );
+    assert!(matches!(
+        unavailable,
+        Udisks2Error::ServiceUnavailable { .. }
+    ));
+    assert!(unavailable.hint().contains("udisks2"));
+    assert!(
+        Udisks2Error::from_dbus("org.freedesktop.UDisks2.Error.NotAuthorized", "")
+            .hint()
+            .contains("polkit")
+    );
+}
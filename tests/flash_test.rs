@@ -0,0 +1,240 @@
+mod common;
+
+use common::{MockDrive, MockPartition, MockUdisks2};
+use rust_system_tools::{FlashOptions, FlashPhase, FlashProgress, Flasher};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tempfile::NamedTempFile;
+
+const DRIVE_SIZE: u64 = 8 << 20;
+
+/// An image of `len` bytes with a pattern that differs in every chunk
+fn image(len: usize) -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    let data: Vec<u8> = (0..len).map(|i| (i * 7 + i / 4096) as u8).collect();
+    fs::write(file.path(), data).unwrap();
+    file
+}
+
+/// A regular file of the drive's size standing in for `/dev/sdb`
+fn device_file() -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    file.as_file().set_len(DRIVE_SIZE).unwrap();
+    file
+}
+
+fn usb_drive(device_file: &Path) -> MockDrive {
+    MockDrive {
+        id: "SanDisk_Cruzer_4C530001".into(),
+        vendor: "SanDisk".into(),
+        model: "Cruzer Blade".into(),
+        size: DRIVE_SIZE,
+        connection_bus: "usb".into(),
+        removable: true,
+        ejectable: true,
+        block_name: "sdb".into(),
+        device_file: Some(device_file.to_path_buf()),
+        partitions: vec![MockPartition {
+            number: 1,
+            type_guid: "0x0c".into(),
+            name: String::new(),
+            fstype: "vfat".into(),
+            label: "USB".into(),
+        }],
+        mounts: vec![(1, "/media/user/USB".into())],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_flash_writes_and_verifies_image() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let device = device_file();
+    mock.add_drive(usb_drive(device.path())).await.unwrap();
+    // Not a multiple of the chunk size, so the last chunk is short
+    let image = image((5 << 20) + 1234);
+
+    let progress: Arc<Mutex<Vec<FlashProgress>>> = Arc::default();
+    let recorded = progress.clone();
+    let flasher = Flasher::new(mock.connection()).await.unwrap();
+    let report = flasher
+        .flash(
+            image.path(),
+            device.path(),
+            &FlashOptions::default(),
+            move |p| recorded.lock().unwrap().push(p),
+        )
+        .await
+        .unwrap();
+
+    let data = fs::read(image.path()).unwrap();
+    let written = fs::read(device.path()).unwrap();
+    assert_eq!(written.len() as u64, DRIVE_SIZE);
+    assert_eq!(&written[..data.len()], data.as_slice());
+    assert_eq!(report.device, device.path());
+    assert_eq!(report.bytes, data.len() as u64);
+    assert_eq!(report.sha256, format!("{:x}", Sha256::digest(&data)));
+    assert!(report.verified);
+
+    let progress = progress.lock().unwrap();
+    let total = data.len() as u64;
+    for phase in [FlashPhase::Writing, FlashPhase::Verifying] {
+        let phase_progress: Vec<_> = progress.iter().filter(|p| p.phase == phase).collect();
+        assert!(phase_progress.windows(2).all(|w| w[0].bytes < w[1].bytes));
+        assert_eq!(phase_progress.last().unwrap().bytes, total);
+        assert!(phase_progress.iter().all(|p| p.total == total));
+    }
+
+    let calls = mock.calls();
+    let position = |call: &str| calls.iter().position(|c| c.starts_with(call)).unwrap();
+    assert!(position("Filesystem.Unmount") < position("Block.OpenForRestore"));
+    assert!(position("Block.OpenForRestore") < position("Block.OpenForBackup"));
+}
+
+#[tokio::test]
+async fn test_flash_without_verify_skips_read_back() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let device = device_file();
+    mock.add_drive(usb_drive(device.path())).await.unwrap();
+    let image = image(4096);
+
+    let flasher = Flasher::new(mock.connection()).await.unwrap();
+    let options = FlashOptions {
+        verify: false,
+        ..FlashOptions::default()
+    };
+    let report = flasher
+        .flash(image.path(), device.path(), &options, |_| {})
+        .await
+        .unwrap();
+
+    assert!(!report.verified);
+    assert!(
+        !mock
+            .calls()
+            .iter()
+            .any(|c| c.starts_with("Block.OpenForBackup"))
+    );
+}
+
+#[tokio::test]
+async fn test_flash_refuses_system_drive_even_with_force() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let device = device_file();
+    mock.add_drive(MockDrive {
+        mounts: vec![(1, "/".into())],
+        ..usb_drive(device.path())
+    })
+    .await
+    .unwrap();
+    let image = image(4096);
+
+    let flasher = Flasher::new(mock.connection()).await.unwrap();
+    let options = FlashOptions {
+        force: true,
+        ..FlashOptions::default()
+    };
+    let error = flasher
+        .flash(image.path(), device.path(), &options, |_| {})
+        .await
+        .unwrap_err();
+
+    assert!(
+        format!("{error:#}").contains("root filesystem"),
+        "{error:#}"
+    );
+    assert!(mock.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_flash_refuses_non_removable_drive_without_force() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let device = device_file();
+    mock.add_drive(MockDrive {
+        removable: false,
+        connection_bus: "sata".into(),
+        ..usb_drive(device.path())
+    })
+    .await
+    .unwrap();
+    let image = image(4096);
+    let flasher = Flasher::new(mock.connection()).await.unwrap();
+
+    let error = flasher
+        .flash(
+            image.path(),
+            device.path(),
+            &FlashOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("--force"), "{error:#}");
+    assert!(mock.calls().is_empty());
+
+    let options = FlashOptions {
+        force: true,
+        ..FlashOptions::default()
+    };
+    flasher
+        .flash(image.path(), device.path(), &options, |_| {})
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_flash_refuses_target_smaller_than_image() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let device = device_file();
+    mock.add_drive(usb_drive(device.path())).await.unwrap();
+    let image = image(DRIVE_SIZE as usize + 1);
+
+    let flasher = Flasher::new(mock.connection()).await.unwrap();
+    let error = flasher
+        .flash(
+            image.path(),
+            device.path(),
+            &FlashOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+
+    assert!(format!("{error:#}").contains("only holds"), "{error:#}");
+    assert!(mock.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_flash_rejects_partition_and_unknown_targets() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let device = device_file();
+    mock.add_drive(usb_drive(device.path())).await.unwrap();
+    let image = image(4096);
+    let flasher = Flasher::new(mock.connection()).await.unwrap();
+
+    let error = flasher
+        .flash(
+            image.path(),
+            Path::new("/dev/sdb1"),
+            &FlashOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("is a partition"), "{error:#}");
+
+    let error = flasher
+        .flash(
+            image.path(),
+            Path::new("/dev/sdz"),
+            &FlashOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("No drive found"), "{error:#}");
+}
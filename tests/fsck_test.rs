@@ -0,0 +1,227 @@
+mod common;
+
+use common::{
+    BLOCK_DEVICES_PATH, MANAGER_PATH, MockDrive, MockUdisks2, filesystem_tool, partition,
+    seed_image,
+};
+use rust_system_tools::{
+    FsckOptions, FsckStatus, IsoMounter, LoopOptions, MountOptions, UnmountOptions,
+};
+use std::path::Path;
+
+#[tokio::test]
+async fn test_fsck_checks_every_filesystem_of_an_image_and_detaches() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = seed_image();
+    mock.set_next_partitions(vec![
+        partition(1, "vfat", "CIDATA"),
+        partition(2, "ext4", "rootfs"),
+    ]);
+    mock.set_damaged(&format!("{BLOCK_DEVICES_PATH}/loop0p2"));
+
+    let tool = filesystem_tool(&mock).await;
+    let report = tool
+        .fsck(image.path(), &FsckOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(report.target, image.path());
+    assert_eq!(report.loop_device.as_deref(), Some(Path::new("/dev/loop0")));
+    let statuses: Vec<_> = report
+        .filesystems
+        .iter()
+        .map(|fs| (fs.device.to_str().unwrap(), fs.number, fs.status))
+        .collect();
+    assert_eq!(
+        statuses,
+        [
+            ("/dev/loop0p1", Some(1), FsckStatus::Clean),
+            ("/dev/loop0p2", Some(2), FsckStatus::Damaged),
+        ]
+    );
+    assert_eq!(report.filesystems[1].label.as_deref(), Some("rootfs"));
+    assert!(!report.is_ok());
+    assert_eq!(
+        mock.calls(),
+        [
+            format!("Manager.LoopSetup {MANAGER_PATH}"),
+            format!("Filesystem.Check {BLOCK_DEVICES_PATH}/loop0p1"),
+            format!("Filesystem.Check {BLOCK_DEVICES_PATH}/loop0p2"),
+            format!("Loop.Delete {BLOCK_DEVICES_PATH}/loop0"),
+        ]
+    );
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_fsck_repair_attaches_image_writable() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = seed_image();
+    mock.set_next_partitions(vec![
+        partition(1, "vfat", "CIDATA"),
+        partition(2, "ext4", "rootfs"),
+    ]);
+    mock.set_damaged(&format!("{BLOCK_DEVICES_PATH}/loop0p2"));
+
+    let tool = filesystem_tool(&mock).await;
+    let report = tool
+        .fsck(
+            image.path(),
+            &FsckOptions {
+                repair: true,
+                partition: Some(2),
+            },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.filesystems.len(), 1);
+    assert_eq!(report.filesystems[0].status, FsckStatus::Repaired);
+    assert!(report.is_ok());
+    // The mock refuses to repair filesystems on read-only loop devices
+    assert!(
+        mock.calls()
+            .contains(&format!("Filesystem.Repair {BLOCK_DEVICES_PATH}/loop0p2"))
+    );
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_relabel_and_resize_need_a_single_filesystem() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = seed_image();
+    let tool = filesystem_tool(&mock).await;
+
+    mock.set_next_partitions(vec![
+        partition(1, "vfat", "CIDATA"),
+        partition(2, "ext4", "rootfs"),
+    ]);
+    let error = tool.relabel(image.path(), None, "SEED").await.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("has 2 filesystems, choose one with --partition"),
+        "{error}"
+    );
+    assert!(mock.block_devices().await.is_empty());
+
+    mock.set_next_partitions(vec![
+        partition(1, "vfat", "CIDATA"),
+        partition(2, "ext4", "rootfs"),
+    ]);
+    let device = tool.relabel(image.path(), Some(1), "SEED").await.unwrap();
+    assert_eq!(device, Path::new("/dev/loop1p1"));
+
+    mock.set_next_partitions(vec![partition(1, "ext4", "rootfs")]);
+    let device = tool.resize(image.path(), None, None).await.unwrap();
+    assert_eq!(device, Path::new("/dev/loop2p1"));
+
+    let calls = mock.calls();
+    assert!(calls.contains(&format!("Filesystem.SetLabel {BLOCK_DEVICES_PATH}/loop1p1")));
+    assert!(calls.contains(&format!("Filesystem.Resize {BLOCK_DEVICES_PATH}/loop2p1")));
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_fsck_on_drive_partitions_refuses_mounted_filesystems() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.add_drive(MockDrive {
+        id: "SanDisk_Cruzer_4C530001".into(),
+        connection_bus: "usb".into(),
+        removable: true,
+        block_name: "sdb".into(),
+        partitions: vec![partition(1, "vfat", "USB"), partition(2, "ext4", "data")],
+        mounts: vec![(1, "/media/user/USB".into())],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let tool = filesystem_tool(&mock).await;
+
+    let error = tool
+        .fsck(Path::new("/dev/sdb1"), &FsckOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "/dev/sdb1 is mounted at /media/user/USB, unmount it first"
+    );
+
+    let report = tool
+        .fsck(
+            Path::new("/dev/sdb"),
+            &FsckOptions {
+                repair: false,
+                partition: Some(2),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(report.loop_device, None);
+    assert_eq!(report.filesystems[0].device, Path::new("/dev/sdb2"));
+    assert_eq!(report.filesystems[0].status, FsckStatus::Clean);
+    assert_eq!(
+        mock.calls(),
+        [format!("Filesystem.Check {BLOCK_DEVICES_PATH}/sdb2")]
+    );
+}
+
+#[tokio::test]
+async fn test_fsck_uses_an_existing_loop_device_unless_it_is_mounted() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = seed_image();
+    mock.set_next_partitions(vec![partition(1, "ext4", "rootfs")]);
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let mounted = mounter
+        .mount_iso(
+            image.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    let tool = filesystem_tool(&mock).await;
+
+    let error = tool
+        .fsck(image.path(), &FsckOptions::default())
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("is mounted from /dev/loop0 at"),
+        "{error}"
+    );
+
+    // Still attached but no longer mounted: checked through the same device, which stays
+    mounter
+        .backend()
+        .unmount(&mounted.partitions[0], &UnmountOptions::default())
+        .await
+        .unwrap();
+    let report = tool
+        .fsck(
+            image.path(),
+            &FsckOptions {
+                repair: true,
+                partition: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.loop_device.as_deref(), Some(Path::new("/dev/loop0")));
+    assert!(report.is_ok());
+    let calls = mock.calls();
+    assert_eq!(
+        calls
+            .iter()
+            .filter(|c| c.starts_with("Manager.LoopSetup"))
+            .count(),
+        1
+    );
+    assert!(!calls.iter().any(|c| c.starts_with("Loop.Delete")));
+    assert!(
+        mock.block_devices()
+            .await
+            .contains(&format!("{BLOCK_DEVICES_PATH}/loop0"))
+    );
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use common::{
+    BLOCK_DEVICES_PATH, MANAGER_PATH, MockUdisks2, filesystem_tool, partition, seed_image,
+};
+use rust_system_tools::{
+    FsckOptions, FsckStatus, IsoMounter, LoopOptions, MountOptions, PassphraseSource,
+    Udisks2Backend, UnmountOptions,
+};
+use std::{fs, path::Path, sync::Arc};
+use tempfile::NamedTempFile;
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+/// An archived disk image with a plain ESP and a LUKS root partition
+fn encrypted_image(mock: &MockUdisks2) -> NamedTempFile {
+    mock.set_next_partitions(vec![
+        partition(1, "vfat", "ESP"),
+        partition(2, "crypto_LUKS", ""),
+    ]);
+    mock.set_luks_passphrase(PASSPHRASE);
+    seed_image()
+}
+
+fn keyfile(contents: &str) -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    fs::write(file.path(), contents).unwrap();
+    file
+}
+
+async fn mounter(mock: &MockUdisks2, passphrase: PassphraseSource) -> IsoMounter {
+    IsoMounter::with_backend(Arc::new(
+        Udisks2Backend::new(mock.connection())
+            .await
+            .unwrap()
+            .with_passphrase_source(passphrase),
+    ))
+}
+
+#[tokio::test]
+async fn test_mount_unlocks_luks_partition_and_locks_before_delete() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = encrypted_image(&mock);
+    let key = keyfile(PASSPHRASE);
+    let mounter = mounter(&mock, PassphraseSource::Keyfile(key.path().into())).await;
+
+    let mounted = mounter
+        .mount_guarded(
+            image.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let found: Vec<_> = mounted
+        .partitions
+        .iter()
+        .map(|p| (p.id.as_str(), p.number, p.fstype.as_str()))
+        .collect();
+    let cleartext = format!("{BLOCK_DEVICES_PATH}/dm_2d0");
+    let esp = format!("{BLOCK_DEVICES_PATH}/loop0p1");
+    assert_eq!(
+        found,
+        [
+            (esp.as_str(), Some(1), "vfat"),
+            (cleartext.as_str(), Some(2), "ext4"),
+        ]
+    );
+    assert!(Path::new(&mounted.partitions[1].mount_path).is_dir());
+
+    mounted.close().await.unwrap();
+
+    assert_eq!(
+        mock.calls(),
+        [
+            format!("Manager.LoopSetup {MANAGER_PATH}"),
+            format!("Encrypted.Unlock {BLOCK_DEVICES_PATH}/loop0p2"),
+            format!("Filesystem.Mount {esp}"),
+            format!("Filesystem.Mount {cleartext}"),
+            format!("Filesystem.Unmount {esp}"),
+            format!("Filesystem.Unmount {cleartext}"),
+            format!("Encrypted.Lock {BLOCK_DEVICES_PATH}/loop0p2"),
+            format!("Loop.Delete {BLOCK_DEVICES_PATH}/loop0"),
+        ]
+    );
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_wrong_key_fails_and_deletes_loop_device() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = encrypted_image(&mock);
+    let key = keyfile("wrong");
+    let mounter = mounter(&mock, PassphraseSource::Keyfile(key.path().into())).await;
+
+    let error = mounter
+        .mount_guarded(
+            image.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+    assert!(
+        format!("{error:#}").contains("Operation not permitted"),
+        "{error:#}"
+    );
+    let calls = mock.calls();
+    assert!(!calls.iter().any(|c| c.starts_with("Filesystem.Mount")));
+    assert!(!calls.iter().any(|c| c.starts_with("Encrypted.Lock")));
+    assert_eq!(
+        calls.last().unwrap(),
+        &format!("Loop.Delete {BLOCK_DEVICES_PATH}/loop0")
+    );
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_detach_kept_image_locks_luks_partition() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = encrypted_image(&mock);
+    let key = keyfile(PASSPHRASE);
+    let mounter = mounter(&mock, PassphraseSource::Keyfile(key.path().into())).await;
+    mounter
+        .mount_guarded(
+            image.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap()
+        .keep_mounted()
+        .await
+        .unwrap();
+
+    // The cleartext filesystem is listed with the loop device, so `unmount <image>` finds it
+    let device = mounter
+        .find_loop_device(image.path())
+        .await
+        .unwrap()
+        .unwrap();
+    let cleartext = device
+        .filesystems
+        .iter()
+        .find(|fs| fs.fstype == "ext4")
+        .unwrap();
+    assert_eq!(cleartext.number, Some(2));
+    assert_eq!(cleartext.mount_points.len(), 1);
+
+    mounter
+        .detach(&device, &UnmountOptions::default())
+        .await
+        .unwrap();
+
+    let calls = mock.calls();
+    let lock = calls
+        .iter()
+        .position(|c| c == &format!("Encrypted.Lock {BLOCK_DEVICES_PATH}/loop0p2"))
+        .unwrap();
+    let delete = calls
+        .iter()
+        .position(|c| c == &format!("Loop.Delete {BLOCK_DEVICES_PATH}/loop0"))
+        .unwrap();
+    assert!(lock < delete);
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_fsck_checks_cleartext_filesystem_of_encrypted_image() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let image = encrypted_image(&mock);
+    let key = keyfile(PASSPHRASE);
+    let tool = filesystem_tool(&mock)
+        .await
+        .with_passphrase_source(PassphraseSource::Keyfile(key.path().into()));
+
+    let report = tool
+        .fsck(image.path(), &FsckOptions::default())
+        .await
+        .unwrap();
+
+    let checked: Vec<_> = report
+        .filesystems
+        .iter()
+        .map(|fs| (fs.device.to_str().unwrap(), fs.fstype.as_str(), fs.status))
+        .collect();
+    assert_eq!(
+        checked,
+        [
+            ("/dev/loop0p1", "vfat", FsckStatus::Clean),
+            ("/dev/dm-0", "ext4", FsckStatus::Clean),
+        ]
+    );
+    assert!(mock.block_devices().await.is_empty());
+}
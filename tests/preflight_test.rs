@@ -0,0 +1,95 @@
+mod common;
+
+use common::{MockUdisks2, filesystem_tool, partition, seed_image};
+use rust_system_tools::{
+    CallPolicy, FsckOptions, Udisks2Capabilities, Udisks2Error, Udisks2Feature, Udisks2Version,
+    set_call_policy,
+};
+
+#[tokio::test]
+async fn test_probe_reads_version_and_filesystems() {
+    let mock = MockUdisks2::start().await.unwrap();
+
+    let capabilities = Udisks2Capabilities::probe(mock.connection()).await.unwrap();
+
+    assert_eq!(capabilities.version_string, "2.10.1");
+    assert_eq!(capabilities.version, Some(Udisks2Version::new(2, 10, 1)));
+    assert!(
+        capabilities
+            .supported_filesystems
+            .as_ref()
+            .unwrap()
+            .contains(&"udf".to_string())
+    );
+    assert!(capabilities.supports(Udisks2Feature::FilesystemMaintenance));
+}
+
+#[tokio::test]
+async fn test_old_daemon_refuses_fsck_but_still_relabels() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_version("2.6.5");
+    mock.set_next_partitions(vec![partition(1, "ext4", "data")]);
+    let image = seed_image();
+
+    let tool = filesystem_tool(&mock).await;
+    let error = tool
+        .fsck(image.path(), &FsckOptions::default())
+        .await
+        .unwrap_err();
+
+    assert!(
+        error.to_string().contains("needs UDisks2 2.7.2 or newer"),
+        "{error:#}"
+    );
+    assert!(matches!(
+        Udisks2Error::find(&error),
+        Some(Udisks2Error::NotSupported { .. })
+    ));
+    // Nothing was attached for the refused check
+    assert!(
+        !mock
+            .calls()
+            .iter()
+            .any(|c| c.starts_with("Manager.LoopSetup"))
+    );
+
+    tool.relabel(image.path(), None, "backup").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stalled_daemon_times_out() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_stalled(true);
+    set_call_policy(CallPolicy {
+        timeout_secs: 1,
+        retries: 1,
+        retry_delay_ms: 10,
+        ..CallPolicy::default()
+    });
+
+    let error = Udisks2Capabilities::probe(mock.connection())
+        .await
+        .unwrap_err();
+    set_call_policy(CallPolicy::default());
+
+    let classified = Udisks2Error::find(&error).unwrap();
+    assert!(
+        matches!(classified, Udisks2Error::Timeout { .. }),
+        "{error:#}"
+    );
+    assert_eq!(classified.exit_code(), 17);
+}
+
+#[test]
+fn test_version_parsing() {
+    assert_eq!(
+        Udisks2Version::parse("2.10.91-git"),
+        Some(Udisks2Version::new(2, 10, 91))
+    );
+    assert_eq!(
+        Udisks2Version::parse("2.8"),
+        Some(Udisks2Version::new(2, 8, 0))
+    );
+    assert_eq!(Udisks2Version::parse("unknown"), None);
+    assert!(Udisks2Version::new(2, 7, 2) > Udisks2Version::new(2, 6, 10));
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{BLOCK_DEVICES_PATH, DRIVES_PATH, MockDrive, MockError, MockUdisks2, partition};
+use rust_system_tools::{RemovalAction, SafeRemover};
+use std::{fs::File, path::Path};
+
+fn usb_drive() -> MockDrive {
+    MockDrive {
+        id: "SanDisk_Cruzer_4C530001".into(),
+        vendor: "SanDisk".into(),
+        model: "Cruzer Blade".into(),
+        size: 16 << 30,
+        connection_bus: "usb".into(),
+        removable: true,
+        ejectable: true,
+        block_name: "sdb".into(),
+        partitions: vec![partition(1, "vfat", ""), partition(2, "crypto_LUKS", "")],
+        mounts: vec![
+            (1, "/media/user/USB".into()),
+            (2, "/media/user/secret".into()),
+        ],
+        unlocked: vec![(2, "ext4".into())],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_safely_remove_unmounts_locks_and_powers_off() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.add_drive(usb_drive()).await.unwrap();
+
+    let remover = SafeRemover::new(mock.connection()).await.unwrap();
+    let report = remover.safely_remove(Path::new("/dev/sdb")).await.unwrap();
+
+    assert_eq!(report.device, Path::new("/dev/sdb"));
+    assert_eq!(
+        report.unmounted,
+        [Path::new("/dev/sdb1"), Path::new("/dev/dm-2")]
+    );
+    assert_eq!(report.locked, [Path::new("/dev/sdb2")]);
+    assert_eq!(report.action, RemovalAction::PoweredOff);
+    assert_eq!(
+        mock.calls(),
+        [
+            format!("Filesystem.Unmount {BLOCK_DEVICES_PATH}/sdb1"),
+            format!("Filesystem.Unmount {BLOCK_DEVICES_PATH}/dm_2d2"),
+            format!("Encrypted.Lock {BLOCK_DEVICES_PATH}/sdb2"),
+            format!("Drive.PowerOff {DRIVES_PATH}/SanDisk_Cruzer_4C530001"),
+        ]
+    );
+    assert!(
+        !mock
+            .block_devices()
+            .await
+            .contains(&format!("{BLOCK_DEVICES_PATH}/dm_2d2"))
+    );
+}
+
+#[tokio::test]
+async fn test_safely_remove_ejects_drives_that_cannot_power_off() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.add_drive(MockDrive {
+        id: "HL_DT_ST_DVDRAM_GP65NB60".into(),
+        connection_bus: "sdio".into(),
+        media: "optical_dvd".into(),
+        block_name: "sr0".into(),
+        partitions: Vec::new(),
+        mounts: Vec::new(),
+        unlocked: Vec::new(),
+        ..usb_drive()
+    })
+    .await
+    .unwrap();
+
+    let remover = SafeRemover::new(mock.connection()).await.unwrap();
+    let report = remover.safely_remove(Path::new("/dev/sr0")).await.unwrap();
+
+    assert!(report.unmounted.is_empty());
+    assert!(report.locked.is_empty());
+    assert_eq!(report.action, RemovalAction::Ejected);
+    assert_eq!(
+        mock.calls(),
+        [format!(
+            "Drive.Eject {DRIVES_PATH}/HL_DT_ST_DVDRAM_GP65NB60"
+        )]
+    );
+}
+
+#[tokio::test]
+async fn test_safely_remove_lists_processes_holding_a_busy_mount() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let mount_point = tempfile::tempdir().unwrap();
+    let mount_point = mount_point.path().canonicalize().unwrap();
+    let held = mount_point.join("notes.txt");
+    let _file = File::create(&held).unwrap();
+    mock.add_drive(MockDrive {
+        partitions: vec![partition(1, "vfat", "")],
+        mounts: vec![(1, mount_point.to_string_lossy().into_owned())],
+        unlocked: Vec::new(),
+        ..usb_drive()
+    })
+    .await
+    .unwrap();
+    mock.fail_next(
+        "Filesystem.Unmount",
+        MockError::DeviceBusy("target is busy".into()),
+    );
+
+    let remover = SafeRemover::new(mock.connection()).await.unwrap();
+    let error = remover
+        .safely_remove(Path::new("/dev/sdb"))
+        .await
+        .unwrap_err()
+        .to_string();
+
+    assert!(error.contains("/dev/sdb is still in use"), "{error}");
+    assert!(error.contains("target is busy"), "{error}");
+    assert!(
+        error.contains(&format!(
+            "pid {} ({}): open file {}",
+            std::process::id(),
+            std::fs::read_to_string("/proc/self/comm")
+                .unwrap()
+                .trim_end(),
+            held.display()
+        )),
+        "{error}"
+    );
+    assert!(
+        !mock
+            .calls()
+            .iter()
+            .any(|c| c.starts_with("Drive.") || c.starts_with("Encrypted."))
+    );
+}
+
+#[tokio::test]
+async fn test_safely_remove_refuses_system_drive() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.add_drive(MockDrive {
+        id: "Samsung_SSD_970_EVO_S1234".into(),
+        connection_bus: String::new(),
+        removable: false,
+        ejectable: false,
+        block_name: "sda".into(),
+        mounts: vec![(1, "/boot/efi".into()), (2, "/".into())],
+        unlocked: Vec::new(),
+        ..usb_drive()
+    })
+    .await
+    .unwrap();
+
+    let remover = SafeRemover::new(mock.connection()).await.unwrap();
+    let error = remover
+        .safely_remove(Path::new("/dev/sda"))
+        .await
+        .unwrap_err();
+
+    assert!(error.to_string().contains("running system"), "{error}");
+    assert!(mock.calls().is_empty());
+}
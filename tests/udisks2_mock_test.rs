@@ -0,0 +1,864 @@
+mod common;
+
+use common::{
+    BLOCK_DEVICES_PATH, MockDrive, MockError, MockPartition, MockUdisks2, fake_iso, partition,
+};
+use rust_system_tools::loop_refs::LoopRefs;
+use rust_system_tools::{
+    HolderUsage, IsoMounter, LoopOptions, MountGuard, MountOptions, MountedImage, UDisks2Block,
+    UDisks2Filesystem, UDisks2Loop, UDisks2ObjectManager, Udisks2Error, UnmountOptions,
+    UnmountRetry, find_holders,
+};
+use std::{path::Path, time::Duration};
+use zbus::zvariant::ObjectPath;
+
+#[tokio::test]
+async fn test_mount_and_close_whole_device_image() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(mounted.partitions.len(), 1);
+    let partition = &mounted.partitions[0];
+    assert_eq!(partition.number, None);
+    assert_eq!(partition.fstype, "iso9660");
+    assert_eq!(partition.label.as_deref(), Some("MOCK_ISO"));
+    assert!(std::path::Path::new(&partition.mount_path).is_dir());
+    assert_eq!(mock.block_devices().await.len(), 1);
+
+    mounted.close().await.unwrap();
+
+    assert!(mock.block_devices().await.is_empty());
+    let calls = mock.calls();
+    assert!(calls.iter().any(|c| c.starts_with("Filesystem.Unmount")));
+    assert!(calls.iter().any(|c| c.starts_with("Loop.Delete")));
+}
+
+#[tokio::test]
+async fn test_mount_every_partition_of_partitioned_image() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_next_partitions(vec![
+        MockPartition {
+            number: 2,
+            type_guid: "0fc63daf-8483-4772-8e79-3d69d8477de4".into(),
+            name: "root".into(),
+            fstype: "ext4".into(),
+            label: "".into(),
+        },
+        MockPartition {
+            number: 1,
+            type_guid: "c12a7328-f81f-11d2-ba4b-00a0c93ec93b".into(),
+            name: "EFI system partition".into(),
+            fstype: "vfat".into(),
+            label: "ESP".into(),
+        },
+    ]);
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_iso(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let numbers: Vec<_> = mounted.partitions.iter().map(|p| p.number).collect();
+    assert_eq!(numbers, vec![Some(1), Some(2)]);
+    assert_eq!(mounted.partitions[0].fstype, "vfat");
+    assert_eq!(mounted.partitions[0].label.as_deref(), Some("ESP"));
+    assert_eq!(
+        mounted.partitions[0].type_guid.as_deref(),
+        Some("c12a7328-f81f-11d2-ba4b-00a0c93ec93b")
+    );
+    // Without an IdLabel the partition name is used
+    assert_eq!(mounted.partitions[1].label.as_deref(), Some("root"));
+
+    // The plain MountedIso of mount_iso is unmounted through the mounter
+    mounter.unmount_iso(mounted).await.unwrap();
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_mount_failure_deletes_loop_device() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.fail_next(
+        "Filesystem.Mount",
+        MockError::NotAuthorized("Not authorized to perform operation".into()),
+    );
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let result = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await;
+
+    assert!(result.is_err());
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_loop_setup_error_is_reported() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.fail_next(
+        "Manager.LoopSetup",
+        MockError::Failed("Error creating loop device".into()),
+    );
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let error = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+    assert!(format!("{error:#}").contains("Error creating loop device"));
+}
+
+#[tokio::test]
+async fn test_unsupported_fstype_is_rejected_before_loop_setup() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let mount_options = MountOptions {
+        fstype: Some("hfsplus".into()),
+        ..Default::default()
+    };
+
+    let result = mounter
+        .mount_guarded(iso.path(), &LoopOptions::default(), &mount_options)
+        .await;
+
+    assert!(result.is_err());
+    assert!(mock.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_find_and_detach_by_backing_file_and_mount_point() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let first = fake_iso();
+    let second = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let first_image = mounter
+        .mount_iso(
+            first.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    let second_image = mounter
+        .mount_iso(
+            second.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let by_file = mounter
+        .find_loop_device(first.path())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(by_file.object_path.as_str(), first_image.device.id);
+
+    let mount_path = second_image.mount_path().unwrap();
+    let by_mount = mounter
+        .find_loop_device(mount_path.as_ref())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(by_mount.backing_file, second.path().canonicalize().unwrap());
+
+    mounter
+        .detach(&by_file, &UnmountOptions::default())
+        .await
+        .unwrap();
+    mounter
+        .detach(&by_mount, &UnmountOptions::default())
+        .await
+        .unwrap();
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_busy_unmount_is_retried_with_backoff() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    mounter
+        .mount_iso(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    mock.fail_next(
+        "Filesystem.Unmount",
+        MockError::DeviceBusy("target is busy".into()),
+    );
+
+    let device = mounter.find_loop_device(iso.path()).await.unwrap().unwrap();
+    let options = UnmountOptions {
+        retry: Some(UnmountRetry {
+            attempts: 2,
+            initial_delay: Duration::from_millis(10),
+        }),
+        ..Default::default()
+    };
+    mounter.detach(&device, &options).await.unwrap();
+
+    let unmounts = mock
+        .calls()
+        .iter()
+        .filter(|c| c.starts_with("Filesystem.Unmount"))
+        .count();
+    assert_eq!(unmounts, 2);
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_busy_unmount_lists_holders() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let mounted = mounter
+        .mount_iso(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    let held = std::path::Path::new(mounted.mount_path().unwrap()).join("setup.exe");
+    let _file = std::fs::File::create(&held).unwrap();
+    mock.fail_next(
+        "Filesystem.Unmount",
+        MockError::DeviceBusy("target is busy".into()),
+    );
+
+    let device = mounter.find_loop_device(iso.path()).await.unwrap().unwrap();
+    let error = mounter
+        .detach(&device, &UnmountOptions::default())
+        .await
+        .unwrap_err();
+
+    let message = format!("{error:#}");
+    assert!(message.contains("is still in use by:"), "{message}");
+    assert!(
+        message.contains(&format!(
+            "pid {} ({}): open file {}",
+            std::process::id(),
+            std::fs::read_to_string("/proc/self/comm")
+                .unwrap()
+                .trim_end(),
+            held.display()
+        )),
+        "{message}"
+    );
+    assert!(matches!(
+        Udisks2Error::find(&error),
+        Some(Udisks2Error::DeviceBusy { .. })
+    ));
+}
+
+#[test]
+fn test_holders_of_removed_files_are_listed_by_their_path() {
+    let mount_point = tempfile::tempdir().unwrap();
+    let mount_point = mount_point.path().canonicalize().unwrap();
+    let held = mount_point.join("setup.exe");
+    let _file = std::fs::File::create(&held).unwrap();
+    std::fs::remove_file(&held).unwrap();
+
+    let holders = find_holders(&mount_point);
+
+    assert!(
+        holders.iter().any(|holder| holder.pid == std::process::id()
+            && holder.usage == HolderUsage::OpenFile
+            && holder.path == held),
+        "{holders:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_mounting_same_image_twice_shares_loop_device() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let link_dir = tempfile::tempdir().unwrap();
+    let link = link_dir.path().join("same.iso");
+    std::os::unix::fs::symlink(iso.path(), &link).unwrap();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let first = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    // The symlink names the same inode, so the first mount is reused
+    let second = mounter
+        .mount_guarded(&link, &LoopOptions::default(), &MountOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(second.device, first.device);
+    assert_eq!(second.mount_path(), first.mount_path());
+    // Read from the loop device listing, they match what the first mount was told
+    assert_eq!(second.partitions[0].details, first.partitions[0].details);
+    assert!(second.is_shared());
+    let loop_setups = || {
+        mock.calls()
+            .iter()
+            .filter(|c| c.starts_with("Manager.LoopSetup"))
+            .count()
+    };
+    assert_eq!(loop_setups(), 1);
+
+    // Only the last user unmounts and deletes the loop device
+    first.close().await.unwrap();
+    assert_eq!(mock.block_devices().await.len(), 1);
+    assert!(
+        !mock
+            .calls()
+            .iter()
+            .any(|c| c.starts_with("Filesystem.Unmount"))
+    );
+    second.close().await.unwrap();
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_reused_kept_mount_stays_until_unmounted() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap()
+        .keep_mounted()
+        .await
+        .unwrap();
+
+    let reused = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    reused.close().await.unwrap();
+    let device = mounter.find_loop_device(iso.path()).await.unwrap().unwrap();
+    assert_eq!(device.mount_points().count(), 1);
+
+    // An offset asks for a different view of the image and gets its own loop device
+    let offset = LoopOptions {
+        offset: Some(512),
+        ..LoopOptions::default()
+    };
+    let separate = mounter
+        .mount_guarded(iso.path(), &offset, &MountOptions::default())
+        .await
+        .unwrap();
+    assert_ne!(separate.device.id, device.object_path.as_str());
+    separate.close().await.unwrap();
+
+    mounter
+        .detach(&device, &UnmountOptions::default())
+        .await
+        .unwrap();
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_different_read_only_or_mount_options_get_their_own_loop_device() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let first = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let read_only = LoopOptions {
+        read_only: true,
+        ..LoopOptions::default()
+    };
+    let second = mounter
+        .mount_guarded(iso.path(), &read_only, &MountOptions::default())
+        .await
+        .unwrap();
+    // The mock's mount points are not in the mount table, so their options are unknown
+    let noexec = MountOptions {
+        options: Some("noexec".into()),
+        ..MountOptions::default()
+    };
+    let third = mounter
+        .mount_guarded(iso.path(), &LoopOptions::default(), &noexec)
+        .await
+        .unwrap();
+
+    assert_ne!(second.device, first.device);
+    assert_ne!(third.device, first.device);
+    assert_ne!(third.device, second.device);
+    for mounted in [first, second, third] {
+        mounted.close().await.unwrap();
+    }
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_iso_images_are_attached_read_only_and_reused() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = tempfile::Builder::new().suffix(".iso").tempfile().unwrap();
+    iso.as_file().set_len(4096).unwrap();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let first = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    let device = mounter.find_loop_device(iso.path()).await.unwrap().unwrap();
+    assert!(device.read_only);
+    let second = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(second.device, first.device);
+    second.close().await.unwrap();
+    first.close().await.unwrap();
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[test]
+fn test_acquire_fails_once_the_last_user_tore_the_device_down() {
+    let iso = fake_iso();
+    let metadata = iso.as_file().metadata().unwrap();
+    let refs = LoopRefs::new("/org/freedesktop/UDisks2/block_devices/loop99", &metadata).unwrap();
+    refs.create().unwrap();
+
+    // The last user keeps the references locked while it detaches the device
+    let released = refs.release().unwrap();
+    assert_eq!(released.left(), 0);
+    let waiting = {
+        let refs = refs.clone();
+        std::thread::spawn(move || refs.acquire())
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!waiting.is_finished());
+    refs.clear();
+    drop(released);
+
+    let error = waiting.join().unwrap().unwrap_err();
+    assert!(error.to_string().contains("was detached"), "{error:#}");
+    refs.clear();
+}
+
+#[tokio::test]
+async fn test_keep_mounted_sets_autoclear() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap()
+        .keep_mounted()
+        .await
+        .unwrap();
+
+    let device = mounter.find_loop_device(iso.path()).await.unwrap().unwrap();
+    assert!(device.autoclear);
+    assert_eq!(
+        device.mount_points().next().unwrap(),
+        std::path::Path::new(mounted.mount_path().unwrap())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_dropped_guard_cleans_up() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    drop(mounted);
+
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_abandoned_mount_cleans_up_what_it_set_up() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_next_partitions(vec![
+        partition(1, "vfat", "ESP"),
+        partition(2, "ext4", "rootfs"),
+    ]);
+    mock.stall_mount(
+        &format!("{BLOCK_DEVICES_PATH}/loop0p2"),
+        Duration::from_secs(1),
+    );
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    // Given up while the second filesystem waits, like on Ctrl-C during a polkit prompt
+    let (loop_options, mount_options) = (LoopOptions::default(), MountOptions::default());
+    let mounting = mounter.mount_guarded(iso.path(), &loop_options, &mount_options);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), mounting)
+            .await
+            .is_err()
+    );
+
+    let calls = mock.calls();
+    assert!(calls.contains(&format!("Filesystem.Unmount {BLOCK_DEVICES_PATH}/loop0p1")));
+    assert!(calls.contains(&format!("Loop.Delete {BLOCK_DEVICES_PATH}/loop0")));
+}
+
+#[tokio::test]
+async fn test_read_only_option_controls_loop_device() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    for read_only in [false, true] {
+        let loop_options = LoopOptions {
+            read_only,
+            ..LoopOptions::default()
+        };
+        let mounted = mounter
+            .mount_guarded(iso.path(), &loop_options, &MountOptions::default())
+            .await
+            .unwrap();
+        let block = UDisks2Block::new(
+            mock.connection(),
+            ObjectPath::try_from(mounted.device.id.clone()).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(block.read_only().await.unwrap(), read_only);
+        mounted.close().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_typed_property_reads() {
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let loop_device = UDisks2Loop::new(
+        mock.connection(),
+        ObjectPath::try_from(mounted.device.id.clone()).unwrap(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        loop_device.backing_file().await.unwrap(),
+        iso.path().canonicalize().unwrap()
+    );
+    assert!(!loop_device.autoclear().await.unwrap());
+
+    let partition = &mounted.partitions[0];
+    let object_path = ObjectPath::try_from(partition.id.clone()).unwrap();
+    let filesystem = UDisks2Filesystem::new(mock.connection(), object_path.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        filesystem.mount_points().await.unwrap(),
+        vec![std::path::PathBuf::from(&partition.mount_path)]
+    );
+
+    let block = UDisks2Block::new(mock.connection(), object_path)
+        .await
+        .unwrap();
+    assert_eq!(block.size().await.unwrap(), 4096);
+    assert_eq!(block.id_type().await.unwrap(), "iso9660");
+    assert_eq!(block.id_label().await.unwrap(), "MOCK_ISO");
+
+    mounted.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_mount_returns_mount_info() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_next_partitions(vec![partition(1, "ext4", "data")]);
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions {
+                read_only: true,
+                ..LoopOptions::default()
+            },
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+    // The backend keeps what the daemon reported with each mounted partition
+    let details = &mounted.partitions[0].details;
+    assert_eq!(details.device.as_deref(), Some(Path::new("/dev/loop0p1")));
+    assert_eq!(
+        details.mount_path,
+        Path::new(&mounted.partitions[0].mount_path)
+    );
+    assert_eq!(details.fstype, "ext4");
+    assert!(details.uuid.is_some());
+    assert!(details.read_only);
+    assert_eq!(
+        details.object_path.as_deref(),
+        Some(mounted.partitions[0].id.as_str())
+    );
+    assert_eq!(
+        details.loop_object_path.as_deref(),
+        Some(mounted.device.id.as_str())
+    );
+    let object_path = ObjectPath::try_from(mounted.partitions[0].id.clone()).unwrap();
+    let filesystem = UDisks2Filesystem::new(mock.connection(), object_path.clone())
+        .await
+        .unwrap();
+    filesystem
+        .unmount(&UnmountOptions::default())
+        .await
+        .unwrap();
+
+    let info = filesystem.mount(&MountOptions::default()).await.unwrap();
+
+    let block = UDisks2Block::new(mock.connection(), object_path.clone())
+        .await
+        .unwrap();
+    assert_eq!(info.device, block.device().await.unwrap());
+    assert_eq!(info.object_path.as_str(), object_path.as_str());
+    assert_eq!(
+        info.loop_object_path.as_ref().map(|p| p.as_str()),
+        Some(mounted.device.id.as_str())
+    );
+    assert_eq!(
+        filesystem.mount_points().await.unwrap(),
+        std::slice::from_ref(&info.mount_path)
+    );
+    assert_eq!(info.fstype, "ext4");
+    assert_eq!(info.label.as_deref(), Some("data"));
+    assert!(info.uuid.is_some());
+    assert_eq!(info.size, block.size().await.unwrap());
+    assert!(info.read_only);
+    assert_eq!(
+        info.backing_file.as_deref(),
+        Some(iso.path().canonicalize().unwrap().as_path())
+    );
+
+    mounted.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_misreported_mount_point_fails_and_unmounts() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.set_misreport_mounts(true);
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+
+    let error = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+    assert!(
+        format!("{error:#}").contains("but its MountPoints are"),
+        "{error:#}"
+    );
+    assert!(
+        mock.calls()
+            .iter()
+            .any(|c| c.starts_with("Filesystem.Unmount"))
+    );
+    assert!(mock.block_devices().await.is_empty());
+}
+
+fn assert_send_sync_static<T: Send + Sync + 'static>() {}
+
+#[tokio::test]
+async fn test_mount_handles_are_send_sync_static() {
+    assert_send_sync_static::<IsoMounter>();
+    assert_send_sync_static::<MountGuard>();
+    assert_send_sync_static::<MountedImage>();
+
+    let mock = MockUdisks2::start().await.unwrap();
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let path = iso.path().to_path_buf();
+
+    // Both the mounter and the guard move into a task that outlives this borrow of the connection
+    let mounted = tokio::spawn(async move {
+        mounter
+            .mount_guarded(path, &LoopOptions::default(), &MountOptions::default())
+            .await
+    })
+    .await
+    .unwrap()
+    .unwrap();
+    tokio::spawn(async move { mounted.close().await })
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(mock.block_devices().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_drives_lists_block_devices_and_marks_system_drive() {
+    let mock = MockUdisks2::start().await.unwrap();
+    mock.add_drive(MockDrive {
+        id: "Samsung_SSD_860_S3Z9NB0K".into(),
+        vendor: String::new(),
+        model: "Samsung SSD 860 EVO".into(),
+        serial: "S3Z9NB0K".into(),
+        size: 500 << 30,
+        connection_bus: "sata".into(),
+        block_name: "sda".into(),
+        partitions: vec![partition(1, "vfat", "EFI"), partition(2, "ext4", "root")],
+        mounts: vec![(1, "/boot/efi".into()), (2, "/".into())],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let usb_path = mock
+        .add_drive(MockDrive {
+            id: "SanDisk_Cruzer_4C530001".into(),
+            vendor: "SanDisk".into(),
+            model: "Cruzer Blade".into(),
+            serial: "4C530001".into(),
+            size: 16 << 30,
+            connection_bus: "usb".into(),
+            removable: true,
+            ejectable: true,
+            media: "thumb".into(),
+            block_name: "sdb".into(),
+            partitions: vec![partition(1, "vfat", "USB")],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    // Loop devices have no drive and must not show up on either drive
+    let iso = fake_iso();
+    let mounter = IsoMounter::new(mock.connection()).await.unwrap();
+    let mounted = mounter
+        .mount_guarded(
+            iso.path(),
+            &LoopOptions::default(),
+            &MountOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let object_manager = UDisks2ObjectManager::new(mock.connection()).await.unwrap();
+    let drives = object_manager.drives().await.unwrap();
+    assert_eq!(drives.len(), 2);
+
+    let system = drives.iter().find(|d| d.system).unwrap();
+    assert_eq!(system.name(), "Samsung SSD 860 EVO");
+    assert_eq!(system.device(), Some(std::path::Path::new("/dev/sda")));
+    let devices: Vec<_> = system
+        .block_devices
+        .iter()
+        .map(|b| (b.device.display().to_string(), b.partition_number))
+        .collect();
+    assert_eq!(
+        devices,
+        [
+            ("/dev/sda".to_string(), None),
+            ("/dev/sda1".to_string(), Some(1)),
+            ("/dev/sda2".to_string(), Some(2)),
+        ]
+    );
+    assert_eq!(
+        system.block_devices[2].mount_points,
+        [std::path::PathBuf::from("/")]
+    );
+    assert!(system.block_devices.iter().all(|b| b.hint_system));
+
+    let usb = drives.iter().find(|d| !d.system).unwrap();
+    assert_eq!(usb.object_path.as_str(), usb_path);
+    assert_eq!(usb.name(), "SanDisk Cruzer Blade");
+    assert_eq!(usb.serial, "4C530001");
+    assert_eq!(usb.size, 16 << 30);
+    assert_eq!(usb.connection_bus, "usb");
+    assert!(usb.removable && usb.media_removable && usb.ejectable && usb.can_power_off);
+    assert_eq!(usb.media, "thumb");
+    assert_eq!(usb.block_devices.len(), 2);
+    assert_eq!(usb.block_devices[1].fstype.as_deref(), Some("vfat"));
+    assert_eq!(usb.block_devices[1].label.as_deref(), Some("USB"));
+    assert!(usb.block_devices[1].mount_points.is_empty());
+    assert!(!usb.block_devices[0].hint_system);
+
+    mounted.close().await.unwrap();
+}
@@ -0,0 +1,261 @@
+mod common;
+
+use common::{MockDrive, MockPartition, MockUdisks2, iso::build_iso};
+use rust_system_tools::{
+    IsoMounter, RemovalAction, UserspaceBackend, WindowsUsbCreator, WindowsUsbOptions,
+};
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tempfile::{NamedTempFile, TempDir};
+
+const DRIVE_SIZE: u64 = 16 << 30;
+const DISK_PATH: &str = "/org/freedesktop/UDisks2/block_devices/sdb";
+const PARTITION_PATH: &str = "/org/freedesktop/UDisks2/block_devices/sdb1";
+
+/// A Windows-like ISO whose `install.wim` is `wim_size` bytes
+fn windows_iso(wim_size: usize) -> NamedTempFile {
+    let wim: Vec<u8> = (0..wim_size).map(|i| (i % 251) as u8).collect();
+    let image = build_iso(
+        "CCCOMA_X64FRE",
+        &[
+            ("", "SETUP.EXE;1", b"MZ setup"),
+            ("SOURCES", "INSTALL.WIM;1", &wim),
+        ],
+    );
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&image).unwrap();
+    file
+}
+
+/// A stand-in for `wimlib-imagex` that splits at 1 MiB with head and tail
+fn fake_wimlib(dir: &TempDir) -> PathBuf {
+    let path = dir.path().join("wimlib-imagex");
+    fs::write(
+        &path,
+        r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo "wimlib-imagex 1.14.4"; exit 0; }
+[ "$1" = split ] && [ "$4" = 1 ] || { echo "unexpected arguments: $*" >&2; exit 1; }
+base="${3%.swm}"
+head -c 1048576 "$2" > "$3"
+tail -c +1048577 "$2" > "${base}2.swm"
+"#,
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+async fn usb_stick(mock: &MockUdisks2) {
+    usb_drive(mock, true).await;
+}
+
+/// A USB drive, which the mock can power off, that can be ejected or not
+async fn usb_drive(mock: &MockUdisks2, ejectable: bool) {
+    mock.add_drive(MockDrive {
+        id: "SanDisk_Cruzer_4C530001".into(),
+        vendor: "SanDisk".into(),
+        model: "Cruzer Blade".into(),
+        size: DRIVE_SIZE,
+        connection_bus: "usb".into(),
+        removable: true,
+        ejectable,
+        block_name: "sdb".into(),
+        partitions: vec![MockPartition {
+            number: 1,
+            type_guid: "0x83".into(),
+            name: String::new(),
+            fstype: "ext4".into(),
+            label: "OLD".into(),
+        }],
+        mounts: vec![(1, "/media/user/OLD".into())],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+}
+
+async fn creator(mock: &MockUdisks2) -> WindowsUsbCreator {
+    let mounter = IsoMounter::with_backend(Arc::new(UserspaceBackend::new()));
+    WindowsUsbCreator::new(mock.connection(), mounter)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_windows_usb_splits_large_install_wim() {
+    let mock = MockUdisks2::start().await.unwrap();
+    usb_stick(&mock).await;
+    let iso = windows_iso(1536 << 10);
+    let tools = TempDir::new().unwrap();
+    let options = WindowsUsbOptions {
+        split_size_mib: 1,
+        wimlib_imagex: fake_wimlib(&tools),
+        ..WindowsUsbOptions::default()
+    };
+
+    let report = creator(&mock)
+        .await
+        .create(iso.path(), Path::new("/dev/sdb"), &options, |_| {})
+        .await
+        .unwrap();
+
+    assert_eq!(report.device, Path::new("/dev/sdb"));
+    assert_eq!(report.partition, Path::new("/dev/sdb1"));
+    assert_eq!(report.files, 1);
+    assert_eq!(report.bytes, 8 + (1536 << 10));
+    assert_eq!(
+        report.split_parts,
+        [
+            PathBuf::from("sources/install.swm"),
+            PathBuf::from("sources/install2.swm")
+        ]
+    );
+    assert_eq!(report.removal, RemovalAction::Ejected);
+
+    let dest = mock.mount_root().join("sdb1");
+    assert_eq!(fs::read(dest.join("setup.exe")).unwrap(), b"MZ setup");
+    assert!(!dest.join("sources/install.wim").exists());
+    let part1 = fs::metadata(dest.join("sources/install.swm"))
+        .unwrap()
+        .len();
+    let part2 = fs::metadata(dest.join("sources/install2.swm"))
+        .unwrap()
+        .len();
+    assert_eq!((part1, part2), (1 << 20, 512 << 10));
+
+    let calls = mock.calls();
+    let expected = [
+        "Filesystem.Unmount /org/freedesktop/UDisks2/block_devices/sdb1".to_string(),
+        format!("Block.Format {DISK_PATH}"),
+        format!("PartitionTable.CreatePartition {DISK_PATH}"),
+        format!("Block.Format {PARTITION_PATH}"),
+        format!("Filesystem.Mount {PARTITION_PATH}"),
+        format!("Filesystem.Unmount {PARTITION_PATH}"),
+        "Drive.Eject /org/freedesktop/UDisks2/drives/SanDisk_Cruzer_4C530001".to_string(),
+    ];
+    assert_eq!(calls, expected);
+}
+
+#[tokio::test]
+async fn test_windows_usb_copies_small_install_wim_without_wimlib() {
+    let mock = MockUdisks2::start().await.unwrap();
+    usb_stick(&mock).await;
+    let iso = windows_iso(4096);
+    let options = WindowsUsbOptions {
+        wimlib_imagex: PathBuf::from("/nonexistent/wimlib-imagex"),
+        eject: false,
+        ..WindowsUsbOptions::default()
+    };
+
+    let report = creator(&mock)
+        .await
+        .create(iso.path(), Path::new("/dev/sdb"), &options, |_| {})
+        .await
+        .unwrap();
+
+    assert_eq!(report.files, 2);
+    assert!(report.split_parts.is_empty());
+    assert_eq!(report.removal, RemovalAction::None);
+    let dest = mock.mount_root().join("sdb1");
+    assert_eq!(
+        fs::metadata(dest.join("sources/install.wim"))
+            .unwrap()
+            .len(),
+        4096
+    );
+    assert!(!mock.calls().iter().any(|c| c.starts_with("Drive.Eject")));
+}
+
+#[tokio::test]
+async fn test_windows_usb_leaves_drive_alone_without_wimlib() {
+    let mock = MockUdisks2::start().await.unwrap();
+    usb_stick(&mock).await;
+    let iso = windows_iso(1536 << 10);
+    let options = WindowsUsbOptions {
+        split_size_mib: 1,
+        wimlib_imagex: PathBuf::from("/nonexistent/wimlib-imagex"),
+        ..WindowsUsbOptions::default()
+    };
+
+    let error = creator(&mock)
+        .await
+        .create(iso.path(), Path::new("/dev/sdb"), &options, |_| {})
+        .await
+        .unwrap_err();
+
+    assert!(format!("{error:#}").contains("wimlib"), "{error:#}");
+    assert!(mock.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_windows_usb_rejects_long_label() {
+    let mock = MockUdisks2::start().await.unwrap();
+    usb_stick(&mock).await;
+    let iso = windows_iso(4096);
+    let options = WindowsUsbOptions {
+        label: "WINDOWS_INSTALLER".into(),
+        ..WindowsUsbOptions::default()
+    };
+
+    let error = creator(&mock)
+        .await
+        .create(iso.path(), Path::new("/dev/sdb"), &options, |_| {})
+        .await
+        .unwrap_err();
+
+    assert!(format!("{error:#}").contains("label"), "{error:#}");
+    assert!(mock.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_windows_usb_rejects_split_size_beyond_fat32_limit() {
+    let mock = MockUdisks2::start().await.unwrap();
+    usb_stick(&mock).await;
+    let iso = windows_iso(4096);
+
+    for split_size_mib in [0, 4096] {
+        let options = WindowsUsbOptions {
+            split_size_mib,
+            ..WindowsUsbOptions::default()
+        };
+        let error = creator(&mock)
+            .await
+            .create(iso.path(), Path::new("/dev/sdb"), &options, |_| {})
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("1 to 4095 MiB"), "{error:#}");
+    }
+    assert!(mock.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_windows_usb_powers_off_drive_that_cannot_be_ejected() {
+    let mock = MockUdisks2::start().await.unwrap();
+    usb_drive(&mock, false).await;
+    let iso = windows_iso(4096);
+
+    let report = creator(&mock)
+        .await
+        .create(
+            iso.path(),
+            Path::new("/dev/sdb"),
+            &WindowsUsbOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.removal, RemovalAction::PoweredOff);
+    let calls = mock.calls();
+    assert_eq!(
+        calls.last().map(String::as_str),
+        Some("Drive.PowerOff /org/freedesktop/UDisks2/drives/SanDisk_Cruzer_4C530001")
+    );
+    assert!(!calls.iter().any(|c| c.starts_with("Drive.Eject")));
+}